@@ -0,0 +1,132 @@
+//! A benchmark scene for lighting clouds with several lights.
+//!
+//! Run it with `cargo run --release -- --bench-lights`. A single cloud volume
+//! fills most of the screen, lit by three volumetric directional lights, with
+//! vsync off. After a warm-up, the app logs the mean frame time over a fixed
+//! number of frames and exits, so that runs from before and after a change to
+//! the raymarch can be compared.
+//!
+//! To compare two commits, run the benchmark a few times at each on the same
+//! machine, with the window at the same size, and compare the lowest of the
+//! logged frame times of each.
+//!
+//! No timings have been recorded yet for the change that finds the lights
+//! once per ray instead of once per step, against the raymarch from before
+//! it. Until they are, with the three lights, the same window size, and the
+//! same step count at both commits, that change's speedup is unmeasured.
+
+use std::time::Duration;
+
+use bevy::{math::vec3, prelude::*, render::camera::Exposure, window::PresentMode};
+
+use crate::volumetric_clouds::{CloudVolume, VolumetricCloudLight, VolumetricCloudSettings};
+
+/// The number of frames to render before measuring, while shaders compile and
+/// the frame time settles.
+const WARM_UP_FRAMES: u32 = 120;
+
+/// The number of frames to average the frame time over.
+const MEASURED_FRAMES: u32 = 600;
+
+/// A plugin that replaces the demo scene with the benchmark scene, measures
+/// the mean frame time, logs it, and exits.
+pub struct LightsBenchmarkPlugin;
+
+impl Plugin for LightsBenchmarkPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(AmbientLight::NONE)
+            .add_systems(Startup, setup)
+            .add_systems(Update, measure_frame_time);
+    }
+}
+
+/// Spawns the cloud, the three lights, and the camera, and turns vsync off.
+fn setup(mut commands: Commands, mut windows: Query<&mut Window>) {
+    for mut window in windows.iter_mut() {
+        window.present_mode = PresentMode::AutoNoVsync;
+    }
+
+    commands
+        .spawn(SpatialBundle {
+            transform: Transform::from_scale(vec3(4.0, 2.0, 4.0)),
+            ..default()
+        })
+        .insert(CloudVolume {
+            density_factor: 0.5,
+            scattering: 1.0,
+            ..default()
+        });
+
+    // Three lights from different directions and in different colors, so
+    // that none of them can be folded into another. They don't march toward
+    // the light, which leaves the main raymarch as the bulk of the cost.
+    for (color, rotation) in [
+        (
+            Color::WHITE,
+            Quat::from_euler(EulerRot::YXZ, 0.5, -0.8, 0.0),
+        ),
+        (
+            Color::srgb(1.0, 0.6, 0.3),
+            Quat::from_euler(EulerRot::YXZ, 2.6, -0.3, 0.0),
+        ),
+        (
+            Color::srgb(0.4, 0.5, 1.0),
+            Quat::from_euler(EulerRot::YXZ, -2.0, -1.2, 0.0),
+        ),
+    ] {
+        commands
+            .spawn(DirectionalLightBundle {
+                directional_light: DirectionalLight {
+                    color,
+                    illuminance: light_consts::lux::OVERCAST_DAY,
+                    shadows_enabled: true,
+                    ..default()
+                },
+                transform: Transform::from_rotation(rotation),
+                ..default()
+            })
+            .insert(VolumetricCloudLight::default());
+    }
+
+    commands
+        .spawn(Camera3dBundle {
+            transform: Transform::from_xyz(0.0, 0.5, 4.5).looking_at(Vec3::ZERO, Vec3::Y),
+            camera: Camera {
+                hdr: true,
+                ..default()
+            },
+            exposure: Exposure::OVERCAST,
+            ..default()
+        })
+        .insert(VolumetricCloudSettings {
+            step_count: 128,
+            ambient_intensity: 0.0,
+            ..default()
+        });
+}
+
+/// Logs the mean frame time once enough frames have been measured, and exits.
+fn measure_frame_time(
+    time: Res<Time<Real>>,
+    mut frame_count: Local<u32>,
+    mut measured_time: Local<Duration>,
+    mut app_exit_events: EventWriter<AppExit>,
+) {
+    *frame_count += 1;
+    if *frame_count <= WARM_UP_FRAMES {
+        return;
+    }
+
+    *measured_time += time.delta();
+    if *frame_count < WARM_UP_FRAMES + MEASURED_FRAMES {
+        return;
+    }
+
+    let mean_frame_time = *measured_time / MEASURED_FRAMES;
+    info!(
+        "Mean frame time over {} frames: {:.3} ms",
+        MEASURED_FRAMES,
+        mean_frame_time.as_secs_f64() * 1000.0
+    );
+    app_exit_events.send(AppExit::Success);
+}
@@ -1,15 +1,29 @@
+mod bench;
 mod camera_controller;
 mod volumetric_clouds;
-use bevy::{log::tracing_subscriber::fmt::time, math::vec3, prelude::*};
+use bevy::{log::tracing_subscriber::fmt::time, math::vec3, prelude::*, render::camera::Exposure};
 
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use camera_controller::{PanOrbitCamera, PanOrbitCameraPlugin};
 use volumetric_clouds::{
-    CloudVolume, VolumetricCloudLight, VolumetricCloudPlugin, VolumetricCloudSettings,
+    CloudAmbientAir, CloudLightning, CloudLightningBundle, CloudSkyClock, CloudVolume,
+    VolumetricCloudPlugin, VolumetricCloudSettings,
 };
 
 /// Entry point.
 fn main() {
+    // Run the benchmark scene instead of the demo if asked to.
+    if std::env::args().any(|arg| arg == "--bench-lights") {
+        App::new()
+            .add_plugins((
+                DefaultPlugins,
+                VolumetricCloudPlugin,
+                bench::LightsBenchmarkPlugin,
+            ))
+            .run();
+        return;
+    }
+
     App::new()
         .add_plugins((
             DefaultPlugins.set(AssetPlugin {
@@ -21,7 +35,7 @@ fn main() {
         ))
         .insert_resource(AmbientLight::NONE)
         .add_systems(Startup, setup)
-        .add_systems(Update, rotate_camera)
+        .add_systems(Update, (rotate_camera, spawn_lightning))
         .run();
 }
 
@@ -43,19 +57,13 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
             ..default()
         });
 
-    // Spawn a bright directional light that illuminates the cloud well.
-    commands
-        .spawn(DirectionalLightBundle {
-            transform: Transform::from_xyz(1.0, 1.0, -0.3).looking_at(vec3(0.0, 0.5, 0.0), Vec3::Y),
-            directional_light: DirectionalLight {
-                shadows_enabled: true,
-                illuminance: 32000.0,
-                ..default()
-            },
-            ..default()
-        })
-        // Make sure to add this for the light to interact with the cloud.
-        .insert(VolumetricCloudLight);
+    // Run the sun and the moon through a summer day, ten minutes per second.
+    // The clock spawns both as volumetric cloud lights.
+    commands.insert_resource(CloudSkyClock {
+        time_of_day: 7.0,
+        time_scale: 600.0,
+        ..default()
+    });
 
     // Spawn a camera.
     commands
@@ -66,6 +74,8 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
                 hdr: true,
                 ..default()
             },
+            // Expose for daylight. Nights will be close to black.
+            exposure: Exposure::SUNLIGHT,
             ..default()
         })
         .insert(VolumetricCloudSettings {
@@ -73,6 +83,13 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
             step_count: 64,
             // Disable ambient light.
             ambient_intensity: 0.0,
+            // Fill the air with a light haze, so that the bunny casts
+            // crepuscular rays.
+            ambient_air: Some(CloudAmbientAir {
+                density: 0.02,
+                max_distance: 10.0,
+                ..default()
+            }),
             ..default()
         });
 }
@@ -86,3 +103,27 @@ fn rotate_camera(mut cameras: Query<&mut Transform, With<Camera3d>>, time: Res<T
         .looking_at(vec3(0.0, 0.5, 0.0), Vec3::Y);
     }
 }
+
+/// Flashes lightning inside the cloud whenever the L key is pressed.
+fn spawn_lightning(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut seed: Local<u32>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyL) {
+        return;
+    }
+
+    *seed += 1;
+    commands.spawn(CloudLightningBundle {
+        lightning: CloudLightning {
+            intensity: 2.0e5,
+            length: 0.4,
+            seed: *seed,
+            point_light_range: Some(5.0),
+            ..default()
+        },
+        transform: Transform::from_xyz(0.0, 0.7, 0.0),
+        ..default()
+    });
+}
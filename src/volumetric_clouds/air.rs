@@ -0,0 +1,923 @@
+//! Crepuscular rays: sunbeams through gaps in the clouds.
+//!
+//! The clouds themselves are only rendered inside their volumes, so on their
+//! own they can't light up the air around them. When [`CloudAmbientAir`] is
+//! enabled on a camera, a thin medium fills the air between the camera and the
+//! scene, and is raymarched in a pass of its own after the clouds.
+//!
+//! The air is composited over the clouds, so each ray stops at the nearest
+//! cloud volume that it enters, as well as at the scene. This leaves out the
+//! air behind the clouds, which only shows through thin ones.
+//!
+//! To find out where the sun reaches that air, the cloud volumes are first
+//! rendered into a *cloud shadow map*: an orthographic projection of all the
+//! volumes along the direction of the sun. Each texel stores the transmittance
+//! through every cloud above it, in RGB, and the light-space depth at which the
+//! first of those clouds starts, in alpha. Volumes are combined with
+//! multiplicative blending, so they can overlap freely. The air raymarch then
+//! looks up this map at each step, so shafts appear through the holes and
+//! edges of the clouds without any help from the scene's shadow maps.
+
+use bevy::{
+    core_pipeline::{
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
+    },
+    math::{Mat3A, Vec3A},
+    pbr::{MeshPipelineViewLayoutKey, MeshPipelineViewLayouts, MeshViewBindGroup},
+    prelude::*,
+    render::{
+        mesh::{GpuBufferInfo, GpuMesh, MeshVertexBufferLayoutRef},
+        render_asset::RenderAssets,
+        render_resource::{
+            binding_types::{
+                sampler, texture_2d, texture_3d, texture_depth_2d, texture_depth_2d_multisampled,
+                uniform_buffer,
+            },
+            AddressMode, BindGroupLayout, BindGroupLayoutEntries, BindingResource, BlendComponent,
+            BlendFactor, BlendOperation, BlendState, CachedRenderPipelineId, ColorTargetState,
+            ColorWrites, DynamicBindGroupEntries, DynamicUniformBuffer, Extent3d, Face, FilterMode,
+            FragmentState, LoadOp, MultisampleState, Operations, PipelineCache, PrimitiveState,
+            RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor, Sampler,
+            SamplerBindingType, SamplerDescriptor, ShaderStages, ShaderType,
+            SpecializedRenderPipeline, SpecializedRenderPipelines, StoreOp, TextureDescriptor,
+            TextureDimension, TextureFormat, TextureSampleType, TextureUsages, VertexState,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        settings::WgpuFeatures,
+        texture::{BevyDefault, CachedTexture, GpuImage, TextureCache},
+        view::{ExtractedView, ViewDepthTexture, ViewTarget, VisibleEntities},
+        Extract,
+    },
+};
+use bitflags::bitflags;
+
+use crate::volumetric_clouds::{
+    render::CUBE_MESH, CloudVolume, VolumetricCloudIntegrator, VolumetricCloudLight,
+    VolumetricCloudSettings, WithCloudVolume,
+};
+
+/// The maximum number of cloud volumes that the ambient air of a view stops
+/// at. The air passes through any further volumes.
+///
+/// This must match the constant in `ambient_air.wgsl`.
+const MAX_AMBIENT_AIR_CLOUD_VOLUMES: usize = 16;
+
+/// A thin medium that fills the air around the clouds, so that sunlight
+/// streaming through gaps in them forms visible shafts.
+///
+/// Set this as [`VolumetricCloudSettings::ambient_air`] to enable it. The sun
+/// is the brightest directional light with a [`VolumetricCloudLight`].
+#[derive(Clone, Copy, Debug, Reflect)]
+pub struct CloudAmbientAir {
+    /// The single-scattering albedo of the air, which tints the shafts.
+    ///
+    /// Defaults to white.
+    pub color: Color,
+
+    /// The extinction coefficient of the air, in 1/m.
+    ///
+    /// Keep this low: the air fills everything up to [`Self::max_distance`],
+    /// so even small values add up to a thick haze.
+    ///
+    /// The default value is 0.02.
+    pub density: f32,
+
+    /// The Henyey-Greenstein asymmetry parameter of the air.
+    ///
+    /// Haze scatters mostly forward, which makes the shafts brightest when
+    /// looking toward the sun.
+    ///
+    /// The default value is 0.7.
+    pub scattering_asymmetry: f32,
+
+    /// How far from the camera the air extends, in meters.
+    ///
+    /// The default value is 100.0.
+    pub max_distance: f32,
+
+    /// The number of raymarching steps through the air.
+    ///
+    /// The default value is 32.
+    pub step_count: u32,
+
+    /// The width and height of the cloud shadow map, in texels.
+    ///
+    /// The shadow map covers all the cloud volumes, so raise this when they
+    /// are spread over a large area.
+    ///
+    /// The default value is 512.
+    pub shadow_map_size: u32,
+}
+
+impl Default for CloudAmbientAir {
+    fn default() -> Self {
+        Self {
+            color: Color::WHITE,
+            density: 0.02,
+            scattering_asymmetry: 0.7,
+            max_distance: 100.0,
+            step_count: 32,
+            shadow_map_size: 512,
+        }
+    }
+}
+
+/// The directional light that casts crepuscular rays, extracted to the render
+/// world.
+#[derive(Clone, Copy, Resource)]
+pub struct ExtractedCloudSun {
+    /// The world-space direction toward the light.
+    direction_to_light: Vec3,
+    /// The illuminance of the light, in lux, premultiplied by its color.
+    color: Vec3,
+}
+
+bitflags! {
+    /// Flags that describe the pipeline used to render the ambient air.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    struct AmbientAirPipelineKeyFlags: u8 {
+        /// The view's color format has high dynamic range.
+        const HDR = 0x1;
+        /// The device supports dual-source blending.
+        const DUAL_SOURCE_BLENDING = 0x2;
+        /// Each raymarching step is integrated analytically.
+        const ANALYTIC_INTEGRATION = 0x4;
+    }
+}
+
+/// Identifies a single specialization of the ambient air shader.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct AmbientAirPipelineKey {
+    /// The layout of the view.
+    mesh_pipeline_view_key: MeshPipelineViewLayoutKey,
+    /// Flags that specify features on the pipeline key.
+    flags: AmbientAirPipelineKeyFlags,
+}
+
+/// Identifies a single specialization of the cloud shadow map shader.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct CloudShadowMapPipelineKey {
+    /// The vertex buffer layout of the cube mesh.
+    vertex_buffer_layout: MeshVertexBufferLayoutRef,
+    /// True if the cloud volume has a 3D voxel density texture.
+    density_texture: bool,
+}
+
+/// The GPU pipeline for the ambient air.
+#[derive(Resource)]
+pub struct AmbientAirPipeline {
+    shader: Handle<Shader>,
+    /// A reference to the shared set of mesh pipeline view layouts.
+    mesh_view_layouts: MeshPipelineViewLayouts,
+    /// The bind group layouts, without and with multisampling.
+    bind_group_layouts: [BindGroupLayout; 2],
+    /// The sampler that the air uses to sample the cloud shadow map.
+    shadow_map_sampler: Sampler,
+}
+
+/// The GPU pipeline that renders cloud volumes into the cloud shadow map.
+#[derive(Resource)]
+pub struct CloudShadowMapPipeline {
+    shader: Handle<Shader>,
+    /// The bind group layouts, without and with a density texture.
+    bind_group_layouts: [BindGroupLayout; 2],
+}
+
+/// The render pipelines that draw the ambient air for a view.
+#[derive(Component)]
+pub struct ViewAmbientAirPipelines {
+    air: CachedRenderPipelineId,
+    shadow_map_textureless: CachedRenderPipelineId,
+    shadow_map_textured: CachedRenderPipelineId,
+}
+
+/// The ambient air and cloud shadow map of a view, ready to be drawn.
+#[derive(Component)]
+pub struct ViewAmbientAir {
+    /// The cloud shadow map.
+    shadow_map: CachedTexture,
+    /// The offset of this view's [`AmbientAirUniform`] within the
+    /// [`AmbientAirUniformBuffer`].
+    uniform_buffer_offset: u32,
+    /// The cloud volumes to draw into the shadow map.
+    shadow_casters: Vec<CloudShadowCaster>,
+}
+
+/// A cloud volume that's drawn into the cloud shadow map.
+struct CloudShadowCaster {
+    /// The 3D voxel density texture for this volume, if present.
+    density_texture: Option<AssetId<Image>>,
+    /// The offset of this volume's [`CloudShadowMapUniform`] within the
+    /// [`CloudShadowMapUniformBuffer`].
+    uniform_buffer_offset: u32,
+}
+
+/// The GPU version of [`CloudAmbientAir`], together with the sun.
+#[derive(ShaderType)]
+pub struct AmbientAirUniform {
+    /// The transform from world space to the clip space of the cloud shadow
+    /// map.
+    shadow_clip_from_world: Mat4,
+    direction_to_light: Vec3,
+    light_color: Vec3,
+    scattering: Vec3,
+    extinction: f32,
+    scattering_asymmetry: f32,
+    max_distance: f32,
+    step_count: u32,
+    jitter_strength: f32,
+    /// The number of entries in `cloud_local_from_world` that are in use.
+    cloud_count: u32,
+    /// The transforms from world space to the 1×1×1 local space of each cloud
+    /// volume visible to the view, which the air stops at.
+    cloud_local_from_world: [Mat4; MAX_AMBIENT_AIR_CLOUD_VOLUMES],
+}
+
+/// A single cloud volume, formatted for the cloud shadow map.
+#[derive(ShaderType)]
+pub struct CloudShadowMapUniform {
+    /// The transform from the 1×1×1 local space of the volume to the clip space
+    /// of the cloud shadow map.
+    clip_from_local: Mat4,
+    /// The direction in which the light travels, in local space, scaled so
+    /// that a unit length in world space maps to a unit length along it.
+    light_direction: Vec3,
+    step_count: u32,
+    /// The extinction coefficient of each color channel, premultiplied by the
+    /// density factor.
+    extinction: Vec3,
+}
+
+/// The GPU buffer that stores the [`AmbientAirUniform`] data.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct AmbientAirUniformBuffer(pub DynamicUniformBuffer<AmbientAirUniform>);
+
+/// The GPU buffer that stores the [`CloudShadowMapUniform`] data.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct CloudShadowMapUniformBuffer(pub DynamicUniformBuffer<CloudShadowMapUniform>);
+
+impl FromWorld for AmbientAirPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let mesh_view_layouts = world.resource::<MeshPipelineViewLayouts>();
+
+        let bind_group_layouts = [false, true].map(|multisampled| {
+            render_device.create_bind_group_layout(
+                "ambient air bind group layout",
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        // `ambient_air`
+                        uniform_buffer::<AmbientAirUniform>(true),
+                        // `depth_texture`
+                        if multisampled {
+                            texture_depth_2d_multisampled()
+                        } else {
+                            texture_depth_2d()
+                        },
+                        // `shadow_map` and `shadow_map_sampler`
+                        texture_2d(TextureSampleType::Float { filterable: true }),
+                        sampler(SamplerBindingType::Filtering),
+                    ),
+                ),
+            )
+        });
+
+        let shadow_map_sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("cloud shadow map sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        AmbientAirPipeline {
+            shader: world
+                .resource::<AssetServer>()
+                .load("embedded://bevy_clouds/volumetric_clouds/ambient_air.wgsl"),
+            mesh_view_layouts: mesh_view_layouts.clone(),
+            bind_group_layouts,
+            shadow_map_sampler,
+        }
+    }
+}
+
+impl FromWorld for CloudShadowMapPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let bind_group_layouts = [false, true].map(|density_texture| {
+            let mut entries = BindGroupLayoutEntries::single(
+                ShaderStages::VERTEX_FRAGMENT,
+                // `cloud_volume`
+                uniform_buffer::<CloudShadowMapUniform>(true),
+            )
+            .to_vec();
+
+            // `density_texture` and `density_sampler`
+            if density_texture {
+                entries.extend_from_slice(&BindGroupLayoutEntries::with_indices(
+                    ShaderStages::FRAGMENT,
+                    (
+                        (2, texture_3d(TextureSampleType::Float { filterable: true })),
+                        (3, sampler(SamplerBindingType::Filtering)),
+                    ),
+                ));
+            }
+
+            render_device.create_bind_group_layout("cloud shadow map bind group layout", &entries)
+        });
+
+        CloudShadowMapPipeline {
+            shader: world
+                .resource::<AssetServer>()
+                .load("embedded://bevy_clouds/volumetric_clouds/cloud_shadow_map.wgsl"),
+            bind_group_layouts,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for AmbientAirPipeline {
+    type Key = AmbientAirPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mesh_view_layout = self
+            .mesh_view_layouts
+            .get_view_layout(key.mesh_pipeline_view_key);
+
+        let multisampled = key
+            .mesh_pipeline_view_key
+            .contains(MeshPipelineViewLayoutKey::MULTISAMPLED);
+
+        let mut shader_defs = vec![];
+        if multisampled {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+        if key
+            .flags
+            .contains(AmbientAirPipelineKeyFlags::ANALYTIC_INTEGRATION)
+        {
+            shader_defs.push("ANALYTIC_INTEGRATION".into());
+        }
+
+        // Blend the same way the cloud volumes do.
+        let color_blend = if key
+            .flags
+            .contains(AmbientAirPipelineKeyFlags::DUAL_SOURCE_BLENDING)
+        {
+            shader_defs.push("DUAL_SOURCE_BLENDING".into());
+            BlendComponent {
+                src_factor: BlendFactor::One,
+                dst_factor: BlendFactor::Src1,
+                operation: BlendOperation::Add,
+            }
+        } else {
+            BlendComponent {
+                src_factor: BlendFactor::One,
+                dst_factor: BlendFactor::OneMinusSrcAlpha,
+                operation: BlendOperation::Add,
+            }
+        };
+
+        RenderPipelineDescriptor {
+            label: Some("ambient air pipeline".into()),
+            layout: vec![
+                mesh_view_layout.clone(),
+                self.bind_group_layouts[multisampled as usize].clone(),
+            ],
+            push_constant_ranges: vec![],
+            vertex: fullscreen_shader_vertex_state(),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: if key.flags.contains(AmbientAirPipelineKeyFlags::HDR) {
+                        ViewTarget::TEXTURE_FORMAT_HDR
+                    } else {
+                        TextureFormat::bevy_default()
+                    },
+                    blend: Some(BlendState {
+                        color: color_blend,
+                        alpha: BlendComponent {
+                            src_factor: BlendFactor::Zero,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                    }),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for CloudShadowMapPipeline {
+    type Key = CloudShadowMapPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let shader_defs = if key.density_texture {
+            vec!["DENSITY_TEXTURE".into()]
+        } else {
+            vec![]
+        };
+
+        let vertex_format = key
+            .vertex_buffer_layout
+            .0
+            .get_layout(&[Mesh::ATTRIBUTE_POSITION.at_shader_location(0)])
+            .expect("Failed to get vertex layout for cloud shadow map hull");
+
+        RenderPipelineDescriptor {
+            label: Some("cloud shadow map pipeline".into()),
+            layout: vec![self.bind_group_layouts[key.density_texture as usize].clone()],
+            push_constant_ranges: vec![],
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: "vertex".into(),
+                buffers: vec![vertex_format],
+            },
+            primitive: PrimitiveState {
+                cull_mode: Some(Face::Back),
+                ..default()
+            },
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: CLOUD_SHADOW_MAP_FORMAT,
+                    // Multiply the transmittance of overlapping volumes
+                    // together, and keep the depth of whichever starts
+                    // closest to the light.
+                    blend: Some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::Zero,
+                            dst_factor: BlendFactor::Src,
+                            operation: BlendOperation::Add,
+                        },
+                        alpha: BlendComponent {
+                            src_factor: BlendFactor::One,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Min,
+                        },
+                    }),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+        }
+    }
+}
+
+/// The texture format of the cloud shadow map.
+const CLOUD_SHADOW_MAP_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+/// Extracts the brightest [`VolumetricCloudLight`] as the light that casts
+/// crepuscular rays.
+pub fn extract_cloud_sun(
+    mut commands: Commands,
+    lights: Extract<Query<(&DirectionalLight, &VolumetricCloudLight, &GlobalTransform)>>,
+) {
+    let Some((light, volumetric_light, transform)) =
+        lights
+            .iter()
+            .max_by(|(a, a_volumetric, _), (b, b_volumetric, _)| {
+                (a.illuminance * a_volumetric.intensity)
+                    .total_cmp(&(b.illuminance * b_volumetric.intensity))
+            })
+    else {
+        commands.remove_resource::<ExtractedCloudSun>();
+        return;
+    };
+
+    commands.insert_resource(ExtractedCloudSun {
+        direction_to_light: transform.back().into(),
+        color: light.color.to_linear().to_vec3()
+            * volumetric_light.tint.to_linear().to_vec3()
+            * light.illuminance
+            * volumetric_light.intensity,
+    });
+}
+
+/// Specializes the ambient air pipelines for all views that have it enabled.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_ambient_air_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut air_pipelines: ResMut<SpecializedRenderPipelines<AmbientAirPipeline>>,
+    mut shadow_map_pipelines: ResMut<SpecializedRenderPipelines<CloudShadowMapPipeline>>,
+    ambient_air_pipeline: Res<AmbientAirPipeline>,
+    cloud_shadow_map_pipeline: Res<CloudShadowMapPipeline>,
+    view_targets: Query<(
+        Entity,
+        &ExtractedView,
+        Has<NormalPrepass>,
+        Has<DepthPrepass>,
+        Has<MotionVectorPrepass>,
+        Has<DeferredPrepass>,
+        &VolumetricCloudSettings,
+    )>,
+    msaa: Res<Msaa>,
+    meshes: Res<RenderAssets<GpuMesh>>,
+    render_device: Res<RenderDevice>,
+) {
+    let cube_mesh = meshes.get(&CUBE_MESH).expect("Cube mesh not found!");
+
+    for (
+        entity,
+        view,
+        normal_prepass,
+        depth_prepass,
+        motion_vector_prepass,
+        deferred_prepass,
+        volumetric_cloud_settings,
+    ) in view_targets.iter()
+    {
+        if volumetric_cloud_settings.ambient_air.is_none() {
+            continue;
+        }
+
+        // Create a mesh pipeline view layout key corresponding to the view.
+        let mut mesh_pipeline_view_key = MeshPipelineViewLayoutKey::from(*msaa);
+        mesh_pipeline_view_key.set(MeshPipelineViewLayoutKey::NORMAL_PREPASS, normal_prepass);
+        mesh_pipeline_view_key.set(MeshPipelineViewLayoutKey::DEPTH_PREPASS, depth_prepass);
+        mesh_pipeline_view_key.set(
+            MeshPipelineViewLayoutKey::MOTION_VECTOR_PREPASS,
+            motion_vector_prepass,
+        );
+        mesh_pipeline_view_key.set(
+            MeshPipelineViewLayoutKey::DEFERRED_PREPASS,
+            deferred_prepass,
+        );
+
+        let mut flags = AmbientAirPipelineKeyFlags::empty();
+        flags.set(AmbientAirPipelineKeyFlags::HDR, view.hdr);
+        flags.set(
+            AmbientAirPipelineKeyFlags::DUAL_SOURCE_BLENDING,
+            render_device
+                .features()
+                .contains(WgpuFeatures::DUAL_SOURCE_BLENDING),
+        );
+        flags.set(
+            AmbientAirPipelineKeyFlags::ANALYTIC_INTEGRATION,
+            volumetric_cloud_settings.integrator == VolumetricCloudIntegrator::Analytic,
+        );
+
+        let air = air_pipelines.specialize(
+            &pipeline_cache,
+            &ambient_air_pipeline,
+            AmbientAirPipelineKey {
+                mesh_pipeline_view_key,
+                flags,
+            },
+        );
+        let [shadow_map_textureless, shadow_map_textured] = [false, true].map(|density_texture| {
+            shadow_map_pipelines.specialize(
+                &pipeline_cache,
+                &cloud_shadow_map_pipeline,
+                CloudShadowMapPipelineKey {
+                    vertex_buffer_layout: cube_mesh.layout.clone(),
+                    density_texture,
+                },
+            )
+        });
+
+        commands.entity(entity).insert(ViewAmbientAirPipelines {
+            air,
+            shadow_map_textureless,
+            shadow_map_textured,
+        });
+    }
+}
+
+/// A system that prepares the cloud shadow map and the uniforms for the
+/// ambient air of each view that has it enabled.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_ambient_air(
+    mut commands: Commands,
+    view_targets: Query<(Entity, &VisibleEntities, &VolumetricCloudSettings)>,
+    cloud_volumes: Query<(&CloudVolume, &GlobalTransform)>,
+    sun: Option<Res<ExtractedCloudSun>>,
+    images: Res<RenderAssets<GpuImage>>,
+    mut texture_cache: ResMut<TextureCache>,
+    mut air_uniform_buffer: ResMut<AmbientAirUniformBuffer>,
+    mut shadow_map_uniform_buffer: ResMut<CloudShadowMapUniformBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(sun) = sun else {
+        return;
+    };
+
+    let view_count = view_targets
+        .iter()
+        .filter(|(_, _, settings)| settings.ambient_air.is_some())
+        .count();
+    if view_count == 0 {
+        return;
+    }
+
+    let Some(mut air_writer) =
+        air_uniform_buffer.get_writer(view_count, &render_device, &render_queue)
+    else {
+        return;
+    };
+    let mut shadow_map_writer = shadow_map_uniform_buffer.get_writer(
+        view_count * cloud_volumes.iter().len(),
+        &render_device,
+        &render_queue,
+    );
+
+    // Set up an orthographic projection along the light that tightly encloses
+    // every cloud volume. This doesn't depend on the view.
+    let light_direction = -sun.direction_to_light;
+    let up = if light_direction.y.abs() > 0.99 {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    };
+    let light_from_world = Mat4::look_to_rh(Vec3::ZERO, light_direction, up);
+    let (mut light_min, mut light_max) = (Vec3::MAX, Vec3::MIN);
+    for (_, transform) in cloud_volumes.iter() {
+        let light_from_local = light_from_world * transform.compute_matrix();
+        for corner in 0..8 {
+            let local_corner = Vec3::new(
+                (corner & 1) as f32 - 0.5,
+                ((corner >> 1) & 1) as f32 - 0.5,
+                ((corner >> 2) & 1) as f32 - 0.5,
+            );
+            let light_corner = light_from_local.transform_point3(local_corner);
+            light_min = light_min.min(light_corner);
+            light_max = light_max.max(light_corner);
+        }
+    }
+
+    // The light looks down its -Z axis, so the near plane is at the largest Z.
+    let shadow_clip_from_world = if light_min.cmplt(light_max).all() {
+        Mat4::orthographic_rh(
+            light_min.x,
+            light_max.x,
+            light_min.y,
+            light_max.y,
+            -light_max.z,
+            -light_min.z,
+        ) * light_from_world
+    } else {
+        light_from_world
+    };
+
+    for (view_entity, visible_entities, volumetric_cloud_settings) in view_targets.iter() {
+        let Some(ambient_air) = volumetric_cloud_settings.ambient_air else {
+            continue;
+        };
+
+        // The air is composited over the clouds, so it has to stop where the
+        // clouds that the view can see start.
+        let mut cloud_count = 0;
+        let mut cloud_local_from_world = [Mat4::IDENTITY; MAX_AMBIENT_AIR_CLOUD_VOLUMES];
+        for &visible_entity in visible_entities.iter::<WithCloudVolume>() {
+            let Ok((_, transform)) = cloud_volumes.get(visible_entity) else {
+                continue;
+            };
+            if cloud_count == MAX_AMBIENT_AIR_CLOUD_VOLUMES {
+                warn_once!(
+                    "More than {} cloud volumes are visible to a view with ambient air; the air \
+                    passes through the rest",
+                    MAX_AMBIENT_AIR_CLOUD_VOLUMES
+                );
+                break;
+            }
+            cloud_local_from_world[cloud_count] = transform.compute_matrix().inverse();
+            cloud_count += 1;
+        }
+
+        let mut shadow_casters = vec![];
+        if let Some(shadow_map_writer) = shadow_map_writer.as_mut() {
+            for (cloud_volume, transform) in cloud_volumes.iter() {
+                // Skip volumes whose density texture is still loading.
+                let density_texture = cloud_volume.density_texture.as_ref().map(Handle::id);
+                if density_texture
+                    .is_some_and(|density_texture| images.get(density_texture).is_none())
+                {
+                    continue;
+                }
+
+                let world_from_local = transform.compute_matrix();
+                let local_from_world = world_from_local.inverse();
+                let uniform_buffer_offset = shadow_map_writer.write(&CloudShadowMapUniform {
+                    clip_from_local: shadow_clip_from_world * world_from_local,
+                    light_direction: (Mat3A::from_mat4(local_from_world)
+                        * Vec3A::from(light_direction))
+                    .into(),
+                    step_count: volumetric_cloud_settings.step_count,
+                    extinction: (cloud_volume.absorption_rgb() + cloud_volume.scattering_rgb())
+                        * cloud_volume.density_factor,
+                });
+
+                shadow_casters.push(CloudShadowCaster {
+                    density_texture,
+                    uniform_buffer_offset,
+                });
+            }
+        }
+
+        let uniform_buffer_offset = air_writer.write(&AmbientAirUniform {
+            shadow_clip_from_world,
+            direction_to_light: sun.direction_to_light,
+            light_color: sun.color,
+            scattering: ambient_air.color.to_linear().to_vec3() * ambient_air.density,
+            extinction: ambient_air.density,
+            scattering_asymmetry: ambient_air.scattering_asymmetry,
+            max_distance: ambient_air.max_distance,
+            step_count: ambient_air.step_count,
+            jitter_strength: volumetric_cloud_settings.jitter,
+            cloud_count: cloud_count as u32,
+            cloud_local_from_world,
+        });
+
+        let shadow_map = texture_cache.get(
+            &render_device,
+            TextureDescriptor {
+                label: Some("cloud shadow map"),
+                size: Extent3d {
+                    width: ambient_air.shadow_map_size,
+                    height: ambient_air.shadow_map_size,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: CLOUD_SHADOW_MAP_FORMAT,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+        );
+
+        commands.entity(view_entity).insert(ViewAmbientAir {
+            shadow_map,
+            uniform_buffer_offset,
+            shadow_casters,
+        });
+    }
+}
+
+/// Renders the cloud shadow map of a view, and then the ambient air on top of
+/// the main pass.
+///
+/// This is called by the volumetric cloud node after it draws the clouds
+/// themselves, so that the air in front of the clouds dims them.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn render_ambient_air(
+    render_context: &mut RenderContext,
+    world: &World,
+    view_target: &ViewTarget,
+    view_depth_texture: &ViewDepthTexture,
+    view_bind_group: &MeshViewBindGroup,
+    view_bind_group_offsets: &[u32],
+    view_ambient_air: &ViewAmbientAir,
+    view_ambient_air_pipelines: &ViewAmbientAirPipelines,
+) {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let ambient_air_pipeline = world.resource::<AmbientAirPipeline>();
+    let cloud_shadow_map_pipeline = world.resource::<CloudShadowMapPipeline>();
+    let image_assets = world.resource::<RenderAssets<GpuImage>>();
+    let gpu_meshes = world.resource::<RenderAssets<GpuMesh>>();
+    let msaa = world.resource::<Msaa>();
+
+    let (Some(air_pipeline), Some(air_uniform_buffer_binding)) = (
+        pipeline_cache.get_render_pipeline(view_ambient_air_pipelines.air),
+        world.resource::<AmbientAirUniformBuffer>().binding(),
+    ) else {
+        return;
+    };
+
+    // Create the bind groups for all the shadow casters up front, since they
+    // have to outlive the render pass.
+    let shadow_map_uniform_buffer_binding =
+        world.resource::<CloudShadowMapUniformBuffer>().binding();
+    let mut shadow_casters = vec![];
+    for shadow_caster in &view_ambient_air.shadow_casters {
+        let Some(ref shadow_map_uniform_buffer_binding) = shadow_map_uniform_buffer_binding else {
+            break;
+        };
+
+        let density_image = shadow_caster
+            .density_texture
+            .and_then(|density_texture| image_assets.get(density_texture));
+        let pipeline_id = if density_image.is_some() {
+            view_ambient_air_pipelines.shadow_map_textured
+        } else {
+            view_ambient_air_pipelines.shadow_map_textureless
+        };
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(pipeline_id) else {
+            continue;
+        };
+
+        let mut bind_group_entries =
+            DynamicBindGroupEntries::sequential((shadow_map_uniform_buffer_binding.clone(),));
+        if let Some(density_image) = density_image {
+            bind_group_entries = bind_group_entries.extend_with_indices((
+                (2, BindingResource::TextureView(&density_image.texture_view)),
+                (3, BindingResource::Sampler(&density_image.sampler)),
+            ));
+        }
+        let bind_group = render_context.render_device().create_bind_group(
+            "cloud shadow map bind group",
+            &cloud_shadow_map_pipeline.bind_group_layouts[density_image.is_some() as usize],
+            &bind_group_entries,
+        );
+
+        shadow_casters.push((pipeline, bind_group, shadow_caster.uniform_buffer_offset));
+    }
+
+    // Draw the shadow casters into the cloud shadow map. This clears it to
+    // full transmittance at the far plane even if there are none.
+    {
+        let mut render_pass =
+            render_context
+                .command_encoder()
+                .begin_render_pass(&RenderPassDescriptor {
+                    label: Some("cloud shadow map pass"),
+                    color_attachments: &[Some(RenderPassColorAttachment {
+                        view: &view_ambient_air.shadow_map.default_view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(LinearRgba::WHITE.into()),
+                            store: StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+
+        if let Some(gpu_mesh) = gpu_meshes.get(&CUBE_MESH) {
+            render_pass.set_vertex_buffer(0, *gpu_mesh.vertex_buffer.slice(..));
+            if let GpuBufferInfo::Indexed {
+                buffer,
+                index_format,
+                ..
+            } = &gpu_mesh.buffer_info
+            {
+                render_pass.set_index_buffer(*buffer.slice(..), *index_format);
+            }
+
+            for (pipeline, bind_group, uniform_buffer_offset) in &shadow_casters {
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(0, bind_group, &[*uniform_buffer_offset]);
+                match &gpu_mesh.buffer_info {
+                    GpuBufferInfo::Indexed { count, .. } => {
+                        render_pass.draw_indexed(0..*count, 0, 0..1);
+                    }
+                    GpuBufferInfo::NonIndexed => {
+                        render_pass.draw(0..gpu_mesh.vertex_count, 0..1);
+                    }
+                }
+            }
+        }
+    }
+
+    // Raymarch the air on top of the main pass.
+    let air_bind_group = render_context.render_device().create_bind_group(
+        "ambient air bind group",
+        &ambient_air_pipeline.bind_group_layouts[!matches!(*msaa, Msaa::Off) as usize],
+        &DynamicBindGroupEntries::sequential((
+            air_uniform_buffer_binding,
+            BindingResource::TextureView(view_depth_texture.view()),
+            BindingResource::TextureView(&view_ambient_air.shadow_map.default_view),
+            BindingResource::Sampler(&ambient_air_pipeline.shadow_map_sampler),
+        )),
+    );
+
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("ambient air pass"),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view: view_target.main_texture_view(),
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Load,
+                store: StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+
+    render_pass.set_render_pipeline(air_pipeline);
+    render_pass.set_bind_group(0, &view_bind_group.value, view_bind_group_offsets);
+    render_pass.set_bind_group(
+        1,
+        &air_bind_group,
+        &[view_ambient_air.uniform_buffer_offset],
+    );
+    render_pass.draw(0..3, 0..1);
+}
@@ -0,0 +1,170 @@
+// A postprocessing shader that raymarches the thin ambient air between the
+// camera and the scene, lit by the sun through the cloud shadow map.
+//
+// This is what turns gaps in the clouds into crepuscular rays. It runs after
+// the cloud volumes are drawn and stops at the nearest of them, so that the air
+// in front of the clouds dims them. See `air.rs`.
+
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import bevy_pbr::mesh_view_bindings::{globals, view}
+#import bevy_pbr::utils::interleaved_gradient_noise
+#import bevy_pbr::view_transformations::{frag_coord_to_ndc, position_ndc_to_world}
+
+// The GPU version of `CloudAmbientAir`. See `AmbientAirUniform` in `air.rs`.
+struct AmbientAir {
+    shadow_clip_from_world: mat4x4<f32>,
+    direction_to_light: vec3<f32>,
+    light_color: vec3<f32>,
+    scattering: vec3<f32>,
+    extinction: f32,
+    scattering_asymmetry: f32,
+    max_distance: f32,
+    step_count: u32,
+    jitter_strength: f32,
+    cloud_count: u32,
+    cloud_local_from_world: array<mat4x4<f32>, MAX_AMBIENT_AIR_CLOUD_VOLUMES>,
+}
+
+// The maximum number of cloud volumes that the air stops at.
+//
+// This must match the constant in `air.rs`.
+const MAX_AMBIENT_AIR_CLOUD_VOLUMES: u32 = 16u;
+
+@group(1) @binding(0) var<uniform> ambient_air: AmbientAir;
+
+#ifdef MULTISAMPLED
+@group(1) @binding(1) var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(1) @binding(1) var depth_texture: texture_depth_2d;
+#endif
+
+@group(1) @binding(2) var shadow_map: texture_2d<f32>;
+@group(1) @binding(3) var shadow_map_sampler: sampler;
+
+// 1 / (4π)
+const FRAC_4_PI: f32 = 0.07957747154594767;
+
+// The Henyey-Greenstein phase function. See `volumetric_clouds.wgsl`.
+fn henyey_greenstein(neg_LdotV: f32) -> f32 {
+    let g = ambient_air.scattering_asymmetry;
+    let denom = 1.0 + g * g - 2.0 * g * neg_LdotV;
+    return FRAC_4_PI * (1.0 - g * g) / (denom * sqrt(denom));
+}
+
+// Integrates the light that a single raymarching step contributes. This is the
+// same as in `volumetric_clouds.wgsl`.
+fn integrate_step(
+    source: vec3<f32>,
+    extinction: vec3<f32>,
+    step_size: f32,
+    step_transmittance: vec3<f32>
+) -> vec3<f32> {
+#ifdef ANALYTIC_INTEGRATION
+    return select(
+        source * (1.0 - step_transmittance) / max(extinction, vec3(1e-7)),
+        source * step_size,
+        extinction < vec3(1e-7)
+    );
+#else   // ANALYTIC_INTEGRATION
+    return source * step_size * step_transmittance;
+#endif  // ANALYTIC_INTEGRATION
+}
+
+// Returns the transmittance of the clouds between the sun and the given point.
+fn cloud_transmittance(P_world: vec3<f32>) -> vec3<f32> {
+    let P_clip = ambient_air.shadow_clip_from_world * vec4(P_world, 1.0);
+    let uv = P_clip.xy * vec2(0.5, -0.5) + 0.5;
+    if (any(uv < vec2(0.0)) || any(uv > vec2(1.0))) {
+        return vec3(1.0);
+    }
+
+    // Points closer to the sun than the top of the clouds are fully lit.
+    let shadow = textureSampleLevel(shadow_map, shadow_map_sampler, uv, 0.0);
+    return select(vec3(1.0), shadow.rgb, P_clip.z > shadow.a);
+}
+
+// Returns the distance along the given ray at which it enters the nearest cloud
+// volume, or the given maximum if it doesn't enter any before that. This is zero
+// if the ray starts inside a volume.
+fn distance_to_clouds(Ro_world: vec3<f32>, Rd_world: vec3<f32>, max_distance: f32) -> f32 {
+    var distance = max_distance;
+    for (var i = 0u; i < ambient_air.cloud_count; i += 1u) {
+        // Intersect the ray with the 1×1×1 cube in the local space of the
+        // volume. The transform is affine, so distances along the ray carry
+        // over unchanged.
+        let local_from_world = ambient_air.cloud_local_from_world[i];
+        let Ro_local = (local_from_world * vec4(Ro_world, 1.0)).xyz;
+        let Rd_local = (local_from_world * vec4(Rd_world, 0.0)).xyz;
+        let Rd_local_inv = 1.0 / select(Rd_local, vec3(1e-8), abs(Rd_local) < vec3(1e-8));
+        let t_a = (vec3(-0.5) - Ro_local) * Rd_local_inv;
+        let t_b = (vec3(0.5) - Ro_local) * Rd_local_inv;
+        let t_near = max(max(min(t_a.x, t_b.x), min(t_a.y, t_b.y)), min(t_a.z, t_b.z));
+        let t_far = min(min(max(t_a.x, t_b.x), max(t_a.y, t_b.y)), max(t_a.z, t_b.z));
+        if (t_far >= max(t_near, 0.0)) {
+            distance = min(distance, max(t_near, 0.0));
+        }
+    }
+    return distance;
+}
+
+// The output of the fragment shader. See `volumetric_clouds.wgsl`.
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef DUAL_SOURCE_BLENDING
+    @location(0) @second_blend_source transmittance: vec4<f32>,
+#endif  // DUAL_SOURCE_BLENDING
+}
+
+@fragment
+fn fragment(input: FullscreenVertexOutput) -> FragmentOutput {
+    let frag_coord = input.position;
+    let ndc = frag_coord_to_ndc(frag_coord);
+
+    // Calculate the ray from the camera through this pixel.
+    let Ro_world = view.world_position;
+    let Rd_world = normalize(position_ndc_to_world(vec3(ndc.xy, 1.0)) - Ro_world);
+
+    // Stop at the scene geometry, if it's closer than the end of the air. A
+    // depth of zero is the far plane at infinity.
+    var ray_length = ambient_air.max_distance;
+    let ndc_depth = textureLoad(depth_texture, vec2<i32>(frag_coord.xy), 0);
+    if (ndc_depth > 0.0) {
+        ray_length = min(
+            ray_length,
+            distance(position_ndc_to_world(vec3(ndc.xy, ndc_depth)), Ro_world)
+        );
+    }
+
+    // Stop at the clouds too, since the air is drawn over them.
+    ray_length = distance_to_clouds(Ro_world, Rd_world, ray_length);
+
+    let step_count = ambient_air.step_count;
+    let step_size = ray_length / f32(step_count);
+    let jitter = interleaved_gradient_noise(frag_coord.xy, globals.frame_count) *
+        ambient_air.jitter_strength;
+
+    // The air is homogeneous, so the phase, the light that would reach each
+    // step without clouds, and the transmittance of each step are the same
+    // everywhere along the ray.
+    let phase = henyey_greenstein(dot(ambient_air.direction_to_light, Rd_world));
+    let light = ambient_air.light_color * ambient_air.scattering * phase * view.exposure;
+    let extinction = vec3(ambient_air.extinction);
+    let step_transmittance = exp(-extinction * step_size);
+
+    var accumulated_color = vec3(0.0);
+    var transmittance = vec3(1.0);
+    for (var step = 0u; step < step_count; step += 1u) {
+        let P_world = Ro_world + Rd_world * min((f32(step) + 0.5) * step_size + jitter, ray_length);
+        let source = light * cloud_transmittance(P_world);
+        accumulated_color += integrate_step(source, extinction, step_size, step_transmittance) *
+            transmittance;
+        transmittance *= step_transmittance;
+    }
+
+    var output: FragmentOutput;
+    output.color = vec4(accumulated_color, 1.0 - dot(transmittance, vec3(1.0 / 3.0)));
+#ifdef DUAL_SOURCE_BLENDING
+    output.transmittance = vec4(transmittance, 1.0);
+#endif  // DUAL_SOURCE_BLENDING
+    return output;
+}
@@ -0,0 +1,249 @@
+//! A physically based model of the Earth's atmosphere.
+//!
+//! Sunlight loses some of its blue to Rayleigh scattering, some of everything
+//! to aerosols, and a little of its green and red to ozone on the way down, and
+//! the loss grows as the sun sinks toward the horizon. This module computes
+//! that loss, the *transmittance*, for a configurable atmosphere, using the
+//! model and the Earth-like parameters of [Bruneton 2017] and [Hillaire 2020].
+//!
+//! The same transmittance is precomputed into a 2D lookup table, indexed by
+//! altitude and by the angle of the light from the zenith, so that the cloud
+//! shader can light each sample with the [`CloudSun`] as it arrives at the
+//! altitude of that sample. That's what keeps high clouds white at sunset
+//! while low clouds turn orange, and keeps them lit after the sun has set at
+//! the ground.
+//!
+//! [`CloudSun`]: crate::volumetric_clouds::CloudSun
+//!
+//! [Bruneton 2017]: https://ebruneton.github.io/precomputed_atmospheric_scattering/
+//!
+//! [Hillaire 2020]: https://sebh.github.io/publications/egsr2020.pdf
+
+use bevy::{
+    math::vec3,
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+};
+
+/// The number of steps used to integrate the optical depth along a ray.
+const TRANSMITTANCE_STEP_COUNT: u32 = 64;
+
+/// The transmittance lookup table of the [`CloudAtmosphere`].
+///
+/// Each texel stores the transmittance to the top of the atmosphere in RGB.
+/// The texture coordinates map to the altitude and the zenith angle as in
+/// [Bruneton 2017], which puts most of the texels near the horizon.
+///
+/// [Bruneton 2017]: https://ebruneton.github.io/precomputed_atmospheric_scattering/
+pub const ATMOSPHERE_TRANSMITTANCE_LUT: Handle<Image> = Handle::weak_from_u128(27394615820937465);
+
+/// The width of the [`ATMOSPHERE_TRANSMITTANCE_LUT`], which spans zenith
+/// angles.
+pub const ATMOSPHERE_TRANSMITTANCE_LUT_WIDTH: u32 = 256;
+
+/// The height of the [`ATMOSPHERE_TRANSMITTANCE_LUT`], which spans altitudes.
+pub const ATMOSPHERE_TRANSMITTANCE_LUT_HEIGHT: u32 = 64;
+
+/// The atmosphere that sunlight and moonlight pass through before they reach
+/// the clouds.
+///
+/// All lengths are in meters and all coefficients in 1/m. The default value
+/// describes the Earth.
+#[derive(Clone, Copy, Debug, PartialEq, Resource, ExtractResource, Reflect)]
+#[reflect(Resource)]
+pub struct CloudAtmosphere {
+    /// The radius of the planet.
+    pub bottom_radius: f32,
+
+    /// The radius of the top of the atmosphere, above which there's no air.
+    pub top_radius: f32,
+
+    /// The altitude above sea level of the origin of the scene.
+    ///
+    /// The default value is 0.0.
+    pub scene_altitude: f32,
+
+    /// The Rayleigh scattering coefficient of each color channel at sea
+    /// level.
+    pub rayleigh_scattering: Vec3,
+
+    /// The height over which the density of the air falls off by a factor
+    /// of e.
+    pub rayleigh_scale_height: f32,
+
+    /// The scattering coefficient of aerosols at sea level.
+    pub mie_scattering: f32,
+
+    /// The absorption coefficient of aerosols at sea level.
+    pub mie_absorption: f32,
+
+    /// The height over which the density of aerosols falls off by a factor of
+    /// e.
+    pub mie_scale_height: f32,
+
+    /// The absorption coefficient of each color channel in the densest part of
+    /// the ozone layer.
+    pub ozone_absorption: Vec3,
+
+    /// The altitude of the densest part of the ozone layer.
+    pub ozone_center_altitude: f32,
+
+    /// The thickness of the ozone layer, whose density falls off linearly
+    /// above and below its center.
+    pub ozone_width: f32,
+}
+
+impl CloudAtmosphere {
+    /// The Earth's atmosphere, with the parameters from [Hillaire 2020].
+    ///
+    /// [Hillaire 2020]: https://sebh.github.io/publications/egsr2020.pdf
+    pub const EARTH: Self = Self {
+        bottom_radius: 6_360_000.0,
+        top_radius: 6_460_000.0,
+        scene_altitude: 0.0,
+        rayleigh_scattering: vec3(5.802e-6, 13.558e-6, 33.1e-6),
+        rayleigh_scale_height: 8_000.0,
+        mie_scattering: 3.996e-6,
+        mie_absorption: 4.40e-6,
+        mie_scale_height: 1_200.0,
+        ozone_absorption: vec3(0.650e-6, 1.881e-6, 0.085e-6),
+        ozone_center_altitude: 25_000.0,
+        ozone_width: 30_000.0,
+    };
+
+    /// Returns the extinction coefficient of each color channel at the given
+    /// altitude above sea level.
+    pub fn extinction(&self, altitude: f32) -> Vec3 {
+        let rayleigh_density = (-altitude / self.rayleigh_scale_height).exp();
+        let mie_density = (-altitude / self.mie_scale_height).exp();
+        let ozone_density = (1.0
+            - (altitude - self.ozone_center_altitude).abs() / (self.ozone_width * 0.5))
+            .max(0.0);
+
+        self.rayleigh_scattering * rayleigh_density
+            + Vec3::splat((self.mie_scattering + self.mie_absorption) * mie_density)
+            + self.ozone_absorption * ozone_density
+    }
+
+    /// Returns the transmittance of each color channel from a point at the
+    /// given altitude above sea level to the top of the atmosphere, along a
+    /// direction whose angle from the zenith has the cosine `mu`.
+    ///
+    /// Directions that hit the planet have a transmittance of zero.
+    pub fn transmittance(&self, altitude: f32, mu: f32) -> Vec3 {
+        // The planet is so much larger than the atmosphere that the geometry
+        // needs double precision near the horizon.
+        let bottom_radius = self.bottom_radius as f64;
+        let top_radius = self.top_radius as f64;
+        let r = (bottom_radius + altitude as f64).clamp(bottom_radius, top_radius);
+        let mu = mu.clamp(-1.0, 1.0) as f64;
+
+        // The ray hits the planet if it points downward and the discriminant
+        // of its intersection with the ground is positive.
+        let ground_discriminant = r * r * (mu * mu - 1.0) + bottom_radius * bottom_radius;
+        if mu < 0.0 && ground_discriminant >= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        // Integrate the extinction along the ray up to the top of the
+        // atmosphere, sampling at the middle of each step.
+        let top_discriminant = r * r * (mu * mu - 1.0) + top_radius * top_radius;
+        let ray_length = (-r * mu + top_discriminant.max(0.0).sqrt()).max(0.0);
+        let step_size = ray_length / TRANSMITTANCE_STEP_COUNT as f64;
+
+        let mut optical_depth = Vec3::ZERO;
+        for step in 0..TRANSMITTANCE_STEP_COUNT {
+            let t = (step as f64 + 0.5) * step_size;
+            let r_t = (r * r + t * t + 2.0 * r * mu * t).sqrt();
+            optical_depth += self.extinction((r_t - bottom_radius) as f32) * step_size as f32;
+        }
+
+        (-optical_depth).exp()
+    }
+}
+
+impl CloudAtmosphere {
+    /// Builds the [`ATMOSPHERE_TRANSMITTANCE_LUT`] image for this atmosphere.
+    ///
+    /// The shader must invert the mapping from texture coordinates in the same
+    /// way.
+    pub fn transmittance_lut_image(&self) -> Image {
+        let bottom_radius = self.bottom_radius;
+        let top_radius = self.top_radius;
+
+        // The distance to the top of the atmosphere from the ground, along the
+        // horizon.
+        let horizon = (top_radius * top_radius - bottom_radius * bottom_radius).sqrt();
+
+        let mut data = Vec::with_capacity(
+            (ATMOSPHERE_TRANSMITTANCE_LUT_WIDTH * ATMOSPHERE_TRANSMITTANCE_LUT_HEIGHT) as usize
+                * 16,
+        );
+        for y in 0..ATMOSPHERE_TRANSMITTANCE_LUT_HEIGHT {
+            // Texels are spaced evenly in the distance to the horizon, `rho`.
+            let x_r = unit_range_from_texel(y, ATMOSPHERE_TRANSMITTANCE_LUT_HEIGHT);
+            let rho = horizon * x_r;
+            let r = (rho * rho + bottom_radius * bottom_radius).sqrt();
+
+            for x in 0..ATMOSPHERE_TRANSMITTANCE_LUT_WIDTH {
+                // Texels are spaced evenly in the distance to the top of the
+                // atmosphere, between looking straight up and looking at the
+                // horizon.
+                let x_mu = unit_range_from_texel(x, ATMOSPHERE_TRANSMITTANCE_LUT_WIDTH);
+                let d_min = top_radius - r;
+                let d_max = rho + horizon;
+                let d = d_min + x_mu * (d_max - d_min);
+                let mu = if d == 0.0 {
+                    1.0
+                } else {
+                    (horizon * horizon - rho * rho - d * d) / (2.0 * r * d)
+                };
+
+                let transmittance = self.transmittance(r - bottom_radius, mu);
+                for value in transmittance.extend(1.0).to_array() {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+
+        Image::new(
+            Extent3d {
+                width: ATMOSPHERE_TRANSMITTANCE_LUT_WIDTH,
+                height: ATMOSPHERE_TRANSMITTANCE_LUT_HEIGHT,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba32Float,
+            RenderAssetUsages::RENDER_WORLD,
+        )
+    }
+}
+
+/// A system that rebuilds the [`ATMOSPHERE_TRANSMITTANCE_LUT`] whenever the
+/// [`CloudAtmosphere`] changes.
+pub fn update_atmosphere_transmittance_lut(
+    atmosphere: Res<CloudAtmosphere>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    images.insert(
+        &ATMOSPHERE_TRANSMITTANCE_LUT,
+        atmosphere.transmittance_lut_image(),
+    );
+}
+
+/// Maps the center of a texel to the range from 0 to 1, such that the first
+/// and last texel centers land exactly on 0 and 1.
+fn unit_range_from_texel(texel: u32, size: u32) -> f32 {
+    texel as f32 / (size - 1) as f32
+}
+
+impl Default for CloudAtmosphere {
+    fn default() -> Self {
+        Self::EARTH
+    }
+}
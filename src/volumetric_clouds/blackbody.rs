@@ -0,0 +1,131 @@
+//! Blackbody radiation, used to turn temperatures into emitted light.
+//!
+//! The emitted luminance of a blackbody spans many orders of magnitude between
+//! a dull red ember and a white-hot flash, so we precompute it on the CPU into
+//! a small lookup table that the cloud shader samples. The table is computed by
+//! integrating [Planck's law] against the CIE 1931 color matching functions,
+//! using the multi-lobe Gaussian fit from [Wyman et al. 2013].
+//!
+//! [Planck's law]: https://en.wikipedia.org/wiki/Planck%27s_law
+//!
+//! [Wyman et al. 2013]: https://jcgt.org/published/0002/02/01/
+
+use bevy::{
+    math::vec3,
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+};
+
+/// The blackbody lookup table.
+///
+/// Each texel stores the linear Rec. 709 chromaticity of a blackbody, scaled to
+/// a luminance of 1, in RGB and the base-2 logarithm of its luminance in cd/m²
+/// in alpha.
+pub const BLACKBODY_LUT: Handle<Image> = Handle::weak_from_u128(95081726374512907);
+
+/// The number of texels in the [`BLACKBODY_LUT`].
+pub const BLACKBODY_LUT_SIZE: u32 = 256;
+
+/// The coldest temperature, in kelvin, that the [`BLACKBODY_LUT`] covers.
+///
+/// Anything below this is too dim to see.
+pub const BLACKBODY_LUT_MIN_TEMPERATURE: f32 = 500.0;
+
+/// The hottest temperature, in kelvin, that the [`BLACKBODY_LUT`] covers.
+pub const BLACKBODY_LUT_MAX_TEMPERATURE: f32 = 40000.0;
+
+/// The luminous efficacy of radiation at 555 nm, in lumens per watt.
+const LUMINOUS_EFFICACY: f32 = 683.0;
+
+/// Planck's constant, in J·s.
+const PLANCK: f64 = 6.626_070_15e-34;
+/// The speed of light, in m/s.
+const SPEED_OF_LIGHT: f64 = 2.997_924_58e8;
+/// Boltzmann's constant, in J/K.
+const BOLTZMANN: f64 = 1.380_649e-23;
+
+/// Returns the luminance of a blackbody at the given temperature, in kelvin,
+/// as a linear Rec. 709 color in cd/m².
+pub fn blackbody_luminance(temperature: f32) -> Vec3 {
+    let temperature = temperature as f64;
+
+    // Integrate the spectral radiance against the color matching functions
+    // over the visible spectrum, in 5 nm steps.
+    let mut xyz = Vec3::ZERO;
+    for nanometers in (360..=830).step_by(5) {
+        let wavelength = nanometers as f64 * 1.0e-9;
+        let spectral_radiance = 2.0 * PLANCK * SPEED_OF_LIGHT * SPEED_OF_LIGHT
+            / (wavelength.powi(5)
+                * ((PLANCK * SPEED_OF_LIGHT / (wavelength * BOLTZMANN * temperature)).exp() - 1.0));
+        xyz += color_matching_functions(nanometers as f32) * (spectral_radiance * 5.0e-9) as f32;
+    }
+
+    xyz_to_linear_rec709(xyz * LUMINOUS_EFFICACY).max(Vec3::ZERO)
+}
+
+/// Builds the [`BLACKBODY_LUT`] image.
+///
+/// Texels are spaced evenly in reciprocal temperature, which is where the color
+/// of a blackbody changes most evenly. The shader must invert this mapping in
+/// the same way.
+pub fn blackbody_lut_image() -> Image {
+    let mut data = Vec::with_capacity(BLACKBODY_LUT_SIZE as usize * 16);
+    for texel in 0..BLACKBODY_LUT_SIZE {
+        let u = texel as f32 / (BLACKBODY_LUT_SIZE - 1) as f32;
+        let inverse_temperature =
+            (1.0 - u) / BLACKBODY_LUT_MIN_TEMPERATURE + u / BLACKBODY_LUT_MAX_TEMPERATURE;
+
+        let color = blackbody_luminance(1.0 / inverse_temperature);
+        let luminance = color
+            .dot(vec3(0.2126, 0.7152, 0.0722))
+            .max(f32::MIN_POSITIVE);
+
+        for value in (color / luminance).extend(luminance.log2()).to_array() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    Image::new(
+        Extent3d {
+            width: BLACKBODY_LUT_SIZE,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba32Float,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+/// The CIE 1931 2° color matching functions x̄, ȳ, and z̄ at the given
+/// wavelength, in nanometers.
+fn color_matching_functions(wavelength: f32) -> Vec3 {
+    // A Gaussian with separate widths on either side of the mean.
+    let g = |mean: f32, left_width: f32, right_width: f32| {
+        let width = if wavelength < mean {
+            left_width
+        } else {
+            right_width
+        };
+        (-0.5 * ((wavelength - mean) / width).powi(2)).exp()
+    };
+
+    vec3(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// Converts CIE XYZ to linear Rec. 709 (sRGB primaries, D65 white point).
+fn xyz_to_linear_rec709(xyz: Vec3) -> Vec3 {
+    Mat3::from_cols(
+        vec3(3.240_454_2, -0.969_266, 0.055_643_4),
+        vec3(-1.537_138_5, 1.876_010_8, -0.204_025_9),
+        vec3(-0.498_531_4, 0.041_556, 1.057_225_2),
+    ) * xyz
+}
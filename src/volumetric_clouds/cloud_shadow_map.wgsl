@@ -0,0 +1,73 @@
+// Renders cloud volumes into the cloud shadow map, as seen from the sun.
+//
+// Each fragment is a point where the light enters the volume. We trace the
+// light onward through the volume to find its transmittance, and output that
+// along with the light-space depth of the entry point. The blender multiplies
+// the transmittance of overlapping volumes together and keeps the closest
+// depth. See `air.rs`.
+
+// A single cloud volume. See `CloudShadowMapUniform` in `air.rs`.
+struct CloudVolume {
+    clip_from_local: mat4x4<f32>,
+    light_direction: vec3<f32>,
+    step_count: u32,
+    extinction: vec3<f32>,
+}
+
+@group(0) @binding(0) var<uniform> cloud_volume: CloudVolume;
+
+#ifdef DENSITY_TEXTURE
+@group(0) @binding(2) var density_texture: texture_3d<f32>;
+@group(0) @binding(3) var density_sampler: sampler;
+#endif  // DENSITY_TEXTURE
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) local_position: vec3<f32>,
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var output: VertexOutput;
+    output.position = cloud_volume.clip_from_local * vec4(vertex.position, 1.0);
+    output.local_position = vertex.position;
+    return output;
+}
+
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4<f32> {
+    let Ro_local = input.local_position;
+    let Rd_local = cloud_volume.light_direction;
+
+    // Find where the light leaves the 1×1×1 box, measured in world units. Axes
+    // that the light runs parallel to never bound it.
+    let t_planes = select(
+        (sign(Rd_local) * 0.5 - Ro_local) / Rd_local,
+        vec3(1e30),
+        abs(Rd_local) < vec3(1e-8)
+    );
+    let ray_length = max(min(t_planes.x, min(t_planes.y, t_planes.z)), 0.0);
+
+#ifdef DENSITY_TEXTURE
+    // March through the density texture, sampling at the middle of each step.
+    let step_count = cloud_volume.step_count;
+    let step_size = ray_length / f32(step_count);
+    var density_sum = 0.0;
+    for (var step = 0u; step < step_count; step += 1u) {
+        let P_uvw = Ro_local + Rd_local * ((f32(step) + 0.5) * step_size) + 0.5;
+        if (all(P_uvw >= vec3(0.0)) && all(P_uvw <= vec3(1.0))) {
+            density_sum += textureSampleLevel(density_texture, density_sampler, P_uvw, 0.0).r;
+        }
+    }
+    let optical_depth = cloud_volume.extinction * density_sum * step_size;
+#else   // DENSITY_TEXTURE
+    // The density is uniform, so Beer's law applies to the whole path at once.
+    let optical_depth = cloud_volume.extinction * ray_length;
+#endif  // DENSITY_TEXTURE
+
+    return vec4(exp(-optical_depth), input.position.z);
+}
@@ -0,0 +1,164 @@
+// A fullscreen shader that blends the offscreen clouds of this frame into the
+// reprojected history of previous frames.
+//
+// It runs at the resolution of the offscreen targets, after the cloud volumes
+// are drawn and before they're upsampled onto the view. See `temporal.rs`.
+
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import bevy_pbr::mesh_view_bindings::{globals, view}
+#import bevy_pbr::view_transformations::{
+    depth_ndc_to_view_z,
+    ndc_to_uv,
+    position_ndc_to_world,
+    uv_to_ndc,
+}
+
+// The GPU version of `CloudTemporalAccumulation`. See `CloudTemporalUniform` in
+// `temporal.rs`.
+struct CloudTemporal {
+    previous_clip_from_world: mat4x4<f32>,
+    previous_view_from_world: mat4x4<f32>,
+    current_weight: f32,
+    disocclusion_threshold: f32,
+    update_pattern_size: u32,
+}
+
+@group(1) @binding(0) var<uniform> temporal: CloudTemporal;
+@group(1) @binding(1) var cloud_color: texture_2d<f32>;
+@group(1) @binding(2) var cloud_transmittance: texture_2d<f32>;
+@group(1) @binding(3) var cloud_motion: texture_2d<f32>;
+@group(1) @binding(4) var history_color: texture_2d<f32>;
+@group(1) @binding(5) var history_transmittance: texture_2d<f32>;
+@group(1) @binding(6) var history_sampler: sampler;
+
+#ifdef MULTISAMPLED
+@group(1) @binding(7) var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(1) @binding(7) var depth_texture: texture_depth_2d;
+#endif
+
+// Below this opacity, a texel has no clouds to take the motion of.
+const MIN_MOTION_OPACITY: f32 = 1e-3;
+
+// Inverse depths closer than this, about 10 km apart, are always considered to
+// match. This keeps the sky from flickering between valid and disoccluded.
+const INVERSE_DEPTH_EPSILON: f32 = 1e-4;
+
+// The order in which the pixels of each 4×4 block are raymarched. See
+// `volumetric_clouds.wgsl`.
+const BAYER_4X4: array<u32, 16> = array<u32, 16>(
+    0u, 8u, 2u, 10u,
+    12u, 4u, 14u, 6u,
+    3u, 11u, 1u, 9u,
+    15u, 7u, 13u, 5u,
+);
+
+// Returns the pixel of the update pattern block containing the given texel that
+// was raymarched in this frame. See `is_updated_this_frame` in
+// `volumetric_clouds.wgsl`.
+fn updated_texel_in_block(texel: vec2<i32>, size: vec2<i32>) -> vec2<i32> {
+    let pattern_size = temporal.update_pattern_size;
+    let pixel_count = pattern_size * pattern_size;
+    let rank = globals.frame_count % pixel_count;
+
+    var bayer = BAYER_4X4;
+    var cell = vec2(0u);
+    for (var i = 0u; i < pixel_count; i += 1u) {
+        let candidate = vec2(i % pattern_size, i / pattern_size);
+        if (bayer[candidate.y * 4u + candidate.x] / (16u / pixel_count) == rank) {
+            cell = candidate;
+        }
+    }
+
+    let block = texel - texel % i32(pattern_size);
+    return min(block + vec2<i32>(cell), size - 1);
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    @location(1) transmittance: vec4<f32>,
+}
+
+@fragment
+fn fragment(input: FullscreenVertexOutput) -> FragmentOutput {
+    let texel = vec2<i32>(input.position.xy);
+    let size = vec2<i32>(textureDimensions(cloud_color));
+    let uv = input.position.xy / vec2<f32>(size);
+
+    // With an update pattern, only one texel in each block was raymarched in
+    // this frame. Take the new sample and the motion from that one.
+    let source_texel = updated_texel_in_block(texel, size);
+    let updated = all(source_texel == texel);
+    let pattern_size = i32(temporal.update_pattern_size);
+
+    let color = textureLoad(cloud_color, source_texel, 0).rgb;
+    let transmittance = textureLoad(cloud_transmittance, source_texel, 0).rgb;
+
+    // Find the range of the new samples around this texel, which the history
+    // is clamped to. Only the raymarched texels of the neighboring blocks
+    // hold new samples.
+    var color_min = color;
+    var color_max = color;
+    var transmittance_min = transmittance;
+    var transmittance_max = transmittance;
+    for (var y = -1; y <= 1; y += 1) {
+        for (var x = -1; x <= 1; x += 1) {
+            let neighbor = clamp(source_texel + vec2(x, y) * pattern_size, vec2(0), size - 1);
+            let neighbor_color = textureLoad(cloud_color, neighbor, 0).rgb;
+            let neighbor_transmittance = textureLoad(cloud_transmittance, neighbor, 0).rgb;
+            color_min = min(color_min, neighbor_color);
+            color_max = max(color_max, neighbor_color);
+            transmittance_min = min(transmittance_min, neighbor_transmittance);
+            transmittance_max = max(transmittance_max, neighbor_transmittance);
+        }
+    }
+
+    // Find the scene behind the clouds. Like the clouds themselves, this uses
+    // the full-resolution pixel at the center of the texel. A depth of zero is
+    // the far plane at infinity, so clamp it to a large but finite distance.
+    let frag_coord = input.position.xy * view.viewport.zw / vec2<f32>(size);
+    let ndc_depth = max(textureLoad(depth_texture, vec2<i32>(frag_coord), 0), 1e-7);
+    let inverse_depth = -1.0 / depth_ndc_to_view_z(ndc_depth);
+    let P_world = position_ndc_to_world(vec3(uv_to_ndc(uv), ndc_depth));
+
+    // Follow the clouds back to where they were in the previous frame. Where
+    // there are none, follow the scene instead.
+    let motion = textureLoad(cloud_motion, source_texel, 0);
+    var previous_uv: vec2<f32>;
+    if (motion.a > MIN_MOTION_OPACITY) {
+        previous_uv = uv - motion.rg / motion.a;
+    } else {
+        let previous_clip = temporal.previous_clip_from_world * vec4(P_world, 1.0);
+        previous_uv = ndc_to_uv(previous_clip.xy / previous_clip.w);
+    }
+
+    let history = textureSampleLevel(history_color, history_sampler, previous_uv, 0.0);
+    let history_T = textureSampleLevel(history_transmittance, history_sampler, previous_uv, 0.0);
+
+    // Drop the history if it's off screen, or if the scene behind it was at a
+    // different depth than the scene behind this texel was in the previous
+    // frame. Texels that weren't raymarched keep the history otherwise, and
+    // fall back to the new sample of their block when there's none.
+    let expected_inverse_depth = -1.0 / (temporal.previous_view_from_world * vec4(P_world, 1.0)).z;
+    let depth_difference = abs(history.a - expected_inverse_depth);
+    let disoccluded = depth_difference >
+        temporal.disocclusion_threshold * max(history.a, expected_inverse_depth) +
+        INVERSE_DEPTH_EPSILON;
+    let off_screen = any(previous_uv < vec2(0.0)) || any(previous_uv > vec2(1.0));
+    let current_weight = select(
+        select(0.0, temporal.current_weight, updated),
+        1.0,
+        disoccluded || off_screen || temporal.current_weight >= 1.0
+    );
+
+    var output: FragmentOutput;
+    output.color = vec4(
+        mix(clamp(history.rgb, color_min, color_max), color, current_weight),
+        inverse_depth
+    );
+    output.transmittance = vec4(
+        mix(clamp(history_T.rgb, transmittance_min, transmittance_max), transmittance, current_weight),
+        1.0
+    );
+    return output;
+}
@@ -0,0 +1,83 @@
+// A fullscreen shader that upsamples clouds rendered below full resolution and
+// composites them onto the view.
+//
+// Each of the four nearest low-resolution texels is weighted bilinearly and by
+// how closely the depth that it was raymarched against matches the depth of
+// this pixel, so that clouds don't bleed across the silhouettes of the scene.
+// See `upsample.rs`.
+
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import bevy_pbr::mesh_view_bindings::view
+#import bevy_pbr::view_transformations::depth_ndc_to_view_z
+
+@group(1) @binding(0) var cloud_color: texture_2d<f32>;
+@group(1) @binding(1) var cloud_transmittance: texture_2d<f32>;
+
+#ifdef MULTISAMPLED
+@group(1) @binding(2) var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(1) @binding(2) var depth_texture: texture_depth_2d;
+#endif
+
+// Keeps the depth weights finite when the depths match exactly.
+const DEPTH_WEIGHT_EPSILON: f32 = 0.01;
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef DUAL_SOURCE_BLENDING
+    @location(0) @second_blend_source transmittance: vec4<f32>,
+#endif  // DUAL_SOURCE_BLENDING
+}
+
+// Returns the linear view-space depth of the scene at the given full-resolution
+// pixel. A depth of zero is the far plane at infinity, so clamp it to a large
+// but finite distance.
+fn scene_view_depth(frag_coord: vec2<f32>) -> f32 {
+    let ndc_depth = textureLoad(depth_texture, vec2<i32>(frag_coord), 0);
+    return -depth_ndc_to_view_z(max(ndc_depth, 1e-7));
+}
+
+@fragment
+fn fragment(input: FullscreenVertexOutput) -> FragmentOutput {
+    let frag_coord = input.position.xy;
+    let low_resolution_size = vec2<i32>(textureDimensions(cloud_color));
+    let scale = vec2<f32>(low_resolution_size) / view.viewport.zw;
+
+    // Find the four low-resolution texels around this pixel, and how far
+    // between them this pixel is.
+    let texel = frag_coord * scale - 0.5;
+    let base_texel = vec2<i32>(floor(texel));
+    let bilinear = fract(texel);
+
+    let depth = scene_view_depth(frag_coord);
+
+    var color = vec3(0.0);
+    var transmittance = vec3(0.0);
+    var weight_sum = 0.0;
+    for (var i = 0; i < 4; i += 1) {
+        let offset = vec2(i & 1, i >> 1u);
+        let sample_texel = clamp(base_texel + offset, vec2(0), low_resolution_size - 1);
+
+        // The clouds in each texel were raymarched against the depth of the
+        // full-resolution pixel at its center. See `volumetric_clouds.wgsl`.
+        let sample_depth = scene_view_depth((vec2<f32>(sample_texel) + 0.5) / scale);
+        let depth_weight = 1.0 / (DEPTH_WEIGHT_EPSILON + abs(sample_depth - depth) / depth);
+
+        let bilinear_weights = mix(1.0 - bilinear, bilinear, vec2<f32>(offset));
+        let weight = bilinear_weights.x * bilinear_weights.y * depth_weight;
+
+        color += textureLoad(cloud_color, sample_texel, 0).rgb * weight;
+        transmittance += textureLoad(cloud_transmittance, sample_texel, 0).rgb * weight;
+        weight_sum += weight;
+    }
+    color /= weight_sum;
+    transmittance /= weight_sum;
+
+    // Blend the same way as the clouds do at full resolution.
+    var output: FragmentOutput;
+    output.color = vec4(color, 1.0 - dot(transmittance, vec3(1.0 / 3.0)));
+#ifdef DUAL_SOURCE_BLENDING
+    output.transmittance = vec4(transmittance, 1.0);
+#endif  // DUAL_SOURCE_BLENDING
+    return output;
+}
@@ -0,0 +1,630 @@
+//! The compute backend of the cloud raymarch.
+//!
+//! With [`VolumetricCloudBackend::Compute`], the clouds of a view are
+//! raymarched in a compute shader instead of by rasterizing the hull of each
+//! volume. First, the projected bounds of the volumes are binned into the 8×8
+//! tiles of the offscreen targets on the CPU, giving each tile a list of the
+//! volumes that overlap it, sorted front to back by their nearest depth. Then
+//! a single dispatch over the tiles raymarches, for each pixel, only the
+//! volumes in its tile's list, in that order, so many small volumes cost
+//! little more than one large one. Each workgroup first finds the farthest
+//! depth of the scene in its tile, and stops at the first volume that the
+//! scene hides everywhere in it.
+//!
+//! Each pixel cuts its ray into slices wherever it enters or leaves one of its
+//! volumes, and marches the slices front to back. Where volumes overlap, they
+//! are marched together: each step sums the extinction and the light of all of
+//! them, so that interpenetrating clouds dim each other, which the raster
+//! backend can't do.
+//!
+//! Volumes that need different pipelines or textures can't share a dispatch,
+//! so they're split into batches. The list of each tile is cut into runs of
+//! consecutive volumes of the same batch, and the runs are dispatched in
+//! layers: the first run of every tile in the first layer, the second in the
+//! second, and so on, with one dispatch per batch in each layer. So each tile
+//! still raymarches its volumes front to back, even where volumes of different
+//! batches alternate. Runs are also cut at `MAX_PIXEL_CLOUD_VOLUMES`, with a
+//! warning. Only volumes in the same run dim each other where they
+//! interpenetrate; the runs are composited one behind another.
+//!
+//! Lights aren't culled per tile. Directional lights reach every tile, and a
+//! lightning flash only lights the volume that it starts in, so binning the
+//! volumes already culls it.
+//!
+//! The offscreen targets are storage textures in this backend. The shader
+//! composites each dispatch under the ones before it by hand, with the same
+//! equations that the raster backend blends with, so the temporal
+//! accumulation and upsampling passes afterward are shared.
+//!
+//! The mesh view bind group is only visible to the vertex and fragment stages,
+//! so the compute shader gets its own view bind group, holding just the
+//! bindings of the mesh view bind group that the raymarch uses, at the same
+//! indices.
+
+use std::{mem, num::NonZeroU64};
+
+use bevy::{
+    math::uvec2,
+    pbr::{FogMeta, GpuFog, GpuLights, LightMeta, ShadowSamplers, ViewShadowBindings},
+    prelude::*,
+    render::{
+        globals::{GlobalsBuffer, GlobalsUniform},
+        render_asset::RenderAssets,
+        render_resource::{
+            binding_types::{
+                sampler, storage_buffer_read_only_sized, texture_2d_array, texture_storage_2d,
+                uniform_buffer,
+            },
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BindingResource,
+            BufferBinding, CachedComputePipelineId, ComputePassDescriptor,
+            ComputePipelineDescriptor, DynamicBindGroupEntries, PipelineCache, SamplerBindingType,
+            ShaderStages, SpecializedComputePipeline, StorageBuffer, StorageTextureAccess,
+            TextureSampleType,
+        },
+        renderer::{RenderAdapter, RenderContext, RenderDevice, RenderQueue},
+        settings::WgpuFeatures,
+        texture::GpuImage,
+        view::{ExtractedView, ViewUniform, ViewUniforms},
+    },
+    utils::HashMap,
+};
+use wgpu::TextureFormatFeatureFlags;
+
+use crate::volumetric_clouds::{
+    render::{
+        ViewVolumetricCloud, VolumetricCloudPipeline, VolumetricCloudPipelineKeyFlags,
+        VolumetricCloudUniform,
+    },
+    upsample::{ViewCloudOffscreenTextures, CLOUD_OFFSCREEN_FORMAT},
+    VolumetricCloudBackend, VolumetricCloudSettings,
+};
+
+/// The side length, in texels, of the tiles that each workgroup raymarches.
+///
+/// This must match the workgroup size in `volumetric_clouds.wgsl`.
+const CLOUD_COMPUTE_TILE_SIZE: u32 = 8;
+
+/// The most cloud volumes in the list of a tile in a single dispatch, which
+/// each pixel raymarches together.
+///
+/// Longer runs are split into several, which are composited one behind
+/// another instead of dimming each other. This must match the constant in
+/// `volumetric_clouds.wgsl`.
+const MAX_PIXEL_CLOUD_VOLUMES: usize = 8;
+
+/// The GPU pipeline that raymarches the clouds in a compute shader.
+#[derive(Resource)]
+pub struct CloudComputePipeline {
+    /// The raster pipeline, whose shader and cloud volume bind group layouts
+    /// this shares.
+    raymarch_pipeline: VolumetricCloudPipeline,
+    /// The layout of the parts of the mesh view bind group that the raymarch
+    /// uses.
+    view_bind_group_layout: BindGroupLayout,
+    /// The layouts of the offscreen targets and the tile bins, without and
+    /// with the motion target.
+    output_bind_group_layouts: [BindGroupLayout; 2],
+    /// Whether the device supports read-write storage textures in
+    /// [`CLOUD_OFFSCREEN_FORMAT`]. If not, views that ask for the compute
+    /// backend fall back to the raster backend.
+    pub supported: bool,
+}
+
+/// Identifies a single specialization of the cloud compute shader.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct CloudComputePipelineKey {
+    /// Flags that specify features on the pipeline key.
+    pub(crate) flags: VolumetricCloudPipelineKeyFlags,
+    /// Whether the depth texture is multisampled.
+    pub(crate) multisampled: bool,
+}
+
+/// The compute pipelines that we use for the cloud volumes in a view, keyed by
+/// the features that each volume needs.
+///
+/// Only views that use [`VolumetricCloudBackend::Compute`] have these.
+#[derive(Component, Default)]
+pub struct ViewCloudComputePipelines(
+    pub(crate) HashMap<VolumetricCloudPipelineKeyFlags, CachedComputePipelineId>,
+);
+
+/// The view bind group of the compute backend, for a view that uses it.
+#[derive(Component, Deref, DerefMut)]
+pub struct ViewCloudComputeBindGroup(pub BindGroup);
+
+/// The GPU buffer that stores a copy of the [`VolumetricCloudUniform`] of each
+/// cloud volume in the views that use the compute backend, so that the shader
+/// can read those of all the volumes in a tile.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct CloudComputeVolumeBuffer(pub StorageBuffer<Vec<VolumetricCloudUniform>>);
+
+/// The GPU buffers that store the lists of cloud volumes that overlap each
+/// tile, for all views that use the compute backend.
+#[derive(Resource, Default)]
+pub struct CloudTileBinBuffers {
+    /// The offset within [`Self::tile_volumes`] and the length of the list of
+    /// each tile, for every tile of every dispatch. The tiles of each dispatch
+    /// start at a multiple of the storage buffer offset alignment, since
+    /// they're bound at a dynamic offset.
+    tile_ranges: StorageBuffer<Vec<UVec2>>,
+    /// The indices of the volumes within the [`CloudComputeVolumeBuffer`],
+    /// front to back within each list.
+    tile_volumes: StorageBuffer<Vec<u32>>,
+}
+
+/// The tiles of the offscreen targets of a view that uses the compute backend,
+/// and the dispatches that raymarch the volumes binned into them.
+#[derive(Component)]
+pub struct ViewCloudTileBins {
+    /// The number of tiles across and down the offscreen targets.
+    tile_count: UVec2,
+    /// The batches of volumes, front to back by their nearest volume.
+    batches: Vec<CloudTileBatch>,
+    /// The dispatches, layer by layer.
+    dispatches: Vec<CloudTileDispatch>,
+}
+
+/// Cloud volumes in a view that share a pipeline and textures, and so can be
+/// raymarched in the same dispatch.
+struct CloudTileBatch {
+    /// The features of the pipeline that the volumes need.
+    flags: VolumetricCloudPipelineKeyFlags,
+    /// The index within the [`ViewVolumetricCloud`] of one of the volumes,
+    /// whose bind group supplies the textures of all of them.
+    volume_index: usize,
+}
+
+/// A single dispatch, which raymarches the runs of one batch that are in the
+/// same layer.
+struct CloudTileDispatch {
+    /// The index of the batch within the batches of the view.
+    batch_index: usize,
+    /// The offset, in bytes, of the tiles of this dispatch within
+    /// [`CloudTileBinBuffers::tile_ranges`].
+    tile_ranges_offset: u32,
+}
+
+impl CloudComputePipeline {
+    /// Returns true if a view with the given settings raymarches its clouds
+    /// with this pipeline.
+    pub(crate) fn is_used_by(&self, volumetric_cloud_settings: &VolumetricCloudSettings) -> bool {
+        self.supported && volumetric_cloud_settings.backend == VolumetricCloudBackend::Compute
+    }
+}
+
+impl FromWorld for CloudComputePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let render_adapter = world.resource::<RenderAdapter>();
+
+        let view_bind_group_layout = render_device.create_bind_group_layout(
+            "cloud compute view bind group layout",
+            &BindGroupLayoutEntries::with_indices(
+                ShaderStages::COMPUTE,
+                (
+                    // `view`
+                    (0, uniform_buffer::<ViewUniform>(true)),
+                    // `lights`
+                    (1, uniform_buffer::<GpuLights>(true)),
+                    // `directional_shadow_textures` and
+                    // `directional_shadow_textures_sampler`
+                    (4, texture_2d_array(TextureSampleType::Depth)),
+                    (5, sampler(SamplerBindingType::Comparison)),
+                    // `globals`
+                    (9, uniform_buffer::<GlobalsUniform>(false)),
+                    // `fog`
+                    (10, uniform_buffer::<GpuFog>(true)),
+                ),
+            ),
+        );
+
+        let output_bind_group_layouts = [false, true].map(|temporal| {
+            let storage_texture =
+                texture_storage_2d(CLOUD_OFFSCREEN_FORMAT, StorageTextureAccess::ReadWrite);
+            let mut entries = BindGroupLayoutEntries::with_indices(
+                ShaderStages::COMPUTE,
+                (
+                    // `cloud_color` and `cloud_transmittance`
+                    (0, storage_texture),
+                    (1, storage_texture),
+                    // `cloud_volumes`, `tile_ranges`, and `tile_volumes`
+                    (3, storage_buffer_read_only_sized(false, None)),
+                    (4, storage_buffer_read_only_sized(true, None)),
+                    (5, storage_buffer_read_only_sized(false, None)),
+                ),
+            )
+            .to_vec();
+            // `cloud_motion`
+            if temporal {
+                entries.extend_from_slice(&BindGroupLayoutEntries::with_indices(
+                    ShaderStages::COMPUTE,
+                    ((2, storage_texture),),
+                ));
+            }
+            render_device
+                .create_bind_group_layout("cloud compute output bind group layout", &entries)
+        });
+
+        // Compositing reads the offscreen targets back, which only some
+        // devices can do in this format.
+        let supported = render_device
+            .features()
+            .contains(WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+            && render_adapter
+                .get_texture_format_features(CLOUD_OFFSCREEN_FORMAT)
+                .flags
+                .contains(TextureFormatFeatureFlags::STORAGE_READ_WRITE);
+
+        CloudComputePipeline {
+            raymarch_pipeline: world.resource::<VolumetricCloudPipeline>().clone(),
+            view_bind_group_layout,
+            output_bind_group_layouts,
+            supported,
+        }
+    }
+}
+
+impl SpecializedComputePipeline for CloudComputePipeline {
+    type Key = CloudComputePipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        let temporal = key
+            .flags
+            .contains(VolumetricCloudPipelineKeyFlags::TEMPORAL);
+
+        let mut shader_defs = key.flags.shader_defs(key.multisampled);
+        shader_defs.push("COMPUTE".into());
+        shader_defs.push("OFFSCREEN".into());
+        if temporal {
+            shader_defs.push("TEMPORAL".into());
+        }
+
+        ComputePipelineDescriptor {
+            label: Some("cloud compute pipeline".into()),
+            layout: vec![
+                self.view_bind_group_layout.clone(),
+                self.raymarch_pipeline
+                    .volumetric_view_bind_group_layout(key.flags, key.multisampled)
+                    .clone(),
+                self.output_bind_group_layouts[temporal as usize].clone(),
+            ],
+            push_constant_ranges: vec![],
+            shader: self.raymarch_pipeline.shader.clone(),
+            shader_defs,
+            entry_point: "compute".into(),
+        }
+    }
+}
+
+/// Creates the view bind groups of the compute backend, for all views that use
+/// it.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_cloud_compute_bind_groups(
+    mut commands: Commands,
+    view_targets: Query<(Entity, &ViewShadowBindings, &VolumetricCloudSettings)>,
+    compute_pipeline: Res<CloudComputePipeline>,
+    view_uniforms: Res<ViewUniforms>,
+    light_meta: Res<LightMeta>,
+    globals_buffer: Res<GlobalsBuffer>,
+    fog_meta: Res<FogMeta>,
+    shadow_samplers: Res<ShadowSamplers>,
+    render_device: Res<RenderDevice>,
+) {
+    if !compute_pipeline.supported {
+        return;
+    }
+
+    let (Some(view_binding), Some(light_binding), Some(globals_binding), Some(fog_binding)) = (
+        view_uniforms.uniforms.binding(),
+        light_meta.view_gpu_lights.binding(),
+        globals_buffer.buffer.binding(),
+        fog_meta.gpu_fogs.binding(),
+    ) else {
+        return;
+    };
+
+    for (entity, shadow_bindings, volumetric_cloud_settings) in view_targets.iter() {
+        if !compute_pipeline.is_used_by(volumetric_cloud_settings) {
+            continue;
+        }
+
+        let bind_group = render_device.create_bind_group(
+            "cloud compute view bind group",
+            &compute_pipeline.view_bind_group_layout,
+            &BindGroupEntries::with_indices((
+                (0, view_binding.clone()),
+                (1, light_binding.clone()),
+                (4, &shadow_bindings.directional_light_depth_texture_view),
+                (5, &shadow_samplers.directional_light_sampler),
+                (9, globals_binding.clone()),
+                (10, fog_binding.clone()),
+            )),
+        );
+
+        commands
+            .entity(entity)
+            .insert(ViewCloudComputeBindGroup(bind_group));
+    }
+}
+
+/// Bins the cloud volumes of each view that uses the compute backend into the
+/// tiles of its offscreen targets.
+///
+/// The list of each tile holds the volumes whose projected bounds overlap it,
+/// sorted front to back by their nearest depth. The volumes are grouped into
+/// batches that share a pipeline and textures, and the list is cut into runs
+/// of the same batch, which are dispatched layer by layer.
+pub fn prepare_cloud_tile_bins(
+    mut commands: Commands,
+    view_targets: Query<(
+        Entity,
+        &ExtractedView,
+        &VolumetricCloudSettings,
+        &ViewVolumetricCloud,
+    )>,
+    compute_pipeline: Res<CloudComputePipeline>,
+    mut tile_bin_buffers: ResMut<CloudTileBinBuffers>,
+    images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let CloudTileBinBuffers {
+        tile_ranges: tile_ranges_buffer,
+        tile_volumes: tile_volumes_buffer,
+    } = &mut *tile_bin_buffers;
+    let tile_ranges = tile_ranges_buffer.get_mut();
+    let tile_volumes = tile_volumes_buffer.get_mut();
+    tile_ranges.clear();
+    tile_volumes.clear();
+
+    let tile_ranges_alignment = (render_device.limits().min_storage_buffer_offset_alignment
+        as usize
+        / mem::size_of::<UVec2>())
+    .max(1);
+
+    for (view_entity, extracted_view, volumetric_cloud_settings, view_cloud_volumes) in
+        view_targets.iter()
+    {
+        if !compute_pipeline.is_used_by(volumetric_cloud_settings) {
+            continue;
+        }
+
+        let target_size = volumetric_cloud_settings
+            .resolution_scale
+            .target_size(extracted_view.viewport.zw());
+        let tile_count = (target_size + (CLOUD_COMPUTE_TILE_SIZE - 1)) / CLOUD_COMPUTE_TILE_SIZE;
+
+        // Sort the volumes that are on screen, and whose textures have
+        // loaded, front to back.
+        let mut volumes: Vec<_> = view_cloud_volumes
+            .iter()
+            .enumerate()
+            .filter(|(_, view_cloud_volume)| !view_cloud_volume.screen_rect.is_empty())
+            .filter_map(|(volume_index, view_cloud_volume)| {
+                Some((
+                    volume_index,
+                    view_cloud_volume,
+                    view_cloud_volume.pipeline_flags(&images)?,
+                    view_cloud_volume.compute_volume_index?,
+                ))
+            })
+            .collect();
+        volumes.sort_by(|(_, volume_a, ..), (_, volume_b, ..)| {
+            volume_a
+                .nearest_view_depth
+                .total_cmp(&volume_b.nearest_view_depth)
+        });
+
+        // Group them into batches, which also puts the batches in order of
+        // their nearest volume.
+        let mut batches: Vec<CloudTileBatch> = vec![];
+        let mut binned_volumes = Vec::with_capacity(volumes.len());
+        for (volume_index, view_cloud_volume, flags, compute_volume_index) in volumes {
+            let batch_index = batches
+                .iter()
+                .position(|batch| {
+                    let batch_volume = &view_cloud_volumes[batch.volume_index];
+                    batch.flags == flags
+                        && batch_volume.density_texture == view_cloud_volume.density_texture
+                        && batch_volume.emission_texture == view_cloud_volume.emission_texture
+                })
+                .unwrap_or_else(|| {
+                    batches.push(CloudTileBatch {
+                        flags,
+                        volume_index,
+                    });
+                    batches.len() - 1
+                });
+            binned_volumes.push((
+                view_cloud_volume.screen_rect,
+                batch_index,
+                compute_volume_index,
+            ));
+        }
+
+        // Gather the list of each tile, front to back.
+        let mut tile_lists = vec![vec![]; (tile_count.x * tile_count.y) as usize];
+        for &(screen_rect, batch_index, compute_volume_index) in &binned_volumes {
+            let min_tile = screen_rect.min / CLOUD_COMPUTE_TILE_SIZE;
+            let max_tile =
+                (screen_rect.max + (CLOUD_COMPUTE_TILE_SIZE - 1)) / CLOUD_COMPUTE_TILE_SIZE;
+            for y in min_tile.y..max_tile.y {
+                for x in min_tile.x..max_tile.x {
+                    tile_lists[(y * tile_count.x + x) as usize]
+                        .push((batch_index, compute_volume_index));
+                }
+            }
+        }
+
+        // Cut each list into runs of the same batch, no longer than the
+        // shader can raymarch together.
+        let mut runs_split = false;
+        let tile_runs: Vec<Vec<&[(usize, u32)]>> = tile_lists
+            .iter()
+            .map(|tile_list| {
+                tile_list
+                    .chunk_by(|(batch_a, _), (batch_b, _)| batch_a == batch_b)
+                    .flat_map(|run| {
+                        runs_split |= run.len() > MAX_PIXEL_CLOUD_VOLUMES;
+                        run.chunks(MAX_PIXEL_CLOUD_VOLUMES)
+                    })
+                    .collect()
+            })
+            .collect();
+        if runs_split {
+            warn_once!(
+                "More than {} cloud volumes overlap a tile of the screen; the rest are \
+                composited behind them instead of raymarched with them, so they don't dim \
+                them where they interpenetrate",
+                MAX_PIXEL_CLOUD_VOLUMES
+            );
+        }
+        let layer_count = tile_runs.iter().map(Vec::len).max().unwrap_or_default();
+
+        // Lay out the tiles of each dispatch, layer by layer, with the runs of
+        // its batch in that layer.
+        let mut dispatches = vec![];
+        for layer in 0..layer_count {
+            for batch_index in 0..batches.len() {
+                let run_in_dispatch = |tile: usize| {
+                    tile_runs[tile]
+                        .get(layer)
+                        .filter(|run| run[0].0 == batch_index)
+                        .copied()
+                };
+                if !(0..tile_runs.len()).any(|tile| run_in_dispatch(tile).is_some()) {
+                    continue;
+                }
+
+                tile_ranges.resize(
+                    tile_ranges.len().next_multiple_of(tile_ranges_alignment),
+                    UVec2::ZERO,
+                );
+                dispatches.push(CloudTileDispatch {
+                    batch_index,
+                    tile_ranges_offset: (tile_ranges.len() * mem::size_of::<UVec2>()) as u32,
+                });
+
+                for tile in 0..tile_runs.len() {
+                    let run = run_in_dispatch(tile).unwrap_or_default();
+                    tile_ranges.push(uvec2(tile_volumes.len() as u32, run.len() as u32));
+                    tile_volumes.extend(
+                        run.iter()
+                            .map(|&(_, compute_volume_index)| compute_volume_index),
+                    );
+                }
+            }
+        }
+
+        commands.entity(view_entity).insert(ViewCloudTileBins {
+            tile_count,
+            batches,
+            dispatches,
+        });
+    }
+
+    tile_ranges_buffer.write_buffer(&render_device, &render_queue);
+    tile_volumes_buffer.write_buffer(&render_device, &render_queue);
+}
+
+/// Raymarches the cloud volumes of a view into its offscreen targets with the
+/// compute backend.
+///
+/// This is called by the volumetric cloud node in place of drawing the
+/// volumes, after it clears the offscreen targets. The view bind group offsets
+/// are those of the view, the lights, and the fog, in that order.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn render_cloud_volumes_compute(
+    render_context: &mut RenderContext,
+    world: &World,
+    view_bind_group: &ViewCloudComputeBindGroup,
+    view_bind_group_offsets: &[u32],
+    view_cloud_volumes: &ViewVolumetricCloud,
+    view_tile_bins: &ViewCloudTileBins,
+    offscreen_textures: &ViewCloudOffscreenTextures,
+    view_compute_pipelines: &ViewCloudComputePipelines,
+) {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let compute_pipeline = world.resource::<CloudComputePipeline>();
+    let compute_volume_buffer = world.resource::<CloudComputeVolumeBuffer>();
+    let tile_bin_buffers = world.resource::<CloudTileBinBuffers>();
+    let render_device = render_context.render_device().clone();
+
+    if view_tile_bins.dispatches.is_empty() {
+        return;
+    }
+    let (Some(cloud_volumes_binding), Some(tile_ranges_buffer), Some(tile_volumes_binding)) = (
+        compute_volume_buffer.binding(),
+        tile_bin_buffers.tile_ranges.buffer(),
+        tile_bin_buffers.tile_volumes.binding(),
+    ) else {
+        return;
+    };
+
+    // Each dispatch binds its own tiles, at its dynamic offset.
+    let tile_ranges_size = (view_tile_bins.tile_count.x * view_tile_bins.tile_count.y) as u64
+        * mem::size_of::<UVec2>() as u64;
+    let mut output_entries = DynamicBindGroupEntries::new_with_indices((
+        (0, &offscreen_textures.color.default_view),
+        (1, &offscreen_textures.transmittance.default_view),
+        (3, cloud_volumes_binding),
+        (
+            4,
+            BindingResource::Buffer(BufferBinding {
+                buffer: tile_ranges_buffer,
+                offset: 0,
+                size: NonZeroU64::new(tile_ranges_size),
+            }),
+        ),
+        (5, tile_volumes_binding),
+    ));
+    if let Some(motion) = &offscreen_textures.motion {
+        output_entries = output_entries.extend_with_indices(((2, &motion.default_view),));
+    }
+    let output_bind_group = render_device.create_bind_group(
+        "cloud compute output bind group",
+        &compute_pipeline.output_bind_group_layouts[offscreen_textures.motion.is_some() as usize],
+        &output_entries,
+    );
+
+    let mut compute_pass =
+        render_context
+            .command_encoder()
+            .begin_compute_pass(&ComputePassDescriptor {
+                label: Some("cloud compute pass"),
+                timestamp_writes: None,
+            });
+    compute_pass.set_bind_group(0, &view_bind_group.0, view_bind_group_offsets);
+
+    for dispatch in &view_tile_bins.dispatches {
+        // Skip batches whose pipeline hasn't compiled yet.
+        let batch = &view_tile_bins.batches[dispatch.batch_index];
+        let Some(pipeline) = view_compute_pipelines
+            .0
+            .get(&batch.flags)
+            .and_then(|pipeline_id| pipeline_cache.get_compute_pipeline(*pipeline_id))
+        else {
+            continue;
+        };
+
+        // The shader reads the uniforms of the volumes from the storage
+        // buffer, so this bind group only supplies the textures.
+        let view_cloud_volume = &view_cloud_volumes[batch.volume_index];
+        let Some(volume_bind_group) = &view_cloud_volume.bind_group else {
+            continue;
+        };
+
+        compute_pass.set_pipeline(pipeline);
+        compute_pass.set_bind_group(
+            1,
+            volume_bind_group,
+            &[view_cloud_volume.uniform_buffer_offset],
+        );
+        compute_pass.set_bind_group(2, &output_bind_group, &[dispatch.tile_ranges_offset]);
+        compute_pass.dispatch_workgroups(
+            view_tile_bins.tile_count.x,
+            view_tile_bins.tile_count.y,
+            1,
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Lightning flashes that light storm clouds from within.
+//!
+//! A [`CloudLightning`] is a transient light source shaped like a branching
+//! bolt. Spawn it at a position inside a [`CloudVolume`], and the volume will
+//! scatter its light as part of the regular raymarch. The flash follows a
+//! flickering envelope made of a few return strokes, and despawns itself once
+//! it's over. Optionally, it also drives a short-lived [`PointLight`] so that
+//! the flash lights the scene around the cloud as well.
+//!
+//! [`CloudVolume`]: crate::volumetric_clouds::CloudVolume
+
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+
+/// The maximum number of bolt segments that can light a single cloud volume.
+///
+/// This must match the constant in `volumetric_clouds.wgsl`.
+pub const MAX_CLOUD_LIGHTNING_SEGMENTS: usize = 16;
+
+/// The number of segments in the main channel of a bolt.
+const MAIN_CHANNEL_SEGMENTS: usize = 6;
+
+/// The number of segments in each branch of a bolt.
+const BRANCH_SEGMENTS: usize = 2;
+
+/// A flash of lightning inside a cloud volume.
+///
+/// The flash starts at the entity's position and branches out from there along
+/// [`Self::direction`]. Any cloud volume that contains the entity's position is
+/// lit by it.
+#[derive(Clone, Component, Debug, Reflect)]
+#[reflect(Component)]
+pub struct CloudLightning {
+    /// The color of the flash.
+    ///
+    /// Defaults to a bluish white.
+    pub color: Color,
+
+    /// The luminous power of the flash at its peak, in lumens.
+    ///
+    /// Real lightning emits on the order of 10⁹ lumens at its peak.
+    ///
+    /// The default value is 10⁹.
+    pub intensity: f32,
+
+    /// The length of the flash, in seconds.
+    ///
+    /// The entity is despawned once the flash is over.
+    ///
+    /// The default value is 0.6.
+    pub duration: f32,
+
+    /// The number of return strokes, each of which briefly brightens the flash
+    /// before it decays again.
+    ///
+    /// The default value is 3.
+    pub stroke_count: u32,
+
+    /// How quickly each return stroke fades, in 1/s.
+    ///
+    /// The default value is 12.0.
+    pub stroke_decay: f32,
+
+    /// How strongly the brightness flickers within a stroke, from 0 (no
+    /// flicker) to 1.
+    ///
+    /// The default value is 0.5.
+    pub flicker: f32,
+
+    /// The direction, in world space, in which the main channel travels.
+    ///
+    /// Defaults to straight down.
+    pub direction: Dir3,
+
+    /// The length of the main channel, in meters.
+    ///
+    /// The default value is 1.0.
+    pub length: f32,
+
+    /// How far each segment of the bolt may stray sideways, as a fraction of
+    /// the segment length.
+    ///
+    /// The default value is 0.6.
+    pub jaggedness: f32,
+
+    /// The number of branches that fork off the main channel.
+    ///
+    /// Bolts are limited to [`MAX_CLOUD_LIGHTNING_SEGMENTS`] segments in total,
+    /// so at most 5 branches are generated.
+    ///
+    /// The default value is 3.
+    pub branch_count: u32,
+
+    /// The seed that determines the shape of the bolt and the timing of its
+    /// strokes.
+    pub seed: u32,
+
+    /// If set, a [`PointLight`] with this range, in meters, is spawned at the
+    /// flash for its duration, so that the flash lights the ground as well as
+    /// the cloud.
+    ///
+    /// The default value is `None`.
+    pub point_light_range: Option<f32>,
+}
+
+/// A convenient [`Bundle`] that contains all components necessary to spawn a
+/// lightning flash.
+#[derive(Bundle, Clone, Debug, Default)]
+pub struct CloudLightningBundle {
+    /// The lightning flash.
+    pub lightning: CloudLightning,
+    /// Visibility. This is inherited by the point light of the flash, if it
+    /// has one.
+    pub visibility: Visibility,
+    /// Inherited visibility.
+    pub inherited_visibility: InheritedVisibility,
+    /// View visibility.
+    pub view_visibility: ViewVisibility,
+    /// The local transform. Set this to place the flash inside a cloud volume.
+    pub transform: Transform,
+    /// The global transform.
+    pub global_transform: GlobalTransform,
+}
+
+/// The state of a [`CloudLightning`] flash that has started.
+///
+/// This is added automatically when a [`CloudLightning`] is spawned.
+#[derive(Clone, Component, Debug)]
+pub struct CloudLightningBolt {
+    /// The segments of the bolt, in world space, relative to the origin of
+    /// the flash.
+    pub segments: Vec<(Vec3, Vec3)>,
+    /// The time at which each return stroke starts, in seconds.
+    pub stroke_times: Vec<f32>,
+    /// How long the flash has been going for, in seconds.
+    pub age: f32,
+    /// The current brightness, from 0 to 1, relative to
+    /// [`CloudLightning::intensity`].
+    pub brightness: f32,
+}
+
+/// Marks the [`PointLight`] that a [`CloudLightning`] flash spawns.
+#[derive(Clone, Copy, Component, Debug, Default)]
+pub struct CloudLightningPointLight;
+
+impl Default for CloudLightning {
+    fn default() -> Self {
+        Self {
+            color: Color::srgb(0.8, 0.85, 1.0),
+            intensity: 1.0e9,
+            duration: 0.6,
+            stroke_count: 3,
+            stroke_decay: 12.0,
+            flicker: 0.5,
+            direction: Dir3::NEG_Y,
+            length: 1.0,
+            jaggedness: 0.6,
+            branch_count: 3,
+            seed: 0,
+            point_light_range: None,
+        }
+    }
+}
+
+/// A system that generates the bolt of each newly-spawned [`CloudLightning`],
+/// and spawns its point light if it has one.
+pub fn spawn_cloud_lightning_bolts(
+    mut commands: Commands,
+    lightning: Query<(Entity, &CloudLightning), Without<CloudLightningBolt>>,
+) {
+    for (entity, lightning) in lightning.iter() {
+        commands
+            .entity(entity)
+            .insert(CloudLightningBolt::new(lightning));
+
+        if let Some(range) = lightning.point_light_range {
+            commands.entity(entity).with_children(|parent| {
+                parent.spawn((
+                    PointLightBundle {
+                        point_light: PointLight {
+                            color: lightning.color,
+                            intensity: 0.0,
+                            range,
+                            ..default()
+                        },
+                        ..default()
+                    },
+                    CloudLightningPointLight,
+                ));
+            });
+        }
+    }
+}
+
+/// A system that advances each [`CloudLightning`] flash along its envelope,
+/// and despawns it once it's over.
+pub fn update_cloud_lightning(
+    mut commands: Commands,
+    mut lightning: Query<(
+        Entity,
+        &CloudLightning,
+        &mut CloudLightningBolt,
+        Option<&Children>,
+    )>,
+    mut point_lights: Query<&mut PointLight, With<CloudLightningPointLight>>,
+    time: Res<Time>,
+) {
+    for (entity, lightning, mut bolt, children) in lightning.iter_mut() {
+        bolt.age += time.delta_seconds();
+        if bolt.age >= lightning.duration {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        bolt.brightness = bolt.envelope(lightning);
+
+        for &child in children.into_iter().flatten() {
+            if let Ok(mut point_light) = point_lights.get_mut(child) {
+                point_light.intensity = lightning.intensity * bolt.brightness;
+            }
+        }
+    }
+}
+
+impl CloudLightningBolt {
+    /// Generates the bolt and stroke timing for a flash.
+    fn new(lightning: &CloudLightning) -> Self {
+        let mut random = LightningRandom(lightning.seed.wrapping_mul(0x9e37_79b9) | 1);
+
+        // Pick two axes perpendicular to the direction of the bolt, so that
+        // segments can stray sideways.
+        let direction = *lightning.direction;
+        let tangent = direction.any_orthonormal_vector();
+        let bitangent = direction.cross(tangent);
+        let jitter = |random: &mut LightningRandom, segment_length: f32| {
+            let angle = random.next() * TAU;
+            let offset = random.next() * lightning.jaggedness * segment_length;
+            (tangent * angle.cos() + bitangent * angle.sin()) * offset
+        };
+
+        // Walk the main channel.
+        let segment_length = lightning.length / MAIN_CHANNEL_SEGMENTS as f32;
+        let mut segments = Vec::with_capacity(MAX_CLOUD_LIGHTNING_SEGMENTS);
+        let mut position = Vec3::ZERO;
+        for _ in 0..MAIN_CHANNEL_SEGMENTS {
+            let next = position + direction * segment_length + jitter(&mut random, segment_length);
+            segments.push((position, next));
+            position = next;
+        }
+
+        // Fork shorter branches off random points along the main channel.
+        let max_branches = (MAX_CLOUD_LIGHTNING_SEGMENTS - MAIN_CHANNEL_SEGMENTS) / BRANCH_SEGMENTS;
+        for _ in 0..(lightning.branch_count as usize).min(max_branches) {
+            let fork = (random.next() * MAIN_CHANNEL_SEGMENTS as f32) as usize;
+            let mut position = segments[fork.min(MAIN_CHANNEL_SEGMENTS - 1)].1;
+            let branch_direction =
+                (direction + jitter(&mut random, 1.0).normalize_or_zero() * 1.2).normalize();
+            for _ in 0..BRANCH_SEGMENTS {
+                let next = position
+                    + branch_direction * segment_length * 0.8
+                    + jitter(&mut random, segment_length * 0.8);
+                segments.push((position, next));
+                position = next;
+            }
+        }
+
+        // The first stroke starts right away, and the rest follow at random
+        // over the first half of the flash.
+        let mut stroke_times: Vec<f32> = (0..lightning.stroke_count.max(1))
+            .map(|stroke| {
+                if stroke == 0 {
+                    0.0
+                } else {
+                    random.next() * lightning.duration * 0.5
+                }
+            })
+            .collect();
+        stroke_times.sort_by(f32::total_cmp);
+
+        Self {
+            segments,
+            stroke_times,
+            age: 0.0,
+            brightness: 1.0,
+        }
+    }
+
+    /// Evaluates the brightness of the flash at its current age.
+    fn envelope(&self, lightning: &CloudLightning) -> f32 {
+        let strokes = self
+            .stroke_times
+            .iter()
+            .filter(|&&start| start <= self.age)
+            .map(|&start| (-(self.age - start) * lightning.stroke_decay).exp())
+            .fold(0.0, f32::max);
+
+        // Flicker with a pair of incommensurate sine waves, so that the
+        // pattern doesn't visibly repeat.
+        let phase = self.age * 90.0 + lightning.seed as f32;
+        let flicker = 0.5 + 0.25 * (phase.sin() + (phase * 2.31).sin());
+
+        // Fade out toward the end so that the flash doesn't pop.
+        let fade_out = ((lightning.duration - self.age) / (lightning.duration * 0.2)).min(1.0);
+
+        strokes * (1.0 - lightning.flicker * flicker) * fade_out
+    }
+
+    /// Returns the total length of the bolt, in meters.
+    pub fn total_length(&self) -> f32 {
+        self.segments
+            .iter()
+            .map(|(start, end)| start.distance(*end))
+            .sum()
+    }
+}
+
+/// A tiny xorshift random number generator.
+///
+/// Bolts only need to look random, and they must be reproducible from their
+/// seed, so there's no need for anything fancier.
+struct LightningRandom(u32);
+
+impl LightningRandom {
+    /// Returns a random number between 0 and 1.
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+}
@@ -0,0 +1,250 @@
+//! Physically based descriptions of participating media.
+//!
+//! Tuning [`CloudVolume::absorption`] and [`CloudVolume::scattering`] by eye
+//! is guesswork. A [`CloudMedium`] describes the same medium the way the
+//! literature does, either by its extinction coefficient and single-scattering
+//! albedo or, for water clouds, by its liquid water content and droplet size,
+//! and converts that into the coefficients that the cloud volume uses.
+//!
+//! All coefficients are per meter at a density of 1, so a volume that uses a
+//! medium should keep its [`CloudVolume::density_factor`] at 1 and store
+//! relative densities in its density texture.
+//!
+//! The color channels are treated as monochromatic samples at 650 nm (red),
+//! 550 nm (green), and 450 nm (blue).
+//!
+//! The presets are analytic approximations, not measurements. Their extinction
+//! coefficients follow from the conversions below with typical inputs for each
+//! medium, and their albedos and asymmetries are representative round values,
+//! so they're a plausible starting point rather than a match for any measured
+//! medium.
+
+use bevy::{math::vec3, prelude::*};
+
+use crate::volumetric_clouds::CloudVolume;
+
+/// The density of liquid water, in g/m³.
+const WATER_DENSITY: f32 = 1.0e6;
+
+/// The Koschmieder constant, which relates the meteorological visibility V to
+/// the extinction coefficient as σₜ = 3.912 / V.
+const KOSCHMIEDER: f32 = 3.912;
+
+/// The wavelengths, in nanometers, that the red, green, and blue channels
+/// stand for.
+const CHANNEL_WAVELENGTHS: Vec3 = vec3(650.0, 550.0, 450.0);
+
+/// A participating medium, described by its optical properties.
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub struct CloudMedium {
+    /// The extinction coefficient σₜ of each color channel, in 1/m.
+    ///
+    /// This is the fraction of light that's absorbed or scattered away per
+    /// meter.
+    pub extinction: Vec3,
+
+    /// The single-scattering albedo ω = σₛ / σₜ of each color channel.
+    ///
+    /// This is the fraction of extinguished light that's scattered rather than
+    /// absorbed: 1 for pure water droplets, and well below that for soot.
+    pub albedo: Vec3,
+
+    /// The Henyey-Greenstein asymmetry parameter g.
+    pub asymmetry: f32,
+}
+
+impl CloudMedium {
+    /// A fair-weather cumulus cloud.
+    ///
+    /// Liquid water content 0.5 g/m³ and effective droplet radius 10 µm,
+    /// giving σₜ = 0.075 m⁻¹.
+    pub const CUMULUS: Self = Self {
+        extinction: Vec3::splat(0.075),
+        albedo: Vec3::splat(0.9999),
+        asymmetry: 0.85,
+    };
+
+    /// A low stratus deck.
+    ///
+    /// Liquid water content 0.25 g/m³ and effective droplet radius 8 µm,
+    /// giving σₜ ≈ 0.047 m⁻¹.
+    pub const STRATUS: Self = Self {
+        extinction: Vec3::splat(0.046_875),
+        albedo: Vec3::splat(0.9999),
+        asymmetry: 0.85,
+    };
+
+    /// Thick radiation fog, with a visibility of 200 m.
+    pub const FOG: Self = Self {
+        extinction: Vec3::splat(0.019_56),
+        albedo: Vec3::splat(0.9999),
+        asymmetry: 0.8,
+    };
+
+    /// Wood smoke with a visibility of 200 m.
+    ///
+    /// The fine particles in smoke extinguish blue light more than red light
+    /// (Ångström exponent 1.8), so light passing through it turns orange, and
+    /// they absorb a sizable fraction of the light they extinguish.
+    pub const SMOKE: Self = Self {
+        extinction: vec3(0.014_48, 0.019_56, 0.028_07),
+        albedo: Vec3::splat(0.85),
+        asymmetry: 0.6,
+    };
+
+    /// Airborne mineral dust with a visibility of 1 km.
+    ///
+    /// Dust particles are large, so extinction hardly depends on wavelength
+    /// (Ångström exponent 0.3), but the iron oxides in them absorb blue light,
+    /// which gives dust its ochre tint.
+    pub const DUST: Self = Self {
+        extinction: vec3(0.003_72, 0.003_91, 0.004_15),
+        albedo: vec3(0.97, 0.94, 0.88),
+        asymmetry: 0.72,
+    };
+
+    /// Creates a gray medium from its extinction coefficient, in 1/m, and its
+    /// single-scattering albedo.
+    pub fn from_extinction_albedo(extinction: f32, albedo: f32, asymmetry: f32) -> Self {
+        Self {
+            extinction: Vec3::splat(extinction),
+            albedo: Vec3::splat(albedo),
+            asymmetry,
+        }
+    }
+
+    /// Creates a water cloud from its liquid water content, in g/m³, and the
+    /// effective radius of its droplets, in µm.
+    ///
+    /// Cloud droplets are much larger than the wavelength of visible light, so
+    /// each one extinguishes twice its geometric cross section, and the
+    /// extinction coefficient is σₜ = 3 LWC / (2 ρ r), where ρ is the density
+    /// of water [Stephens 1978]. Water barely absorbs visible light, and the
+    /// droplets scatter strongly forward.
+    ///
+    /// [Stephens 1978]: https://doi.org/10.1175/1520-0469(1978)035%3C2123:RPIEWC%3E2.0.CO;2
+    pub fn from_liquid_water(liquid_water_content: f32, effective_radius: f32) -> Self {
+        let extinction =
+            3.0 * liquid_water_content / (2.0 * WATER_DENSITY * effective_radius * 1.0e-6);
+        Self::from_extinction_albedo(extinction, 0.9999, 0.85)
+    }
+
+    /// Creates a haze from its meteorological visibility, in meters, its
+    /// [Ångström exponent], which describes how much more strongly it
+    /// extinguishes blue light than red light, and its single-scattering
+    /// albedo.
+    ///
+    /// [Ångström exponent]: https://en.wikipedia.org/wiki/Angstrom_exponent
+    pub fn from_visibility(
+        visibility: f32,
+        angstrom_exponent: f32,
+        albedo: f32,
+        asymmetry: f32,
+    ) -> Self {
+        let extinction = KOSCHMIEDER / visibility;
+        Self {
+            extinction: extinction * (CHANNEL_WAVELENGTHS / 550.0).powf(-angstrom_exponent),
+            albedo: Vec3::splat(albedo),
+            asymmetry,
+        }
+    }
+
+    /// Returns the absorption coefficient σₐ = σₜ (1 − ω) of each color
+    /// channel, in 1/m.
+    pub fn absorption(&self) -> Vec3 {
+        self.extinction * (Vec3::ONE - self.albedo)
+    }
+
+    /// Returns the scattering coefficient σₛ = σₜ ω of each color channel, in
+    /// 1/m.
+    pub fn scattering(&self) -> Vec3 {
+        self.extinction * self.albedo
+    }
+}
+
+impl CloudVolume {
+    /// Sets the absorption, scattering, and asymmetry of this volume from a
+    /// physically based description of its medium.
+    ///
+    /// This also resets the density factor to 1, since the medium coefficients
+    /// already carry the units.
+    pub fn set_medium(&mut self, medium: &CloudMedium) {
+        let absorption = medium.absorption();
+        let scattering = medium.scattering();
+
+        self.absorption = absorption.element_sum() / 3.0;
+        self.scattering = scattering.element_sum() / 3.0;
+        self.chromatic_absorption = Some(absorption);
+        self.chromatic_scattering = Some(scattering);
+        self.scattering_asymmetry = medium.asymmetry;
+        self.density_factor = 1.0;
+    }
+
+    /// Returns this volume with the given medium. See [`Self::set_medium`].
+    pub fn with_medium(mut self, medium: &CloudMedium) -> Self {
+        self.set_medium(medium);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::{vec3, Vec3};
+
+    use super::CloudMedium;
+
+    #[test]
+    fn liquid_water_matches_presets() {
+        let cumulus = CloudMedium::from_liquid_water(0.5, 10.0);
+        assert!(cumulus
+            .extinction
+            .abs_diff_eq(CloudMedium::CUMULUS.extinction, 1.0e-6));
+
+        let stratus = CloudMedium::from_liquid_water(0.25, 8.0);
+        assert!(stratus
+            .extinction
+            .abs_diff_eq(CloudMedium::STRATUS.extinction, 1.0e-6));
+    }
+
+    #[test]
+    fn visibility_matches_presets() {
+        let fog = CloudMedium::from_visibility(200.0, 0.0, 0.9999, 0.8);
+        assert!(fog
+            .extinction
+            .abs_diff_eq(CloudMedium::FOG.extinction, 1.0e-6));
+
+        let smoke = CloudMedium::from_visibility(200.0, 1.8, 0.85, 0.6);
+        assert!(smoke
+            .extinction
+            .abs_diff_eq(CloudMedium::SMOKE.extinction, 1.0e-5));
+
+        let dust = CloudMedium::from_visibility(1000.0, 0.3, 0.94, 0.72);
+        assert!(dust
+            .extinction
+            .abs_diff_eq(CloudMedium::DUST.extinction, 1.0e-5));
+    }
+
+    #[test]
+    fn visibility_extinguishes_blue_more_than_red() {
+        let haze = CloudMedium::from_visibility(1000.0, 1.3, 0.9, 0.7);
+        assert!(haze.extinction.z > haze.extinction.y);
+        assert!(haze.extinction.y > haze.extinction.x);
+        // Green is the reference wavelength, so it's unaffected by the
+        // exponent.
+        assert!((haze.extinction.y - 3.912e-3).abs() < 1.0e-7);
+    }
+
+    #[test]
+    fn extinction_albedo_splits_into_absorption_and_scattering() {
+        let medium = CloudMedium::from_extinction_albedo(0.2, 0.75, 0.5);
+        assert_eq!(medium.extinction, Vec3::splat(0.2));
+        assert!(medium.scattering().abs_diff_eq(Vec3::splat(0.15), 1.0e-7));
+        assert!(medium.absorption().abs_diff_eq(Vec3::splat(0.05), 1.0e-7));
+        assert!((medium.scattering() + medium.absorption()).abs_diff_eq(medium.extinction, 1.0e-7));
+        assert_eq!(medium.asymmetry, 0.5);
+        assert_eq!(
+            CloudMedium::from_extinction_albedo(0.1, 1.0, 0.0).absorption(),
+            vec3(0.0, 0.0, 0.0)
+        );
+    }
+}
@@ -11,9 +11,10 @@
 //! be volumetric. [`VolumetricFogSettings`] feature numerous settings that
 //! allow you to define the accuracy of the simulation, as well as the look of
 //! the fog. Currently, only interaction with directional lights that have
-//! shadow maps is supported. Note that the overhead of the effect scales
-//! directly with the number of directional lights in use, so apply
-//! [`VolumetricLight`] sparingly for the best results.
+//! shadow maps is supported. The density of the fog is sampled once per step
+//! for all the lights together, but each light still adds its own shadow map
+//! lookups at every step, so apply [`VolumetricLight`] sparingly for the best
+//! results.
 //!
 //! The overall algorithm, which is implemented as a postprocessing effect, is a
 //! combination of the techniques described in [Scratchapixel] and [this blog
@@ -29,6 +30,12 @@
 //!
 //! [Henyey-Greenstein phase function]: https://www.pbr-book.org/4ed/Volume_Scattering/Phase_Functions#TheHenyeyndashGreensteinPhaseFunction
 
+pub use air::CloudAmbientAir;
+use air::{
+    AmbientAirPipeline, AmbientAirUniformBuffer, CloudShadowMapPipeline,
+    CloudShadowMapUniformBuffer,
+};
+pub use atmosphere::CloudAtmosphere;
 use bevy::{
     asset::embedded_asset,
     core_pipeline::core_3d::{
@@ -38,31 +45,109 @@ use bevy::{
     pbr::graph::NodePbr,
     prelude::*,
     render::{
+        extract_resource::ExtractResourcePlugin,
         render_graph::{RenderGraphApp, ViewNodeRunner},
-        render_resource::SpecializedRenderPipelines,
+        render_resource::{SpecializedComputePipelines, SpecializedRenderPipelines},
+        view::{check_visibility, VisibilitySystems},
         Render, RenderApp, RenderSet,
     },
 };
+use blackbody::{blackbody_lut_image, BLACKBODY_LUT};
+use compute::{CloudComputePipeline, CloudComputeVolumeBuffer, CloudTileBinBuffers};
+pub use lightning::{CloudLightning, CloudLightningBundle};
+pub use medium::CloudMedium;
 use render::{
-    VolumetricCloudNode, VolumetricCloudPass, VolumetricCloudPipeline,
-    VolumetricCloudUniformBuffer, CUBE_MESH, PLANE_MESH,
+    VolumetricCloudBindGroupCache, VolumetricCloudNode, VolumetricCloudPass,
+    VolumetricCloudPipeline, VolumetricCloudUniformBuffer, CUBE_MESH, PLANE_MESH,
 };
+pub use sky::{CloudMoon, CloudSkyClock, CloudSun};
+pub use temporal::CloudTemporalAccumulation;
+use temporal::{CloudTemporalHistory, CloudTemporalPipeline, CloudTemporalUniformBuffer};
+use upsample::CloudUpsamplePipeline;
 
+pub mod air;
+pub mod atmosphere;
+pub mod blackbody;
+pub mod compute;
+pub mod lightning;
+pub mod medium;
 pub mod render;
+pub mod sky;
+pub mod temporal;
+pub mod upsample;
 
 /// A plugin that implements volumetric fog.
 pub struct VolumetricCloudPlugin;
 
-/// Add this component to a [`DirectionalLight`] with a shadow map
-/// (`shadows_enabled: true`) to make volumetric fog interact with it.
+/// Add this component to a [`DirectionalLight`] to make volumetric fog
+/// interact with it.
+///
+/// If the light has a shadow map (`shadows_enabled: true`), this allows the
+/// light to generate light shafts/god rays. The fields adjust how this light
+/// alone affects the clouds, without changing how it lights the rest of the
+/// scene.
 ///
-/// This allows the light to generate light shafts/god rays.
-#[derive(Clone, Copy, Component, Default, Debug, Reflect)]
+/// The light only lights the clouds in the views whose
+/// [`RenderLayers`](bevy::render::view::RenderLayers) it shares.
+#[derive(Clone, Copy, Component, Debug, Reflect)]
 #[reflect(Component)]
-pub struct VolumetricCloudLight;
+pub struct VolumetricCloudLight {
+    /// A factor that scales the light that this light scatters into the
+    /// clouds.
+    ///
+    /// The default value is 1.0.
+    pub intensity: f32,
+
+    /// A color that the light that this light scatters into the clouds is
+    /// multiplied by.
+    ///
+    /// The default value is white.
+    pub tint: Color,
+
+    /// The number of steps to march toward the light from each sample to find
+    /// how much of the cloud the light passes through on the way.
+    ///
+    /// A value of 0 skips the march, and instead assumes that the cloud is as
+    /// dense all the way to its boundary as it is at the sample. That's much
+    /// cheaper, but clouds look flatter.
+    ///
+    /// The default value is 0.
+    pub step_count: u32,
+
+    /// Overrides the [`CloudVolume::scattering_asymmetry`] of every cloud
+    /// volume for this light.
+    ///
+    /// The default value is `None`.
+    pub scattering_asymmetry: Option<f32>,
+
+    /// Whether to look up the light's shadow map, so that the scene can shadow
+    /// the clouds.
+    ///
+    /// This has no effect if the [`DirectionalLight`] doesn't cast shadows.
+    /// The shadow map is filtered with the camera's
+    /// [`ShadowFilteringMethod`](bevy::pbr::ShadowFilteringMethod), so the
+    /// edges of light shafts match the edges of shadows in the scene. Bevy
+    /// doesn't have percentage-closer soft shadows yet, so neither do the
+    /// clouds.
+    ///
+    /// The default value is true.
+    pub shadow_map: bool,
+
+    /// A bit mask of the light-link groups that this light belongs to.
+    ///
+    /// The light only lights the [`CloudVolume`]s whose
+    /// [`CloudVolume::light_links`] share at least one bit with this mask, in
+    /// the same way as [`RenderLayers`](bevy::render::view::RenderLayers).
+    ///
+    /// The default value is 1, the first group.
+    pub light_links: u32,
+}
 
 /// When placed on a [`Camera3d`], enables volumetric fog and volumetric
 /// lighting, also known as light shafts or god rays.
+///
+/// If the camera also has [`FogSettings`], the clouds fade into that fog at
+/// their average depth, like the rest of the scene.
 #[derive(Clone, Copy, Component, Debug, Reflect)]
 #[reflect(Component)]
 pub struct VolumetricCloudSettings {
@@ -87,9 +172,9 @@ pub struct VolumetricCloudSettings {
 
     /// The maximum distance to offset the ray origin randomly by, in meters.
     ///
-    /// This is intended for use with temporal antialiasing. It helps fog look
-    /// less blocky by varying the start position of the ray, using interleaved
-    /// gradient noise.
+    /// This is intended for use with temporal antialiasing or
+    /// [`Self::temporal`]. It helps fog look less blocky by varying the start
+    /// position of the ray, using interleaved gradient noise.
     pub jitter: f32,
 
     /// The number of raymarching steps to perform.
@@ -97,12 +182,254 @@ pub struct VolumetricCloudSettings {
     /// Higher values produce higher-quality results with less banding, but
     /// reduce performance.
     ///
+    /// This has no effect if [`Self::adaptive_steps`] is set.
+    ///
     /// The default value is 64.
     pub step_count: u32,
+
+    /// Varies the length of the raymarching steps with the density of the
+    /// medium and the distance from the camera, instead of splitting each ray
+    /// into [`Self::step_count`] equal steps.
+    ///
+    /// The default value is `None`.
+    pub adaptive_steps: Option<CloudAdaptiveSteps>,
+
+    /// How the light along each raymarching step is integrated.
+    ///
+    /// The default is [`VolumetricCloudIntegrator::Analytic`].
+    pub integrator: VolumetricCloudIntegrator,
+
+    /// The resolution at which the clouds are raymarched, relative to the
+    /// view.
+    ///
+    /// Below full resolution, the clouds are rendered into an offscreen
+    /// target and then upsampled onto the view with a depth-aware filter, so
+    /// their silhouettes against the scene stay sharp.
+    ///
+    /// The default is [`CloudResolutionScale::Full`].
+    pub resolution_scale: CloudResolutionScale,
+
+    /// Accumulates the clouds over several frames, so that a low
+    /// [`Self::step_count`] with some [`Self::jitter`] converges to a smooth
+    /// image.
+    ///
+    /// The default value is `None`.
+    pub temporal: Option<CloudTemporalAccumulation>,
+
+    /// Which pixels are raymarched in each frame, with the rest reprojected
+    /// from the history of [`Self::temporal`].
+    ///
+    /// This has no effect unless [`Self::temporal`] is set.
+    ///
+    /// The default is [`CloudUpdatePattern::Full`].
+    pub update_pattern: CloudUpdatePattern,
+
+    /// How the clouds are raymarched on the GPU.
+    ///
+    /// The default is [`VolumetricCloudBackend::Raster`].
+    pub backend: VolumetricCloudBackend,
+
+    /// The albedo of the ground, a horizontal plane at a world-space height
+    /// of 0.
+    ///
+    /// Sunlight that the ground reflects brightens the undersides of the
+    /// clouds, which matters over snow, sand, or water. Set this to black to
+    /// skip the bounce entirely.
+    ///
+    /// The default value is black.
+    pub ground_albedo: Color,
+
+    /// An optional thin medium that fills the air around the clouds, so that
+    /// sunlight streaming through gaps in them forms crepuscular rays.
+    ///
+    /// The default value is `None`.
+    pub ambient_air: Option<CloudAmbientAir>,
+}
+
+/// How the raymarch integrates the light scattered in along each step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Reflect)]
+pub enum VolumetricCloudIntegrator {
+    /// Integrates each step analytically, assuming the medium is constant
+    /// across it.
+    ///
+    /// This is energy-conserving, so changing the step count mostly trades
+    /// noise for cost instead of changing the brightness.
+    #[default]
+    Analytic,
+
+    /// Sums the light at each step, as though it were concentrated at a
+    /// single point.
+    ///
+    /// This was the original integrator. It overestimates the light in dense
+    /// media, and its results depend strongly on the step count.
+    Riemann,
+}
+
+/// Settings for raymarching with steps whose length adapts to the medium.
+///
+/// Set this as [`VolumetricCloudSettings::adaptive_steps`] to enable it. Each
+/// step is sized from the density where it starts: steps through empty space
+/// take the longest allowed length, and as the density rises, steps shorten
+/// so that none of them crosses much more than a quarter of an optical depth.
+/// Steps also lengthen in proportion to the distance from the camera, where
+/// detail is smaller on screen, up to [`Self::max_step_size`].
+#[derive(Clone, Copy, Debug, Reflect)]
+pub struct CloudAdaptiveSteps {
+    /// The shortest length, in meters, that a step may have.
+    ///
+    /// The default value is 0.01.
+    pub min_step_size: f32,
+
+    /// The longest length, in meters, that a step may have, unless the
+    /// [`Self::step_budget`] runs out.
+    ///
+    /// This is also the length of the steps through empty space, so it bounds
+    /// how thin a wisp of cloud may be missed.
+    ///
+    /// The default value is 0.25.
+    pub max_step_size: f32,
+
+    /// The length of a step, per meter of distance from the camera, below
+    /// which the steps aren't shortened however dense the medium is.
+    ///
+    /// Where this is longer than [`Self::max_step_size`], steps through the
+    /// medium are as long as through empty space.
+    ///
+    /// The default value is 0.005.
+    pub distance_factor: f32,
+
+    /// The most steps that a single ray may take.
+    ///
+    /// Where the steps that the medium calls for wouldn't reach the end of the
+    /// ray within this budget, they're lengthened so that they do, even beyond
+    /// [`Self::max_step_size`].
+    ///
+    /// The default value is 256.
+    pub step_budget: u32,
+}
+
+impl Default for CloudAdaptiveSteps {
+    fn default() -> Self {
+        Self {
+            min_step_size: 0.01,
+            max_step_size: 0.25,
+            distance_factor: 0.005,
+            step_budget: 256,
+        }
+    }
+}
+
+/// How the clouds are raymarched on the GPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Reflect)]
+pub enum VolumetricCloudBackend {
+    /// Rasterizes the hull of each cloud volume, and raymarches the pixels
+    /// that it covers in a fragment shader.
+    ///
+    /// The volumes are drawn back to front, so volumes that are apart
+    /// composite correctly, but where two volumes interpenetrate, one is drawn
+    /// entirely over the other, and they don't dim each other. Use
+    /// [`Self::Compute`] for clouds that interpenetrate.
+    #[default]
+    Raster,
+
+    /// Raymarches the clouds in a compute shader, in a single pass over 8×8
+    /// screen tiles, each of which only visits the cloud volumes that overlap
+    /// it, front to back.
+    ///
+    /// This scales far better than [`Self::Raster`] to many small volumes, and
+    /// interpenetrating volumes dim each other where they overlap, as long as
+    /// they share their textures and pipeline features. Other volumes are
+    /// composited one behind another, front to back by their nearest depth.
+    /// Tiles in which the scene hides a volume entirely skip it as a whole.
+    /// The clouds are always rendered offscreen, as though
+    /// [`VolumetricCloudSettings::resolution_scale`] were below full
+    /// resolution.
+    ///
+    /// This needs read-write storage textures, which not all devices support;
+    /// elsewhere, it falls back to [`Self::Raster`]. Irradiance volumes don't
+    /// light the clouds in this backend.
+    Compute,
+}
+
+/// The resolution at which clouds are raymarched, relative to the view.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Reflect)]
+pub enum CloudResolutionScale {
+    /// Raymarches every pixel of the view.
+    #[default]
+    Full,
+
+    /// Raymarches a quarter as many pixels: half the width and half the
+    /// height.
+    Half,
+
+    /// Raymarches a sixteenth as many pixels: a quarter of the width and a
+    /// quarter of the height.
+    Quarter,
 }
 
+impl CloudResolutionScale {
+    /// Returns the factor that the width and height of the view are scaled
+    /// by.
+    pub fn factor(self) -> f32 {
+        match self {
+            CloudResolutionScale::Full => 1.0,
+            CloudResolutionScale::Half => 0.5,
+            CloudResolutionScale::Quarter => 0.25,
+        }
+    }
+
+    /// Returns the size of the target that the clouds are raymarched into,
+    /// for a view of the given size.
+    pub fn target_size(self, view_size: UVec2) -> UVec2 {
+        (view_size.as_vec2() * self.factor())
+            .ceil()
+            .as_uvec2()
+            .max(UVec2::ONE)
+    }
+}
+
+/// Which pixels of the clouds are raymarched in each frame.
+///
+/// The pixels are split into square blocks, and one pixel of each block is
+/// raymarched per frame, in the order of a Bayer matrix so that consecutive
+/// frames are spread evenly over the block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Reflect)]
+pub enum CloudUpdatePattern {
+    /// Raymarches every pixel in every frame.
+    #[default]
+    Full,
+
+    /// Raymarches one pixel of each 2×2 block per frame, so each pixel is
+    /// updated every 4 frames.
+    Bayer2x2,
+
+    /// Raymarches one pixel of each 4×4 block per frame, so each pixel is
+    /// updated every 16 frames.
+    Bayer4x4,
+}
+
+impl CloudUpdatePattern {
+    /// Returns the width and height of the blocks, in pixels.
+    pub fn size(self) -> u32 {
+        match self {
+            CloudUpdatePattern::Full => 1,
+            CloudUpdatePattern::Bayer2x2 => 2,
+            CloudUpdatePattern::Bayer4x4 => 4,
+        }
+    }
+}
+
+/// A query filter that matches [`CloudVolume`]s, for looking them up in
+/// [`VisibleEntities`](bevy::render::view::VisibleEntities).
+pub type WithCloudVolume = With<CloudVolume>;
+
 /// A convenient [`Bundle`] that contains all components necessary to generate a
 /// fog volume.
+///
+/// The visibility components are required: a volume is only drawn in the
+/// views that it's visible to, which takes its [`Visibility`], the view
+/// frustum, and its [`RenderLayers`](bevy::render::view::RenderLayers) into
+/// account.
 #[derive(Bundle, Clone, Debug, Default)]
 pub struct CloudVolumeBundle {
     /// The actual fog volume.
@@ -152,6 +479,22 @@ pub struct CloudVolume {
     /// The default value is 0.3.
     pub scattering: f32,
 
+    /// Per-channel absorption coefficients, overriding [`Self::absorption`].
+    ///
+    /// Use this for media that absorb some wavelengths more than others, such
+    /// as smoke that reddens the light passing through it.
+    ///
+    /// The default value is `None`.
+    pub chromatic_absorption: Option<Vec3>,
+
+    /// Per-channel scattering coefficients, overriding [`Self::scattering`].
+    ///
+    /// Use this for media that scatter some wavelengths more than others, such
+    /// as fine dust that scatters blue light and lets red light through.
+    ///
+    /// The default value is `None`.
+    pub chromatic_scattering: Option<Vec3>,
+
     /// Measures the fraction of light that's scattered *toward* the camera, as
     /// opposed to *away* from the camera.
     ///
@@ -177,19 +520,132 @@ pub struct CloudVolume {
     ///
     /// The default value is 1.0, which results in no adjustment.
     pub light_intensity: f32,
+
+    /// A bit mask of the light-link groups that this volume belongs to.
+    ///
+    /// Only the [`VolumetricCloudLight`]s whose
+    /// [`VolumetricCloudLight::light_links`] share at least one bit with this
+    /// mask light this volume. Give a key light and a hero cloud a group of
+    /// their own to light that cloud alone.
+    ///
+    /// A volume that no light lights still dims what's behind it, as a dark
+    /// cloud.
+    ///
+    /// The default value is 1, the first group.
+    pub light_links: u32,
+
+    /// The color of the light that the cloud emits by itself.
+    ///
+    /// Emission lets the medium glow without being lit, as fire, lava plumes,
+    /// and lightning-lit storm cores do. The medium emits in proportion to how
+    /// much it absorbs, so a thin wisp barely glows. A thick enough volume
+    /// converges to the emissive luminance times the fraction of its
+    /// extinction that's absorption, `absorption / (absorption + scattering)`:
+    /// the full luminance for a medium that doesn't scatter, and half of it
+    /// with the default coefficients.
+    ///
+    /// When [`Self::emission_texture_mode`] is
+    /// [`CloudEmissionTextureMode::Temperature`], this acts as a tint on the
+    /// blackbody color instead.
+    ///
+    /// Defaults to white.
+    pub emissive_color: Color,
+
+    /// The luminance of the emitted light, in cd/m² (nits).
+    ///
+    /// Like other light sources, this is scaled by the camera exposure. This
+    /// is ignored in [`CloudEmissionTextureMode::Temperature`] mode, where the
+    /// luminance comes from the temperature instead.
+    ///
+    /// The default value is 0.0, which disables emission.
+    pub emissive_intensity: f32,
+
+    /// An optional 3D texture that modulates the emission.
+    ///
+    /// This texture covers the same box as [`Self::density_texture`], and its
+    /// red channel is interpreted according to
+    /// [`Self::emission_texture_mode`].
+    pub emission_texture: Option<Handle<Image>>,
+
+    /// How the red channel of [`Self::emission_texture`] is interpreted.
+    ///
+    /// The default is [`CloudEmissionTextureMode::Intensity`].
+    pub emission_texture_mode: CloudEmissionTextureMode,
+}
+
+/// How a [`CloudVolume`] interprets its emission texture.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Reflect)]
+pub enum CloudEmissionTextureMode {
+    /// The texture scales the emissive luminance, from 0 (no emission) to 1
+    /// (full [`CloudVolume::emissive_intensity`]).
+    #[default]
+    Intensity,
+
+    /// The texture stores a temperature, which is mapped through a physically
+    /// based blackbody ramp to find the emitted color and luminance.
+    Temperature {
+        /// The temperature, in kelvin, that a texture value of 0 maps to.
+        min_temperature: f32,
+        /// The temperature, in kelvin, that a texture value of 1 maps to.
+        max_temperature: f32,
+    },
 }
 
 impl Plugin for VolumetricCloudPlugin {
     fn build(&self, app: &mut App) {
         info!("VolumetricCloudPlugin");
         embedded_asset!(app, "volumetric_clouds.wgsl");
+        embedded_asset!(app, "ambient_air.wgsl");
+        embedded_asset!(app, "cloud_shadow_map.wgsl");
+        embedded_asset!(app, "cloud_upsample.wgsl");
+        embedded_asset!(app, "cloud_temporal.wgsl");
 
         let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
         meshes.insert(&PLANE_MESH, Plane3d::new(Vec3::Z, Vec2::ONE).mesh().into());
         meshes.insert(&CUBE_MESH, Cuboid::new(1.0, 1.0, 1.0).mesh().into());
 
+        let mut images = app.world_mut().resource_mut::<Assets<Image>>();
+        images.insert(&BLACKBODY_LUT, blackbody_lut_image());
+
         app.register_type::<VolumetricCloudSettings>()
-            .register_type::<VolumetricCloudLight>();
+            .register_type::<VolumetricCloudLight>()
+            .register_type::<VolumetricCloudIntegrator>()
+            .register_type::<CloudAdaptiveSteps>()
+            .register_type::<CloudResolutionScale>()
+            .register_type::<CloudTemporalAccumulation>()
+            .register_type::<CloudUpdatePattern>()
+            .register_type::<VolumetricCloudBackend>()
+            .register_type::<CloudAmbientAir>()
+            .register_type::<CloudMedium>()
+            .register_type::<CloudLightning>()
+            .register_type::<CloudAtmosphere>()
+            .register_type::<CloudSkyClock>()
+            .register_type::<CloudSun>()
+            .register_type::<CloudMoon>()
+            .init_resource::<CloudAtmosphere>()
+            .add_systems(
+                Update,
+                (
+                    (
+                        lightning::spawn_cloud_lightning_bolts,
+                        lightning::update_cloud_lightning,
+                    )
+                        .chain(),
+                    (sky::spawn_cloud_sky_lights, sky::update_cloud_sky_clock)
+                        .chain()
+                        .run_if(resource_exists::<CloudSkyClock>),
+                    atmosphere::update_atmosphere_transmittance_lut
+                        .run_if(resource_changed::<CloudAtmosphere>),
+                ),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    render::add_cloud_volume_aabbs.in_set(VisibilitySystems::CalculateBounds),
+                    check_visibility::<WithCloudVolume>.in_set(VisibilitySystems::CheckVisibility),
+                ),
+            )
+            .add_plugins(ExtractResourcePlugin::<CloudAtmosphere>::default());
 
         let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
             return;
@@ -197,13 +653,46 @@ impl Plugin for VolumetricCloudPlugin {
 
         render_app
             .init_resource::<SpecializedRenderPipelines<VolumetricCloudPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<AmbientAirPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<CloudShadowMapPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<CloudUpsamplePipeline>>()
+            .init_resource::<SpecializedRenderPipelines<CloudTemporalPipeline>>()
+            .init_resource::<SpecializedComputePipelines<CloudComputePipeline>>()
             .init_resource::<VolumetricCloudUniformBuffer>()
-            .add_systems(ExtractSchedule, render::extract_volumetric_cloud)
+            .init_resource::<VolumetricCloudBindGroupCache>()
+            .init_resource::<AmbientAirUniformBuffer>()
+            .init_resource::<CloudShadowMapUniformBuffer>()
+            .init_resource::<CloudTemporalUniformBuffer>()
+            .init_resource::<CloudTemporalHistory>()
+            .init_resource::<CloudComputeVolumeBuffer>()
+            .init_resource::<CloudTileBinBuffers>()
+            .add_systems(
+                ExtractSchedule,
+                (render::extract_volumetric_cloud, air::extract_cloud_sun),
+            )
             .add_systems(
                 Render,
                 (
                     render::prepare_volumetric_cloud_pipelines.in_set(RenderSet::Prepare),
                     render::prepare_volumetric_cloud_uniforms.in_set(RenderSet::Prepare),
+                    air::prepare_ambient_air_pipelines.in_set(RenderSet::Prepare),
+                    air::prepare_ambient_air.in_set(RenderSet::Prepare),
+                    upsample::prepare_cloud_upsample_pipelines.in_set(RenderSet::Prepare),
+                    upsample::prepare_cloud_offscreen_textures.in_set(RenderSet::Prepare),
+                    temporal::prepare_cloud_temporal_pipelines.in_set(RenderSet::Prepare),
+                    temporal::prepare_cloud_temporal.in_set(RenderSet::Prepare),
+                    compute::prepare_cloud_tile_bins
+                        .in_set(RenderSet::Prepare)
+                        .after(render::prepare_volumetric_cloud_uniforms),
+                    render::prepare_volumetric_cloud_bind_groups
+                        .in_set(RenderSet::PrepareBindGroups)
+                        .after(render::prepare_volumetric_cloud_uniforms),
+                    compute::prepare_cloud_compute_bind_groups.in_set(RenderSet::PrepareBindGroups),
+                    // Remember this frame's transforms for the next one, once
+                    // everything that needs the previous frame's is done.
+                    temporal::record_cloud_temporal_history
+                        .in_set(RenderSet::Cleanup)
+                        .before(World::clear_entities),
                     render::prepare_view_depth_textures_for_volumetric_fog
                         .in_set(RenderSet::Prepare)
                         .before(prepare_core_3d_depth_textures),
@@ -218,6 +707,13 @@ impl Plugin for VolumetricCloudPlugin {
         info!("VolumetricCloudPlugin finish");
         render_app
             .init_resource::<VolumetricCloudPipeline>()
+            .init_resource::<AmbientAirPipeline>()
+            .init_resource::<CloudShadowMapPipeline>()
+            .init_resource::<CloudUpsamplePipeline>()
+            .init_resource::<CloudTemporalPipeline>()
+            // This shares the shader and layouts of `VolumetricCloudPipeline`,
+            // so it must come after it.
+            .init_resource::<CloudComputePipeline>()
             .add_render_graph_node::<ViewNodeRunner<VolumetricCloudNode>>(
                 Core3d,
                 VolumetricCloudPass,
@@ -235,29 +731,102 @@ impl Plugin for VolumetricCloudPlugin {
     }
 }
 
+impl Default for VolumetricCloudLight {
+    fn default() -> Self {
+        Self {
+            intensity: 1.0,
+            tint: Color::WHITE,
+            step_count: 0,
+            scattering_asymmetry: None,
+            shadow_map: true,
+            light_links: 1,
+        }
+    }
+}
+
 impl Default for VolumetricCloudSettings {
     fn default() -> Self {
         Self {
             step_count: 64,
+            adaptive_steps: None,
             // Matches `AmbientLight` defaults.
             ambient_color: Color::WHITE,
             ambient_intensity: 0.1,
             jitter: 0.0,
+            integrator: VolumetricCloudIntegrator::Analytic,
+            resolution_scale: CloudResolutionScale::Full,
+            temporal: None,
+            update_pattern: CloudUpdatePattern::Full,
+            backend: VolumetricCloudBackend::Raster,
+            ground_albedo: Color::BLACK,
+            ambient_air: None,
+        }
+    }
+}
+
+impl VolumetricCloudSettings {
+    /// Returns true if the clouds are raymarched into offscreen targets and
+    /// composited onto the view afterward, instead of being drawn straight
+    /// onto it.
+    pub fn renders_offscreen(&self) -> bool {
+        self.resolution_scale != CloudResolutionScale::Full
+            || self.temporal.is_some()
+            || self.backend == VolumetricCloudBackend::Compute
+    }
+
+    /// Returns the size of the blocks of the update pattern that's in effect,
+    /// which is 1 if every pixel is raymarched in every frame.
+    pub fn update_pattern_size(&self) -> u32 {
+        if self.temporal.is_some() {
+            self.update_pattern.size()
+        } else {
+            1
         }
     }
 }
 
+impl CloudVolume {
+    /// Returns the absorption coefficient of each color channel.
+    pub fn absorption_rgb(&self) -> Vec3 {
+        self.chromatic_absorption
+            .unwrap_or(Vec3::splat(self.absorption))
+    }
+
+    /// Returns the scattering coefficient of each color channel.
+    pub fn scattering_rgb(&self) -> Vec3 {
+        self.chromatic_scattering
+            .unwrap_or(Vec3::splat(self.scattering))
+    }
+
+    /// Returns true if this volume's emission comes from the blackbody ramp of
+    /// the temperatures in its emission texture.
+    pub fn emits_blackbody(&self) -> bool {
+        self.emission_texture.is_some()
+            && matches!(
+                self.emission_texture_mode,
+                CloudEmissionTextureMode::Temperature { .. }
+            )
+    }
+}
+
 impl Default for CloudVolume {
     fn default() -> Self {
         Self {
             absorption: 0.3,
             scattering: 0.3,
+            chromatic_absorption: None,
+            chromatic_scattering: None,
             density_factor: 0.1,
             density_texture: None,
             scattering_asymmetry: 0.5,
             fog_color: Color::WHITE,
             light_tint: Color::WHITE,
             light_intensity: 1.0,
+            light_links: 1,
+            emissive_color: Color::WHITE,
+            emissive_intensity: 0.0,
+            emission_texture: None,
+            emission_texture_mode: CloudEmissionTextureMode::Intensity,
         }
     }
 }
@@ -3,50 +3,94 @@
 
 use bevy::{
     core_pipeline::prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
-    ecs::{query::QueryItem, system::lifetimeless::Read},
-    math::{vec4, Mat3A, Vec3A},
+    ecs::{entity::EntityHashMap, query::QueryItem, system::lifetimeless::Read},
+    math::{vec2, vec3, vec4, Mat3A, URect, Vec3A},
     pbr::{
+        irradiance_volume::IrradianceVolume, ExtractedDirectionalLight, MeshPipeline,
         MeshPipelineViewLayoutKey, MeshPipelineViewLayouts, MeshViewBindGroup,
-        ViewFogUniformOffset, ViewLightProbesUniformOffset, ViewLightsUniformOffset,
-        ViewScreenSpaceReflectionsUniformOffset,
+        RenderViewLightProbes, ShadowFilteringMethod, ViewFogUniformOffset,
+        ViewLightProbesUniformOffset, ViewLightsUniformOffset,
+        ViewScreenSpaceReflectionsUniformOffset, MAX_DIRECTIONAL_LIGHTS,
     },
     prelude::*,
     render::{
         mesh::{GpuBufferInfo, GpuMesh, MeshVertexBufferLayoutRef},
+        primitives::Aabb,
         render_asset::RenderAssets,
         render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
         render_resource::{
             binding_types::{
-                sampler, texture_3d, texture_depth_2d, texture_depth_2d_multisampled,
+                sampler, texture_2d, texture_3d, texture_depth_2d, texture_depth_2d_multisampled,
                 uniform_buffer,
             },
-            BindGroupLayout, BindGroupLayoutEntries, BindingResource, BlendComponent, BlendFactor,
-            BlendOperation, BlendState, CachedRenderPipelineId, ColorTargetState, ColorWrites,
-            DynamicBindGroupEntries, DynamicUniformBuffer, Face, FragmentState, LoadOp,
-            MultisampleState, Operations, PipelineCache, PrimitiveState, RenderPassColorAttachment,
-            RenderPassDescriptor, RenderPipelineDescriptor, SamplerBindingType, ShaderStages,
-            ShaderType, SpecializedRenderPipeline, SpecializedRenderPipelines, StoreOp,
-            TextureFormat, TextureSampleType, TextureUsages, VertexState,
+            BindGroup, BindGroupLayout, BindGroupLayoutEntries, BindingResource, BlendComponent,
+            BlendFactor, BlendOperation, BlendState, BufferId, CachedRenderPipelineId,
+            ColorTargetState, ColorWrites, DynamicBindGroupEntries, DynamicUniformBuffer, Face,
+            FragmentState, LoadOp, MultisampleState, Operations, PipelineCache, PrimitiveState,
+            RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            SamplerBindingType, ShaderDefVal, ShaderStages, ShaderType,
+            SpecializedComputePipelines, SpecializedRenderPipeline, SpecializedRenderPipelines,
+            StoreOp, TextureFormat, TextureSampleType, TextureUsages, TextureViewId, VertexState,
         },
         renderer::{RenderContext, RenderDevice, RenderQueue},
+        settings::WgpuFeatures,
         texture::{BevyDefault, GpuImage},
-        view::{ExtractedView, ViewDepthTexture, ViewTarget, ViewUniformOffset},
+        view::{ExtractedView, ViewDepthTexture, ViewTarget, ViewUniformOffset, VisibleEntities},
         Extract,
     },
+    utils::HashMap,
 };
 use bitflags::bitflags;
-use std::array;
-
-use crate::volumetric_clouds::*;
+use std::{array, f32::consts::PI, mem};
+
+use crate::volumetric_clouds::{
+    air::{render_ambient_air, ViewAmbientAir, ViewAmbientAirPipelines},
+    atmosphere::ATMOSPHERE_TRANSMITTANCE_LUT,
+    blackbody::BLACKBODY_LUT,
+    compute::{
+        render_cloud_volumes_compute, CloudComputePipeline, CloudComputePipelineKey,
+        CloudComputeVolumeBuffer, ViewCloudComputeBindGroup, ViewCloudComputePipelines,
+        ViewCloudTileBins,
+    },
+    lightning::{CloudLightningBolt, MAX_CLOUD_LIGHTNING_SEGMENTS},
+    temporal::{
+        render_cloud_temporal, CloudTemporalHistory, ViewCloudTemporal, ViewCloudTemporalPipeline,
+    },
+    upsample::{
+        clear_cloud_offscreen_textures, render_cloud_upsample, ViewCloudOffscreenTextures,
+        ViewCloudUpsamplePipeline, CLOUD_OFFSCREEN_FORMAT,
+    },
+    *,
+};
 
 bitflags! {
     /// Flags that describe the bind group layout used to render volumetric cloud.
-    #[derive(Clone, Copy, PartialEq)]
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
     struct VolumetricCloudBindGroupLayoutKey: u8 {
         /// The framebuffer is multisampled.
         const MULTISAMPLED = 0x1;
         /// The volumetric fog has a 3D voxel density texture.
         const DENSITY_TEXTURE = 0x2;
+        /// The cloud volume has a 3D emission texture.
+        const EMISSION_TEXTURE = 0x4;
+    }
+}
+
+bitflags! {
+    /// Flags that describe how a directional light affects the clouds.
+    ///
+    /// These must match the constants in `volumetric_clouds.wgsl`.
+    #[derive(Clone, Copy, PartialEq)]
+    struct VolumetricCloudLightFlags: u32 {
+        /// The light has a [`VolumetricCloudLight`] and lights the clouds.
+        const ENABLED = 0x1;
+        /// The clouds look up the light's shadow map.
+        const SHADOW_MAP = 0x2;
+        /// The light overrides the scattering asymmetry of the volumes.
+        const SCATTERING_ASYMMETRY = 0x4;
+        /// The light is the [`CloudSun`], and is filtered by the atmosphere
+        /// down to each sample.
+        const SUN = 0x8;
     }
 }
 
@@ -54,11 +98,42 @@ bitflags! {
     /// Flags that describe the rasterization pipeline used to render volumetric
     /// fog.
     #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-    struct VolumetricCloudPipelineKeyFlags: u8 {
+    pub(crate) struct VolumetricCloudPipelineKeyFlags: u16 {
         /// The view's color format has high dynamic range.
         const HDR = 0x1;
         /// The volumetric fog has a 3D voxel density texture.
         const DENSITY_TEXTURE = 0x2;
+        /// The cloud volume has a 3D emission texture.
+        const EMISSION_TEXTURE = 0x4;
+        /// The emission texture stores temperatures, which are mapped through
+        /// the blackbody lookup table.
+        const BLACKBODY = 0x8;
+        /// The device supports dual-source blending, so the background can be
+        /// attenuated by a separate transmittance for each color channel.
+        const DUAL_SOURCE_BLENDING = 0x10;
+        /// Each raymarching step is integrated analytically.
+        const ANALYTIC_INTEGRATION = 0x20;
+        /// The view has irradiance volumes, which light the clouds with baked
+        /// global illumination.
+        const IRRADIANCE_VOLUME = 0x40;
+        /// The view filters shadow maps with
+        /// [`ShadowFilteringMethod::Gaussian`].
+        const SHADOW_FILTER_METHOD_GAUSSIAN = 0x80;
+        /// The view filters shadow maps with
+        /// [`ShadowFilteringMethod::Temporal`].
+        ///
+        /// If neither this flag nor
+        /// [`Self::SHADOW_FILTER_METHOD_GAUSSIAN`] is set, the view uses
+        /// [`ShadowFilteringMethod::Hardware2x2`].
+        const SHADOW_FILTER_METHOD_TEMPORAL = 0x100;
+        /// The clouds are rendered into the offscreen targets of
+        /// [`ViewCloudOffscreenTextures`] instead of the view.
+        const OFFSCREEN = 0x200;
+        /// The clouds also write their motion since the previous frame, for
+        /// temporal accumulation.
+        const TEMPORAL = 0x400;
+        /// The length of the raymarching steps adapts to the medium.
+        const ADAPTIVE_STEPS = 0x800;
     }
 }
 
@@ -76,6 +151,11 @@ pub const PLANE_MESH: Handle<Mesh> = Handle::weak_from_u128(435245126479971076);
 /// hardware. The back faces will be calculated in the shader via raytracing.
 pub const CUBE_MESH: Handle<Mesh> = Handle::weak_from_u128(5023959819001661507);
 
+/// Whether the mesh view bind group has irradiance volumes in it.
+///
+/// This mirrors the private constant of the same name in `bevy_pbr`.
+const IRRADIANCE_VOLUMES_ARE_USABLE: bool = cfg!(not(target_arch = "wasm32"));
+
 /// The total number of bind group layouts.
 ///
 /// This is the total number of combinations of all
@@ -93,9 +173,9 @@ static UVW_FROM_LOCAL: Mat4 = Mat4::from_cols(
 );
 
 /// The GPU pipeline for the volumetric cloud postprocessing effect.
-#[derive(Resource)]
+#[derive(Resource, Clone)]
 pub struct VolumetricCloudPipeline {
-    shader: Handle<Shader>,
+    pub(crate) shader: Handle<Shader>,
     /// A reference to the shared set of mesh pipeline view layouts.
     mesh_view_layouts: MeshPipelineViewLayouts,
 
@@ -103,20 +183,21 @@ pub struct VolumetricCloudPipeline {
     ///
     /// Since there aren't too many of these, we precompile them all.
     volumetric_view_bind_group_layouts: [BindGroupLayout; VOLUMETRIC_CLOUD_BIND_GROUP_LAYOUT_COUNT],
-}
 
-/// The two render pipelines that we use for cloud volumes: one for when a 3D
-/// density texture is present and one for when it isn't.
-/// TODO: remove the textureless pipeline, as clouds don't need it
-#[derive(Component)]
-pub struct ViewVolumetricFogPipelines {
-    /// The render pipeline that we use when no density texture is present, and
-    /// the density distribution is uniform.
-    pub textureless: CachedRenderPipelineId,
-    /// The render pipeline that we use when a density texture is present.
-    pub textured: CachedRenderPipelineId,
+    /// Whether the mesh view bind group holds the light probes in binding
+    /// arrays, copied from the [`MeshPipeline`].
+    binding_arrays_are_usable: bool,
 }
 
+/// The render pipelines that we use for the cloud volumes in a view, keyed by
+/// the features that each volume needs.
+///
+/// Only combinations that some cloud volume actually uses are specialized.
+#[derive(Component, Default)]
+pub struct ViewVolumetricFogPipelines(
+    HashMap<VolumetricCloudPipelineKeyFlags, CachedRenderPipelineId>,
+);
+
 /// The node in the render graph, part of the postprocessing stack, that
 /// implements volumetric clouds.
 #[derive(Default)]
@@ -163,19 +244,116 @@ pub struct VolumetricCloudUniform {
 
     fog_color: Vec3,
     light_tint: Vec3,
+
+    /// The emitted luminance, premultiplied by the intensity unless the
+    /// emission texture stores temperatures.
+    emissive: Vec3,
+
+    /// The temperatures, in kelvin, that the emission texture values 0 and 1
+    /// map to.
+    temperature_range: Vec2,
+
     ambient_color: Vec3,
     ambient_intensity: f32,
     step_count: u32,
 
+    /// The number of valid entries in [`Self::lightning`].
+    lightning_count: u32,
+
+    /// The lightning bolt segments that light this volume from within.
+    lightning: [CloudLightningSegmentUniform; MAX_CLOUD_LIGHTNING_SEGMENTS],
+
+    /// The [`VolumetricCloudLight`] settings of each directional light, in
+    /// the same order as the lights in the view bind group.
+    cloud_lights: [VolumetricCloudLightUniform; MAX_DIRECTIONAL_LIGHTS],
+
     /// The radius of a sphere that bounds the cloud volume in view space.
     bounding_radius: f32,
 
-    absorption: f32,
-    scattering: f32,
+    absorption: Vec3,
+    scattering: Vec3,
     density: f32,
     scattering_asymmetry: f32,
     light_intensity: f32,
     jitter_strength: f32,
+
+    /// The radii of the planet and of the top of its atmosphere, used to look
+    /// up the atmospheric transmittance.
+    atmosphere_bottom_radius: f32,
+    atmosphere_top_radius: f32,
+
+    /// The altitude above sea level of the origin of the scene.
+    scene_altitude: f32,
+
+    /// The albedo of the ground, which reflects light onto the clouds.
+    ground_albedo: Vec3,
+
+    /// The size of the target that the clouds are rendered into, relative to
+    /// the size of the view.
+    resolution_scale: Vec2,
+
+    /// The transform from world space in this frame to clip space in the
+    /// previous frame, following the motion of the volume.
+    previous_clip_from_world: Mat4,
+
+    /// The size of the blocks of the update pattern, which is 1 if every pixel
+    /// is raymarched in every frame.
+    update_pattern_size: u32,
+
+    /// The view-space depth of the nearest point of the cloud volume, or 0 if
+    /// the camera is inside it.
+    nearest_view_depth: f32,
+
+    /// The bounds on the length of adaptive steps, and how the length grows
+    /// with the distance from the camera. See
+    /// [`CloudAdaptiveSteps`](crate::volumetric_clouds::CloudAdaptiveSteps).
+    ///
+    /// With adaptive steps, [`Self::step_count`] is the step budget.
+    min_step_size: f32,
+    max_step_size: f32,
+    step_distance_factor: f32,
+}
+
+/// A single segment of a lightning bolt, formatted for the GPU.
+#[derive(Clone, Copy, Default, ShaderType)]
+pub struct CloudLightningSegmentUniform {
+    /// The world-space start of the segment.
+    start: Vec3,
+    /// The world-space end of the segment.
+    end: Vec3,
+    /// The luminous intensity that each meter of the segment emits, in cd/m,
+    /// premultiplied by the flash color.
+    intensity: Vec3,
+}
+
+/// The [`VolumetricCloudLight`] settings of a single directional light,
+/// formatted for the GPU.
+#[derive(Clone, Copy, Default, ShaderType)]
+pub struct VolumetricCloudLightUniform {
+    /// The tint, premultiplied by the intensity.
+    tint: Vec3,
+    /// The number of steps in the march toward the light.
+    step_count: u32,
+    /// The scattering asymmetry, if
+    /// [`VolumetricCloudLightFlags::SCATTERING_ASYMMETRY`] is set.
+    scattering_asymmetry: f32,
+    /// The [`VolumetricCloudLightFlags`].
+    flags: u32,
+    /// The illuminance of the light above the atmosphere, in lux per color
+    /// channel, if [`VolumetricCloudLightFlags::SUN`] is set.
+    top_of_atmosphere: Vec3,
+}
+
+/// A [`CloudLightning`] flash, extracted to the render world.
+#[derive(Clone, Component)]
+pub struct ExtractedCloudLightning {
+    /// The world-space position of the flash origin.
+    origin: Vec3,
+    /// The world-space segments of the bolt.
+    segments: Vec<(Vec3, Vec3)>,
+    /// The luminous intensity that each meter of the bolt emits, in cd/m,
+    /// premultiplied by the flash color.
+    intensity: Vec3,
 }
 
 // /// Inserted on each `Entity` with an `ExtractedView` to keep track of its offset
@@ -187,34 +365,91 @@ pub struct VolumetricCloudUniform {
 
 /// Specifies the offset within the [`VolumetricCloudUniformBuffer`] of the
 /// [`VolumetricCloudUniform`] for a specific view.
+///
+/// The volumes are sorted back to front by the depth of their centers, which
+/// is the order that the raster backend draws them in. Each is blended over
+/// the ones before it as a whole, so interpenetrating volumes don't dim each
+/// other there; see [`VolumetricCloudBackend::Raster`].
 #[derive(Component, Deref, DerefMut)]
 pub struct ViewVolumetricCloud(Vec<ViewCloudVolume>);
 
 /// Information that the render world needs to maintain about each fog volume.
 pub struct ViewCloudVolume {
     /// The 3D voxel density texture for this volume, if present.
-    density_texture: Option<AssetId<Image>>,
+    pub(crate) density_texture: Option<AssetId<Image>>,
+    /// The 3D emission texture for this volume, if present.
+    pub(crate) emission_texture: Option<AssetId<Image>>,
+    /// True if the emission texture stores temperatures.
+    blackbody: bool,
     /// The offset of this view's [`VolumetricCloudUniform`] structure within the
     /// [`VolumetricCloudUniformBuffer`].
-    uniform_buffer_offset: u32,
+    pub(crate) uniform_buffer_offset: u32,
     /// True if the camera is outside the cloud volume; false if it's inside the
     /// cloud volume.
     exterior: bool,
+    /// The texels of the cloud target that the volume covers on screen, which
+    /// may be empty.
+    pub(crate) screen_rect: URect,
+    /// The view-space depth of the nearest point of the volume, or 0 if the
+    /// camera is inside it.
+    pub(crate) nearest_view_depth: f32,
+    /// The index of this volume's [`VolumetricCloudUniform`] within the
+    /// [`CloudComputeVolumeBuffer`], if the view uses the compute backend.
+    pub(crate) compute_volume_index: Option<u32>,
+    /// The bind group of this volume, or `None` if the uniforms or the
+    /// atmospheric transmittance lookup table aren't ready yet.
+    pub(crate) bind_group: Option<BindGroup>,
 }
 
 /// The GPU buffer that stores the [`VolumetricCloudUniform`] data.
 #[derive(Resource, Default, Deref, DerefMut)]
 pub struct VolumetricCloudUniformBuffer(pub DynamicUniformBuffer<VolumetricCloudUniform>);
 
+/// The bind groups of the cloud volumes, kept from one frame to the next.
+///
+/// Each bind group is keyed by everything that it binds, so it's only created
+/// again when one of those changes, such as when the uniform buffer grows or
+/// an image is reloaded. Bind groups that no volume used in a frame are
+/// dropped.
+#[derive(Resource, Default)]
+pub struct VolumetricCloudBindGroupCache {
+    bind_groups: HashMap<VolumetricCloudBindGroupCacheKey, BindGroup>,
+}
+
+/// Identifies a bind group in the [`VolumetricCloudBindGroupCache`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct VolumetricCloudBindGroupCacheKey {
+    /// The layout of the bind group.
+    layout_key: VolumetricCloudBindGroupLayoutKey,
+    /// The [`VolumetricCloudUniformBuffer`], which is reallocated when it
+    /// grows.
+    uniform_buffer: BufferId,
+    /// The depth texture of the view.
+    depth_view: TextureViewId,
+    /// The density texture of the volume, if it has one.
+    density_view: Option<TextureViewId>,
+    /// The emission texture of the volume, if it has one.
+    emission_view: Option<TextureViewId>,
+    /// The blackbody lookup table, if the volume has an emission texture.
+    blackbody_lut_view: Option<TextureViewId>,
+    /// The atmospheric transmittance lookup table, which is rebuilt when the
+    /// [`CloudAtmosphere`] changes.
+    atmosphere_transmittance_lut_view: TextureViewId,
+}
+
 impl FromWorld for VolumetricCloudPipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
         let mesh_view_layouts = world.resource::<MeshPipelineViewLayouts>();
+        let binding_arrays_are_usable = world.resource::<MeshPipeline>().binding_arrays_are_usable;
 
         // Create the bind group layout entries common to all bind group
         // layouts.
+        //
+        // These are shared with the compute backend, so they're visible to
+        // compute shaders too.
         let base_bind_group_layout_entries = &BindGroupLayoutEntries::single(
-            ShaderStages::VERTEX_FRAGMENT,
+            ShaderStages::VERTEX_FRAGMENT | ShaderStages::COMPUTE,
             // `volumetric_fog`
             uniform_buffer::<VolumetricCloudUniform>(true),
         );
@@ -228,7 +463,7 @@ impl FromWorld for VolumetricCloudPipeline {
 
             // `depth_texture`
             bind_group_layout_entries.extend_from_slice(&BindGroupLayoutEntries::with_indices(
-                ShaderStages::FRAGMENT,
+                ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
                 ((
                     1,
                     if flags.contains(VolumetricCloudBindGroupLayoutKey::MULTISAMPLED) {
@@ -242,7 +477,7 @@ impl FromWorld for VolumetricCloudPipeline {
             // `density_texture` and `density_sampler`
             if flags.contains(VolumetricCloudBindGroupLayoutKey::DENSITY_TEXTURE) {
                 bind_group_layout_entries.extend_from_slice(&BindGroupLayoutEntries::with_indices(
-                    ShaderStages::FRAGMENT,
+                    ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
                     (
                         (2, texture_3d(TextureSampleType::Float { filterable: true })),
                         (3, sampler(SamplerBindingType::Filtering)),
@@ -250,6 +485,30 @@ impl FromWorld for VolumetricCloudPipeline {
                 ));
             }
 
+            // `emission_texture`, `emission_sampler`, and `blackbody_lut`
+            if flags.contains(VolumetricCloudBindGroupLayoutKey::EMISSION_TEXTURE) {
+                bind_group_layout_entries.extend_from_slice(&BindGroupLayoutEntries::with_indices(
+                    ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                    (
+                        (4, texture_3d(TextureSampleType::Float { filterable: true })),
+                        (5, sampler(SamplerBindingType::Filtering)),
+                        (
+                            6,
+                            texture_2d(TextureSampleType::Float { filterable: false }),
+                        ),
+                    ),
+                ));
+            }
+
+            // `atmosphere_transmittance_lut`
+            bind_group_layout_entries.extend_from_slice(&BindGroupLayoutEntries::with_indices(
+                ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                ((
+                    7,
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                ),),
+            ));
+
             // Create the bind group layout.
             let description = flags.bind_group_layout_description();
             render_device.create_bind_group_layout(&*description, &bind_group_layout_entries)
@@ -263,37 +522,88 @@ impl FromWorld for VolumetricCloudPipeline {
             shader,
             mesh_view_layouts: mesh_view_layouts.clone(),
             volumetric_view_bind_group_layouts: bind_group_layouts,
+            binding_arrays_are_usable,
         }
     }
 }
 
+/// Gives each [`CloudVolume`] that doesn't have one an [`Aabb`] that covers its
+/// 1×1×1 local space, so that it can be frustum culled.
+pub fn add_cloud_volume_aabbs(
+    mut commands: Commands,
+    cloud_volumes: Query<Entity, (With<CloudVolume>, Without<Aabb>)>,
+) {
+    for entity in cloud_volumes.iter() {
+        commands
+            .entity(entity)
+            .insert(Aabb::from_min_max(Vec3::splat(-0.5), Vec3::splat(0.5)));
+    }
+}
+
 /// Extracts [`VolumetricCloudSettings`], [`CloudVolume`], and [`VolumetricCloudLight`]s
 /// from the main world to the render world.
+///
+/// Hidden cloud volumes aren't extracted. Those outside every view still are,
+/// since they can cast shadows into the views; each view only draws the ones
+/// in its [`VisibleEntities`].
 pub fn extract_volumetric_cloud(
     mut commands: Commands,
     view_targets: Extract<Query<(Entity, &VolumetricCloudSettings)>>,
-    cloud_volumes: Extract<Query<(Entity, &CloudVolume, &GlobalTransform)>>,
-    volumetric_lights: Extract<Query<(Entity, &VolumetricCloudLight)>>,
+    cloud_volumes: Extract<Query<(Entity, &CloudVolume, &GlobalTransform, &InheritedVisibility)>>,
+    volumetric_lights: Extract<Query<(Entity, &VolumetricCloudLight, Option<&CloudSun>)>>,
+    cloud_lightning: Extract<
+        Query<(
+            Entity,
+            &CloudLightning,
+            &CloudLightningBolt,
+            &GlobalTransform,
+        )>,
+    >,
 ) {
-    if volumetric_lights.is_empty() {
-        return;
-    }
-
     for (entity, volumetric_fog_settings) in view_targets.iter() {
         commands
             .get_or_spawn(entity)
             .insert(*volumetric_fog_settings);
     }
 
-    for (entity, fog_volume, fog_transform) in cloud_volumes.iter() {
+    for (entity, fog_volume, fog_transform, inherited_visibility) in cloud_volumes.iter() {
+        if !inherited_visibility.get() {
+            continue;
+        }
+
         commands
             .get_or_spawn(entity)
             .insert((*fog_volume).clone())
             .insert(*fog_transform);
     }
 
-    for (entity, volumetric_light) in volumetric_lights.iter() {
-        commands.get_or_spawn(entity).insert(*volumetric_light);
+    for (entity, volumetric_light, sun) in volumetric_lights.iter() {
+        let mut entity_commands = commands.get_or_spawn(entity);
+        entity_commands.insert(*volumetric_light);
+        if let Some(sun) = sun {
+            entity_commands.insert(*sun);
+        }
+    }
+
+    for (entity, lightning, bolt, transform) in cloud_lightning.iter() {
+        // Spread the luminous power of the flash evenly along the bolt, and
+        // over all directions.
+        let luminous_power = lightning.intensity * bolt.brightness;
+        let intensity = lightning.color.to_linear().to_vec3() * luminous_power
+            / (4.0 * PI * bolt.total_length().max(f32::EPSILON));
+
+        let origin = transform.translation();
+        commands
+            .get_or_spawn(entity)
+            .insert(ExtractedCloudLightning {
+                origin,
+                segments: bolt
+                    .segments
+                    .iter()
+                    .map(|&(start, end)| (origin + start, origin + end))
+                    .collect(),
+                intensity,
+            });
     }
 }
 
@@ -309,6 +619,17 @@ impl ViewNode for VolumetricCloudNode {
         Read<ViewVolumetricCloud>,
         Read<MeshViewBindGroup>,
         Read<ViewScreenSpaceReflectionsUniformOffset>,
+        Option<Read<ViewAmbientAir>>,
+        Option<Read<ViewAmbientAirPipelines>>,
+        (
+            Option<Read<ViewCloudOffscreenTextures>>,
+            Option<Read<ViewCloudUpsamplePipeline>>,
+            Option<Read<ViewCloudTemporal>>,
+            Option<Read<ViewCloudTemporalPipeline>>,
+            Option<Read<ViewCloudComputePipelines>>,
+            Option<Read<ViewCloudComputeBindGroup>>,
+            Option<Read<ViewCloudTileBins>>,
+        ),
     );
 
     fn run<'w>(
@@ -326,144 +647,213 @@ impl ViewNode for VolumetricCloudNode {
             view_fog_volumes,
             view_bind_group,
             view_ssr_offset,
+            view_ambient_air,
+            view_ambient_air_pipelines,
+            (
+                view_offscreen_textures,
+                view_upsample_pipeline,
+                view_temporal,
+                view_temporal_pipeline,
+                view_compute_pipelines,
+                view_compute_bind_group,
+                view_tile_bins,
+            ),
         ): QueryItem<'w, Self::ViewQuery>,
         world: &'w World,
     ) -> Result<(), NodeRunError> {
+        let view_bind_group_offsets = [
+            view_uniform_offset.offset,
+            view_lights_offset.offset,
+            view_fog_offset.offset,
+            **view_light_probes_offset,
+            **view_ssr_offset,
+        ];
+
         let pipeline_cache = world.resource::<PipelineCache>();
-        let volumetric_lighting_pipeline = world.resource::<VolumetricCloudPipeline>();
-        let volumetric_lighting_uniform_buffers = world.resource::<VolumetricCloudUniformBuffer>();
         let image_assets = world.resource::<RenderAssets<GpuImage>>();
-        let msaa = world.resource::<Msaa>();
-
-        // Fetch the uniform buffer and binding.
-        let (
-            Some(textureless_pipeline),
-            Some(textured_pipeline),
-            Some(volumetric_lighting_uniform_buffer_binding),
-        ) = (
-            pipeline_cache.get_render_pipeline(view_volumetric_lighting_pipelines.textureless),
-            pipeline_cache.get_render_pipeline(view_volumetric_lighting_pipelines.textured),
-            volumetric_lighting_uniform_buffers.binding(),
-        )
-        else {
-            return Ok(());
-        };
-
         let gpu_meshes = world.resource::<RenderAssets<GpuMesh>>();
 
-        for view_fog_volume in view_fog_volumes.iter() {
-            // If the camera is outside the fog volume, pick the cube mesh;
-            // otherwise, pick the plane mesh. In the latter case we'll be
-            // effectively rendering a full-screen quad.
-            let mesh_handle = if view_fog_volume.exterior {
-                CUBE_MESH.clone()
-            } else {
-                PLANE_MESH.clone()
-            };
-
-            let density_image = view_fog_volume
-                .density_texture
-                .and_then(|density_texture| image_assets.get(density_texture));
-
-            // Pick the right pipeline, depending on whether a density texture
-            // is present or not.
-            let pipeline = if density_image.is_some() {
-                textured_pipeline
-            } else {
-                textureless_pipeline
-            };
-
-            // This should always succeed, but if the asset was unloaded don't
-            // panic.
-            let Some(gpu_mesh) = gpu_meshes.get(&mesh_handle) else {
-                return Ok(());
-            };
-
-            // Create the bind group for the view.
-            //
-            // TODO: Cache this.
-
-            let mut bind_group_layout_key = VolumetricCloudBindGroupLayoutKey::empty();
-            bind_group_layout_key.set(
-                VolumetricCloudBindGroupLayoutKey::MULTISAMPLED,
-                !matches!(*msaa, Msaa::Off),
-            );
-
-            // Create the bind group entries. The ones relating to the density
-            // texture will only be filled in if that texture is present.
-            let mut bind_group_entries = DynamicBindGroupEntries::sequential((
-                volumetric_lighting_uniform_buffer_binding.clone(),
-                BindingResource::TextureView(view_depth_texture.view()),
-            ));
-            if let Some(density_image) = density_image {
-                bind_group_layout_key.insert(VolumetricCloudBindGroupLayoutKey::DENSITY_TEXTURE);
-                bind_group_entries = bind_group_entries.extend_sequential((
-                    BindingResource::TextureView(&density_image.texture_view),
-                    BindingResource::Sampler(&density_image.sampler),
-                ));
-            }
-
-            let volumetric_view_bind_group_layout = &volumetric_lighting_pipeline
-                .volumetric_view_bind_group_layouts[bind_group_layout_key.bits() as usize];
-
-            let volumetric_view_bind_group = render_context.render_device().create_bind_group(
-                None,
-                volumetric_view_bind_group_layout,
-                &bind_group_entries,
-            );
-
-            let render_pass_descriptor = RenderPassDescriptor {
-                label: Some("volumetric lighting pass"),
-                color_attachments: &[Some(RenderPassColorAttachment {
-                    view: view_target.main_texture_view(),
-                    resolve_target: None,
-                    ops: Operations {
-                        load: LoadOp::Load,
-                        store: StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            };
-
-            let mut render_pass = render_context
-                .command_encoder()
-                .begin_render_pass(&render_pass_descriptor);
+        // Below full resolution, start from empty targets, as the clouds are
+        // composited onto the view afterward.
+        let offscreen = view_offscreen_textures.zip(view_upsample_pipeline);
+        if let Some((offscreen_textures, _)) = offscreen {
+            clear_cloud_offscreen_textures(render_context, offscreen_textures);
+        }
 
-            render_pass.set_vertex_buffer(0, *gpu_mesh.vertex_buffer.slice(..));
-            render_pass.set_pipeline(pipeline);
-            render_pass.set_bind_group(
-                0,
-                &view_bind_group.value,
+        // The compute backend raymarches all the volumes in one go. Of the
+        // dynamic offsets, its view bind group only has those of the view,
+        // the lights, and the fog.
+        if let (
+            Some((offscreen_textures, _)),
+            Some(view_compute_pipelines),
+            Some(view_compute_bind_group),
+            Some(view_tile_bins),
+        ) = (
+            offscreen,
+            view_compute_pipelines,
+            view_compute_bind_group,
+            view_tile_bins,
+        ) {
+            render_cloud_volumes_compute(
+                render_context,
+                world,
+                view_compute_bind_group,
                 &[
                     view_uniform_offset.offset,
                     view_lights_offset.offset,
                     view_fog_offset.offset,
-                    **view_light_probes_offset,
-                    **view_ssr_offset,
                 ],
+                view_fog_volumes,
+                view_tile_bins,
+                offscreen_textures,
+                view_compute_pipelines,
             );
-            render_pass.set_bind_group(
-                1,
-                &volumetric_view_bind_group,
-                &[view_fog_volume.uniform_buffer_offset],
-            );
-
-            // Draw elements or arrays, as appropriate.
-            match &gpu_mesh.buffer_info {
-                GpuBufferInfo::Indexed {
-                    buffer,
-                    index_format,
-                    count,
-                } => {
-                    render_pass.set_index_buffer(*buffer.slice(..), *index_format);
-                    render_pass.draw_indexed(0..*count, 0, 0..1);
+        } else {
+            for view_fog_volume in view_fog_volumes.iter() {
+                // If the camera is outside the fog volume, pick the cube mesh;
+                // otherwise, pick the plane mesh. In the latter case we'll be
+                // effectively rendering a full-screen quad.
+                let mesh_handle = if view_fog_volume.exterior {
+                    CUBE_MESH.clone()
+                } else {
+                    PLANE_MESH.clone()
+                };
+
+                // Pick the right pipeline, depending on which textures are
+                // present. Skip volumes whose textures are still loading, or
+                // whose pipeline hasn't compiled yet.
+                let Some(pipeline) = view_fog_volume
+                    .pipeline_flags(image_assets)
+                    .and_then(|flags| view_volumetric_lighting_pipelines.0.get(&flags))
+                    .and_then(|pipeline_id| pipeline_cache.get_render_pipeline(*pipeline_id))
+                else {
+                    continue;
+                };
+
+                // This should always succeed, but if the asset was unloaded
+                // don't panic.
+                let Some(gpu_mesh) = gpu_meshes.get(&mesh_handle) else {
+                    continue;
+                };
+
+                let Some(volumetric_view_bind_group) = &view_fog_volume.bind_group else {
+                    continue;
+                };
+
+                let load_attachment = |view| {
+                    Some(RenderPassColorAttachment {
+                        view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Load,
+                            store: StoreOp::Store,
+                        },
+                    })
+                };
+                let color_attachments = match offscreen {
+                    Some((offscreen_textures, _)) => {
+                        let mut color_attachments = vec![
+                            load_attachment(&offscreen_textures.color.default_view),
+                            load_attachment(&offscreen_textures.transmittance.default_view),
+                        ];
+                        if let Some(motion) = &offscreen_textures.motion {
+                            color_attachments.push(load_attachment(&motion.default_view));
+                        }
+                        color_attachments
+                    }
+                    None => vec![load_attachment(view_target.main_texture_view())],
+                };
+
+                let render_pass_descriptor = RenderPassDescriptor {
+                    label: Some("volumetric lighting pass"),
+                    color_attachments: &color_attachments,
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                };
+
+                let mut render_pass = render_context
+                    .command_encoder()
+                    .begin_render_pass(&render_pass_descriptor);
+
+                render_pass.set_vertex_buffer(0, *gpu_mesh.vertex_buffer.slice(..));
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(0, &view_bind_group.value, &view_bind_group_offsets);
+                render_pass.set_bind_group(
+                    1,
+                    volumetric_view_bind_group,
+                    &[view_fog_volume.uniform_buffer_offset],
+                );
+
+                // Draw elements or arrays, as appropriate.
+                match &gpu_mesh.buffer_info {
+                    GpuBufferInfo::Indexed {
+                        buffer,
+                        index_format,
+                        count,
+                    } => {
+                        render_pass.set_index_buffer(*buffer.slice(..), *index_format);
+                        render_pass.draw_indexed(0..*count, 0, 0..1);
+                    }
+                    GpuBufferInfo::NonIndexed => {
+                        render_pass.draw(0..gpu_mesh.vertex_count, 0..1);
+                    }
                 }
-                GpuBufferInfo::NonIndexed => {
-                    render_pass.draw(0..gpu_mesh.vertex_count, 0..1);
+            }
+        }
+
+        if let Some((offscreen_textures, upsample_pipeline)) = offscreen {
+            // Blend the clouds into the history, and upsample that instead.
+            let (mut color, mut transmittance) = (
+                &offscreen_textures.color.default_view,
+                &offscreen_textures.transmittance.default_view,
+            );
+            if let (Some(view_temporal), Some(view_temporal_pipeline)) =
+                (view_temporal, view_temporal_pipeline)
+            {
+                if render_cloud_temporal(
+                    render_context,
+                    world,
+                    view_depth_texture,
+                    view_bind_group,
+                    &view_bind_group_offsets,
+                    offscreen_textures,
+                    view_temporal,
+                    view_temporal_pipeline,
+                ) {
+                    color = &view_temporal.write.color.default_view;
+                    transmittance = &view_temporal.write.transmittance.default_view;
                 }
             }
+
+            render_cloud_upsample(
+                render_context,
+                world,
+                view_target,
+                view_depth_texture,
+                view_bind_group,
+                &view_bind_group_offsets,
+                color,
+                transmittance,
+                upsample_pipeline,
+            );
+        }
+
+        // Draw the ambient air last, over the clouds. It stops at the clouds,
+        // so only the air in front of them is drawn over them.
+        if let (Some(view_ambient_air), Some(view_ambient_air_pipelines)) =
+            (view_ambient_air, view_ambient_air_pipelines)
+        {
+            render_ambient_air(
+                render_context,
+                world,
+                view_target,
+                view_depth_texture,
+                view_bind_group,
+                &view_bind_group_offsets,
+                view_ambient_air,
+                view_ambient_air_pipelines,
+            );
         }
 
         Ok(())
@@ -478,27 +868,15 @@ impl SpecializedRenderPipeline for VolumetricCloudPipeline {
             .mesh_view_layouts
             .get_view_layout(key.mesh_pipeline_view_key);
 
-        // We always use hardware 2x2 filtering for sampling the shadow map; the
-        // more accurate versions with percentage-closer filtering aren't worth
-        // the overhead.
-        let mut shader_defs = vec!["SHADOW_FILTER_METHOD_HARDWARE_2X2".into()];
+        let multisampled = key
+            .mesh_pipeline_view_key
+            .contains(MeshPipelineViewLayoutKey::MULTISAMPLED);
 
-        // We need a separate layout for MSAA and non-MSAA, as well as one for
-        // the presence or absence of the density texture.
-        let mut bind_group_layout_key = VolumetricCloudBindGroupLayoutKey::empty();
-        bind_group_layout_key.set(
-            VolumetricCloudBindGroupLayoutKey::MULTISAMPLED,
-            key.mesh_pipeline_view_key
-                .contains(MeshPipelineViewLayoutKey::MULTISAMPLED),
-        );
-        bind_group_layout_key.set(
-            VolumetricCloudBindGroupLayoutKey::DENSITY_TEXTURE,
-            key.flags
-                .contains(VolumetricCloudPipelineKeyFlags::DENSITY_TEXTURE),
-        );
+        let mut shader_defs = key.flags.shader_defs(multisampled);
 
-        let volumetric_view_bind_group_layout =
-            self.volumetric_view_bind_group_layouts[bind_group_layout_key.bits() as usize].clone();
+        let volumetric_view_bind_group_layout = self
+            .volumetric_view_bind_group_layout(key.flags, multisampled)
+            .clone();
 
         // Both the cube and plane have the same vertex layout, so we don't need
         // to distinguish between the two.
@@ -508,19 +886,107 @@ impl SpecializedRenderPipeline for VolumetricCloudPipeline {
             .get_layout(&[Mesh::ATTRIBUTE_POSITION.at_shader_location(0)])
             .expect("Failed to get vertex layout for volumetric fog hull");
 
-        if key
-            .mesh_pipeline_view_key
-            .contains(MeshPipelineViewLayoutKey::MULTISAMPLED)
-        {
-            shader_defs.push("MULTISAMPLED".into());
+        // The light probe bindings in the mesh view bind group depend on the
+        // device, whether or not this view uses them.
+        if self.binding_arrays_are_usable {
+            shader_defs.push("MULTIPLE_LIGHT_PROBES_IN_ARRAY".into());
         }
 
-        if key
+        if IRRADIANCE_VOLUMES_ARE_USABLE {
+            shader_defs.push("IRRADIANCE_VOLUMES_ARE_USABLE".into());
+
+            if key
+                .flags
+                .contains(VolumetricCloudPipelineKeyFlags::IRRADIANCE_VOLUME)
+            {
+                shader_defs.push("IRRADIANCE_VOLUME".into());
+            }
+        }
+
+        let format = if key.flags.contains(VolumetricCloudPipelineKeyFlags::HDR) {
+            ViewTarget::TEXTURE_FORMAT_HDR
+        } else {
+            TextureFormat::bevy_default()
+        };
+
+        // Offscreen, the color and the per-channel transmittance are
+        // accumulated in two separate targets, which the upsampling pass
+        // composites onto the view afterward. Color is blended over what's
+        // already there, attenuating it by the average transmittance, and
+        // transmittance is simply multiplied. Motion is blended like color, so
+        // that it ends up weighted by opacity.
+        let targets = if key
             .flags
-            .contains(VolumetricCloudPipelineKeyFlags::DENSITY_TEXTURE)
+            .contains(VolumetricCloudPipelineKeyFlags::OFFSCREEN)
         {
-            shader_defs.push("DENSITY_TEXTURE".into());
+            shader_defs.push("OFFSCREEN".into());
+            let color_blend = BlendComponent {
+                src_factor: BlendFactor::One,
+                dst_factor: BlendFactor::OneMinusSrcAlpha,
+                operation: BlendOperation::Add,
+            };
+            let color_target = Some(ColorTargetState {
+                format: CLOUD_OFFSCREEN_FORMAT,
+                blend: Some(BlendState {
+                    color: color_blend,
+                    alpha: color_blend,
+                }),
+                write_mask: ColorWrites::ALL,
+            });
+            let mut targets = vec![
+                color_target.clone(),
+                Some(ColorTargetState {
+                    format: CLOUD_OFFSCREEN_FORMAT,
+                    blend: Some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::Zero,
+                            dst_factor: BlendFactor::Src,
+                            operation: BlendOperation::Add,
+                        },
+                        alpha: BlendComponent {
+                            src_factor: BlendFactor::Zero,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                    }),
+                    write_mask: ColorWrites::ALL,
+                }),
+            ];
+            if key
+                .flags
+                .contains(VolumetricCloudPipelineKeyFlags::TEMPORAL)
+            {
+                shader_defs.push("TEMPORAL".into());
+                targets.push(color_target);
+            }
+            targets
         }
+        // With dual-source blending, the shader outputs a separate
+        // transmittance for each color channel to multiply the background by.
+        // Otherwise, it outputs the average transmittance as alpha.
+        else if key
+            .flags
+            .contains(VolumetricCloudPipelineKeyFlags::DUAL_SOURCE_BLENDING)
+        {
+            shader_defs.push("DUAL_SOURCE_BLENDING".into());
+            vec![Some(Self::view_color_target_state(
+                format,
+                BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::Src1,
+                    operation: BlendOperation::Add,
+                },
+            ))]
+        } else {
+            vec![Some(Self::view_color_target_state(
+                format,
+                BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::OneMinusSrcAlpha,
+                    operation: BlendOperation::Add,
+                },
+            ))]
+        };
 
         RenderPipelineDescriptor {
             label: Some("volumetric lighting pipeline".into()),
@@ -542,34 +1008,63 @@ impl SpecializedRenderPipeline for VolumetricCloudPipeline {
                 shader: self.shader.clone(),
                 shader_defs,
                 entry_point: "fragment".into(),
-                targets: vec![Some(ColorTargetState {
-                    format: if key.flags.contains(VolumetricCloudPipelineKeyFlags::HDR) {
-                        ViewTarget::TEXTURE_FORMAT_HDR
-                    } else {
-                        TextureFormat::bevy_default()
-                    },
-                    // Blend on top of what's already in the framebuffer. Doing
-                    // the alpha blending with the hardware blender allows us to
-                    // avoid having to use intermediate render targets.
-                    blend: Some(BlendState {
-                        color: BlendComponent {
-                            src_factor: BlendFactor::One,
-                            dst_factor: BlendFactor::OneMinusSrcAlpha,
-                            operation: BlendOperation::Add,
-                        },
-                        alpha: BlendComponent {
-                            src_factor: BlendFactor::Zero,
-                            dst_factor: BlendFactor::One,
-                            operation: BlendOperation::Add,
-                        },
-                    }),
-                    write_mask: ColorWrites::ALL,
-                })],
+                targets,
             }),
         }
     }
 }
 
+impl VolumetricCloudPipeline {
+    /// Returns the layout of the cloud volume bind group for a pipeline with
+    /// the given flags.
+    pub(crate) fn volumetric_view_bind_group_layout(
+        &self,
+        flags: VolumetricCloudPipelineKeyFlags,
+        multisampled: bool,
+    ) -> &BindGroupLayout {
+        // We need a separate layout for MSAA and non-MSAA, as well as one for
+        // the presence or absence of the density texture.
+        let mut bind_group_layout_key = VolumetricCloudBindGroupLayoutKey::empty();
+        bind_group_layout_key.set(
+            VolumetricCloudBindGroupLayoutKey::MULTISAMPLED,
+            multisampled,
+        );
+        bind_group_layout_key.set(
+            VolumetricCloudBindGroupLayoutKey::DENSITY_TEXTURE,
+            flags.contains(VolumetricCloudPipelineKeyFlags::DENSITY_TEXTURE),
+        );
+        bind_group_layout_key.set(
+            VolumetricCloudBindGroupLayoutKey::EMISSION_TEXTURE,
+            flags.contains(VolumetricCloudPipelineKeyFlags::EMISSION_TEXTURE),
+        );
+
+        &self.volumetric_view_bind_group_layouts[bind_group_layout_key.bits() as usize]
+    }
+
+    /// Returns the state of the view's color target when the clouds are drawn
+    /// straight onto it at full resolution.
+    fn view_color_target_state(
+        format: TextureFormat,
+        color_blend: BlendComponent,
+    ) -> ColorTargetState {
+        ColorTargetState {
+            format,
+            // Blend on top of what's already in the framebuffer. Doing the
+            // alpha blending with the hardware blender allows us to avoid
+            // having to use intermediate render targets.
+            blend: Some(BlendState {
+                color: color_blend,
+                alpha: BlendComponent {
+                    src_factor: BlendFactor::Zero,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+            }),
+            write_mask: ColorWrites::ALL,
+        }
+    }
+}
+
 /// Specializes volumetric fog pipelines for all views with that effect enabled.
 #[allow(clippy::too_many_arguments)]
 pub fn prepare_volumetric_cloud_pipelines(
@@ -577,24 +1072,38 @@ pub fn prepare_volumetric_cloud_pipelines(
     pipeline_cache: Res<PipelineCache>,
     mut pipelines: ResMut<SpecializedRenderPipelines<VolumetricCloudPipeline>>,
     volumetric_lighting_pipeline: Res<VolumetricCloudPipeline>,
-    view_targets: Query<
-        (
-            Entity,
-            &ExtractedView,
-            Has<NormalPrepass>,
-            Has<DepthPrepass>,
-            Has<MotionVectorPrepass>,
-            Has<DeferredPrepass>,
-        ),
-        With<VolumetricCloudSettings>,
-    >,
+    mut compute_pipelines: ResMut<SpecializedComputePipelines<CloudComputePipeline>>,
+    compute_pipeline: Res<CloudComputePipeline>,
+    view_targets: Query<(
+        Entity,
+        &ExtractedView,
+        Has<NormalPrepass>,
+        Has<DepthPrepass>,
+        Has<MotionVectorPrepass>,
+        Has<DeferredPrepass>,
+        Has<RenderViewLightProbes<IrradianceVolume>>,
+        Option<&ShadowFilteringMethod>,
+        &VolumetricCloudSettings,
+    )>,
+    cloud_volumes: Query<&CloudVolume>,
     msaa: Res<Msaa>,
     meshes: Res<RenderAssets<GpuMesh>>,
+    images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
 ) {
     let plane_mesh = meshes.get(&PLANE_MESH).expect("Plane mesh not found!");
 
-    for (entity, view, normal_prepass, depth_prepass, motion_vector_prepass, deferred_prepass) in
-        view_targets.iter()
+    for (
+        entity,
+        view,
+        normal_prepass,
+        depth_prepass,
+        motion_vector_prepass,
+        deferred_prepass,
+        has_irradiance_volumes,
+        shadow_filter_method,
+        volumetric_cloud_settings,
+    ) in view_targets.iter()
     {
         // Create a mesh pipeline view layout key corresponding to the view.
         let mut mesh_pipeline_view_key = MeshPipelineViewLayoutKey::from(*msaa);
@@ -609,46 +1118,137 @@ pub fn prepare_volumetric_cloud_pipelines(
             deferred_prepass,
         );
 
-        let mut textureless_flags = VolumetricCloudPipelineKeyFlags::empty();
-        textureless_flags.set(VolumetricCloudPipelineKeyFlags::HDR, view.hdr);
+        // Fall back to the raster backend if the device can't run the compute
+        // one.
+        let compute = compute_pipeline.is_used_by(volumetric_cloud_settings);
+        if volumetric_cloud_settings.backend == VolumetricCloudBackend::Compute && !compute {
+            warn_once!(
+                "The compute backend of volumetric clouds isn't supported on this device; \
+                falling back to the raster backend"
+            );
+        }
 
-        // Specialize the pipeline.
-        let textureless_pipeline_key = VolumetricCloudPipelineKey {
-            mesh_pipeline_view_key,
-            vertex_buffer_layout: plane_mesh.layout.clone(),
-            flags: textureless_flags,
-        };
-        let textureless_pipeline_id = pipelines.specialize(
-            &pipeline_cache,
-            &volumetric_lighting_pipeline,
-            textureless_pipeline_key.clone(),
+        let mut view_flags = VolumetricCloudPipelineKeyFlags::empty();
+        view_flags.set(VolumetricCloudPipelineKeyFlags::HDR, view.hdr);
+        // Offscreen, the transmittance is written to its own target, and
+        // dual-source blending is left to the upsampling pass.
+        if volumetric_cloud_settings.renders_offscreen() {
+            view_flags.insert(VolumetricCloudPipelineKeyFlags::OFFSCREEN);
+            view_flags.set(
+                VolumetricCloudPipelineKeyFlags::TEMPORAL,
+                volumetric_cloud_settings.temporal.is_some(),
+            );
+        } else {
+            view_flags.set(
+                VolumetricCloudPipelineKeyFlags::DUAL_SOURCE_BLENDING,
+                render_device
+                    .features()
+                    .contains(WgpuFeatures::DUAL_SOURCE_BLENDING),
+            );
+        }
+        view_flags.set(
+            VolumetricCloudPipelineKeyFlags::ANALYTIC_INTEGRATION,
+            volumetric_cloud_settings.integrator == VolumetricCloudIntegrator::Analytic,
         );
-        let textured_pipeline_id = pipelines.specialize(
-            &pipeline_cache,
-            &volumetric_lighting_pipeline,
-            VolumetricCloudPipelineKey {
-                flags: textureless_pipeline_key.flags
-                    | VolumetricCloudPipelineKeyFlags::DENSITY_TEXTURE,
-                ..textureless_pipeline_key
-            },
+        view_flags.set(
+            VolumetricCloudPipelineKeyFlags::ADAPTIVE_STEPS,
+            volumetric_cloud_settings.adaptive_steps.is_some(),
+        );
+        // The view bind group of the compute backend doesn't have the
+        // irradiance volumes.
+        view_flags.set(
+            VolumetricCloudPipelineKeyFlags::IRRADIANCE_VOLUME,
+            has_irradiance_volumes && !compute,
         );
+        match shadow_filter_method.copied().unwrap_or_default() {
+            ShadowFilteringMethod::Hardware2x2 => {}
+            ShadowFilteringMethod::Gaussian => {
+                view_flags.insert(VolumetricCloudPipelineKeyFlags::SHADOW_FILTER_METHOD_GAUSSIAN);
+            }
+            ShadowFilteringMethod::Temporal => {
+                view_flags.insert(VolumetricCloudPipelineKeyFlags::SHADOW_FILTER_METHOD_TEMPORAL);
+            }
+        }
 
-        commands.entity(entity).insert(ViewVolumetricFogPipelines {
-            textureless: textureless_pipeline_id,
-            textured: textured_pipeline_id,
-        });
+        // Specialize a pipeline for every combination of features that the
+        // cloud volumes need. This includes the volumes outside the view, so
+        // that their pipelines are ready by the time they come into it.
+        let mut view_pipelines = ViewVolumetricFogPipelines::default();
+        let mut view_compute_pipelines = ViewCloudComputePipelines::default();
+        for cloud_volume in cloud_volumes.iter() {
+            let Some(volume_flags) = VolumetricCloudPipelineKeyFlags::for_cloud_volume(
+                cloud_volume.density_texture.as_ref().map(Handle::id),
+                cloud_volume.emission_texture.as_ref().map(Handle::id),
+                cloud_volume.emits_blackbody(),
+                &images,
+            ) else {
+                continue;
+            };
+
+            if compute {
+                view_compute_pipelines
+                    .0
+                    .entry(volume_flags)
+                    .or_insert_with(|| {
+                        compute_pipelines.specialize(
+                            &pipeline_cache,
+                            &compute_pipeline,
+                            CloudComputePipelineKey {
+                                flags: view_flags | volume_flags,
+                                multisampled: mesh_pipeline_view_key
+                                    .contains(MeshPipelineViewLayoutKey::MULTISAMPLED),
+                            },
+                        )
+                    });
+                continue;
+            }
+
+            view_pipelines.0.entry(volume_flags).or_insert_with(|| {
+                pipelines.specialize(
+                    &pipeline_cache,
+                    &volumetric_lighting_pipeline,
+                    VolumetricCloudPipelineKey {
+                        mesh_pipeline_view_key,
+                        vertex_buffer_layout: plane_mesh.layout.clone(),
+                        flags: view_flags | volume_flags,
+                    },
+                )
+            });
+        }
+
+        commands.entity(entity).insert(view_pipelines);
+        if compute {
+            commands.entity(entity).insert(view_compute_pipelines);
+        }
     }
 }
 
 /// A system that converts [`VolumetricFogSettings`] into [`VolumetricFogUniform`]s.
+#[allow(clippy::too_many_arguments)]
 pub fn prepare_volumetric_cloud_uniforms(
     mut commands: Commands,
     mut volumetric_lighting_uniform_buffer: ResMut<VolumetricCloudUniformBuffer>,
-    view_targets: Query<(Entity, &ExtractedView, &VolumetricCloudSettings)>,
+    mut compute_volume_buffer: ResMut<CloudComputeVolumeBuffer>,
+    compute_pipeline: Res<CloudComputePipeline>,
+    view_targets: Query<(
+        Entity,
+        &ExtractedView,
+        &VisibleEntities,
+        &VolumetricCloudSettings,
+    )>,
     cloud_volumes: Query<(Entity, &CloudVolume, &GlobalTransform)>,
+    cloud_lightning: Query<&ExtractedCloudLightning>,
+    directional_lights: Query<(
+        Entity,
+        &ExtractedDirectionalLight,
+        Option<&VolumetricCloudLight>,
+        Option<&CloudSun>,
+    )>,
+    atmosphere: Option<Res<CloudAtmosphere>>,
+    temporal_history: Res<CloudTemporalHistory>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
-    mut local_from_world_matrices: Local<Vec<Mat4>>,
+    mut local_from_world_matrices: Local<EntityHashMap<Mat4>>,
 ) {
     let Some(mut writer) = volumetric_lighting_uniform_buffer.get_writer(
         view_targets.iter().len(),
@@ -658,20 +1258,86 @@ pub fn prepare_volumetric_cloud_uniforms(
         return;
     };
 
+    let atmosphere = atmosphere.map_or(CloudAtmosphere::EARTH, |atmosphere| *atmosphere);
+
+    compute_volume_buffer.get_mut().clear();
+
+    // Bevy sorts the directional lights before uploading them, so sort ours
+    // the same way to line them up: volumetric lights first, then shadow
+    // casters, then by entity.
+    let mut sorted_lights: Vec<_> = directional_lights.iter().collect();
+    sorted_lights.sort_by(|(entity_1, light_1, ..), (entity_2, light_2, ..)| {
+        (light_2.volumetric.cmp(&light_1.volumetric))
+            .then_with(|| light_2.shadows_enabled.cmp(&light_1.shadows_enabled))
+            .then_with(|| entity_1.cmp(entity_2))
+    });
+    let mut cloud_lights = [VolumetricCloudLightUniform::default(); MAX_DIRECTIONAL_LIGHTS];
+    let mut cloud_light_links = [0; MAX_DIRECTIONAL_LIGHTS];
+    for ((light_uniform, light_links), (_, _, volumetric_light, sun)) in cloud_lights
+        .iter_mut()
+        .zip(cloud_light_links.iter_mut())
+        .zip(sorted_lights)
+    {
+        let Some(volumetric_light) = volumetric_light else {
+            continue;
+        };
+        *light_links = volumetric_light.light_links;
+
+        let mut flags = VolumetricCloudLightFlags::ENABLED;
+        flags.set(
+            VolumetricCloudLightFlags::SHADOW_MAP,
+            volumetric_light.shadow_map,
+        );
+        flags.set(
+            VolumetricCloudLightFlags::SCATTERING_ASYMMETRY,
+            volumetric_light.scattering_asymmetry.is_some(),
+        );
+        flags.set(VolumetricCloudLightFlags::SUN, sun.is_some());
+
+        *light_uniform = VolumetricCloudLightUniform {
+            tint: volumetric_light.tint.to_linear().to_vec3() * volumetric_light.intensity,
+            step_count: volumetric_light.step_count,
+            scattering_asymmetry: volumetric_light.scattering_asymmetry.unwrap_or_default(),
+            flags: flags.bits(),
+            top_of_atmosphere: sun.map_or(Vec3::ZERO, |sun| sun.top_of_atmosphere_illuminance),
+        };
+    }
+
     // Do this up front to avoid O(n^2) matrix inversion.
     local_from_world_matrices.clear();
-    for (_, _, fog_transform) in cloud_volumes.iter() {
-        local_from_world_matrices.push(fog_transform.compute_matrix().inverse());
+    for (volume_entity, _, fog_transform) in cloud_volumes.iter() {
+        local_from_world_matrices.insert(volume_entity, fog_transform.compute_matrix().inverse());
     }
 
-    for (view_entity, extracted_view, volumetric_fog_settings) in view_targets.iter() {
+    for (view_entity, extracted_view, visible_entities, volumetric_fog_settings) in
+        view_targets.iter()
+    {
         let world_from_view = extracted_view.world_from_view.compute_matrix();
+        let clip_from_world = extracted_view.clip_from_view * world_from_view.inverse();
+        let previous_clip_from_world = temporal_history
+            .previous_clip_from_world(view_entity)
+            .unwrap_or(clip_from_world);
+
+        // Use the rounded size of the target, so that pixel centers line up
+        // with the texels of the offscreen targets exactly.
+        let view_size = extracted_view.viewport.zw();
+        let target_size = volumetric_fog_settings
+            .resolution_scale
+            .target_size(view_size);
+        let resolution_scale = target_size.as_vec2() / view_size.max(UVec2::ONE).as_vec2();
+
+        let adaptive_steps = volumetric_fog_settings.adaptive_steps.unwrap_or_default();
 
         let mut view_fog_volumes = vec![];
 
-        for ((_, fog_volume, _), local_from_world) in
-            cloud_volumes.iter().zip(local_from_world_matrices.iter())
-        {
+        // Only the volumes visible to this view are drawn in it.
+        for &visible_entity in visible_entities.iter::<WithCloudVolume>() {
+            let Ok((volume_entity, fog_volume, fog_transform)) = cloud_volumes.get(visible_entity)
+            else {
+                continue;
+            };
+            let local_from_world = &local_from_world_matrices[&volume_entity];
+
             // Calculate the transforms to and from 1×1×1 local space.
             let local_from_view = *local_from_world * world_from_view;
             let view_from_local = local_from_view.inverse();
@@ -685,38 +1351,259 @@ pub fn prepare_volumetric_cloud_uniforms(
                 &view_from_local,
             );
 
+            // Find the texels that the volume covers, for binning into the
+            // tiles of the compute backend.
+            let (screen_rect, nearest_view_depth) = calculate_fog_volume_screen_bounds(
+                &extracted_view.clip_from_view,
+                &view_from_local,
+                target_size,
+            );
+
             // Calculate the radius of the sphere that bounds the fog volume.
             let bounding_radius = (Mat3A::from_mat4(view_from_local) * Vec3A::splat(0.5)).length();
 
+            // Emission textures that store temperatures take their luminance
+            // from the blackbody ramp, so only the color applies as a tint.
+            let (emissive, temperature_range) = match fog_volume.emission_texture_mode {
+                CloudEmissionTextureMode::Temperature {
+                    min_temperature,
+                    max_temperature,
+                } if fog_volume.emission_texture.is_some() => (
+                    fog_volume.emissive_color.to_linear().to_vec3(),
+                    vec2(min_temperature, max_temperature),
+                ),
+                _ => (
+                    fog_volume.emissive_color.to_linear().to_vec3() * fog_volume.emissive_intensity,
+                    Vec2::ZERO,
+                ),
+            };
+
+            // Gather the segments of every lightning flash that starts inside
+            // this volume.
+            let mut lightning =
+                [CloudLightningSegmentUniform::default(); MAX_CLOUD_LIGHTNING_SEGMENTS];
+            let mut lightning_count = 0;
+            for flash in cloud_lightning.iter().filter(|flash| {
+                local_from_world
+                    .transform_point3(flash.origin)
+                    .abs()
+                    .cmple(Vec3::splat(0.5))
+                    .all()
+            }) {
+                for &(start, end) in &flash.segments {
+                    if lightning_count == MAX_CLOUD_LIGHTNING_SEGMENTS {
+                        break;
+                    }
+                    lightning[lightning_count] = CloudLightningSegmentUniform {
+                        start,
+                        end,
+                        intensity: flash.intensity,
+                    };
+                    lightning_count += 1;
+                }
+            }
+
+            // Turn off the lights that aren't linked to this volume.
+            let mut volume_cloud_lights = cloud_lights;
+            for (light_uniform, light_links) in
+                volume_cloud_lights.iter_mut().zip(cloud_light_links)
+            {
+                if light_links & fog_volume.light_links == 0 {
+                    light_uniform.flags &= !VolumetricCloudLightFlags::ENABLED.bits();
+                }
+            }
+
             // Write out our uniform.
-            let uniform_buffer_offset = writer.write(&VolumetricCloudUniform {
+            let uniform = VolumetricCloudUniform {
                 clip_from_local: hull_clip_from_local,
                 uvw_from_world: UVW_FROM_LOCAL * *local_from_world,
                 far_planes: get_far_planes(&view_from_local),
                 fog_color: fog_volume.fog_color.to_linear().to_vec3(),
                 light_tint: fog_volume.light_tint.to_linear().to_vec3(),
+                emissive,
+                temperature_range,
                 ambient_color: volumetric_fog_settings.ambient_color.to_linear().to_vec3(),
                 ambient_intensity: volumetric_fog_settings.ambient_intensity,
-                step_count: volumetric_fog_settings.step_count,
+                step_count: volumetric_fog_settings
+                    .adaptive_steps
+                    .map_or(volumetric_fog_settings.step_count, |adaptive_steps| {
+                        adaptive_steps.step_budget
+                    }),
+                lightning_count: lightning_count as u32,
+                lightning,
+                cloud_lights: volume_cloud_lights,
                 bounding_radius,
-                absorption: fog_volume.absorption,
-                scattering: fog_volume.scattering,
+                absorption: fog_volume.absorption_rgb(),
+                scattering: fog_volume.scattering_rgb(),
                 density: fog_volume.density_factor,
                 scattering_asymmetry: fog_volume.scattering_asymmetry,
                 light_intensity: fog_volume.light_intensity,
                 jitter_strength: volumetric_fog_settings.jitter,
-            });
+                atmosphere_bottom_radius: atmosphere.bottom_radius,
+                atmosphere_top_radius: atmosphere.top_radius,
+                scene_altitude: atmosphere.scene_altitude,
+                ground_albedo: volumetric_fog_settings.ground_albedo.to_linear().to_vec3(),
+                resolution_scale,
+                // Follow the volume, if it moved since the previous frame.
+                previous_clip_from_world: previous_clip_from_world
+                    * temporal_history
+                        .previous_world_from_local(volume_entity)
+                        .unwrap_or_else(|| fog_transform.compute_matrix())
+                    * *local_from_world,
+                update_pattern_size: volumetric_fog_settings.update_pattern_size(),
+                nearest_view_depth,
+                min_step_size: adaptive_steps.min_step_size,
+                max_step_size: adaptive_steps.max_step_size,
+                step_distance_factor: adaptive_steps.distance_factor,
+            };
+            let uniform_buffer_offset = writer.write(&uniform);
+
+            // The compute backend reads the uniforms of all the volumes in a
+            // tile at once, so it gets a copy of them in a storage buffer too.
+            let compute_volume_index =
+                compute_pipeline
+                    .is_used_by(volumetric_fog_settings)
+                    .then(|| {
+                        let volumes = compute_volume_buffer.get_mut();
+                        volumes.push(uniform);
+                        volumes.len() as u32 - 1
+                    });
+
+            // Sort by the depth of the center of the volume.
+            let sort_depth = -view_from_local.w_axis.z;
+            view_fog_volumes.push((
+                sort_depth,
+                ViewCloudVolume {
+                    uniform_buffer_offset,
+                    exterior: !interior,
+                    density_texture: fog_volume.density_texture.as_ref().map(Handle::id),
+                    emission_texture: fog_volume.emission_texture.as_ref().map(Handle::id),
+                    blackbody: fog_volume.emits_blackbody(),
+                    screen_rect,
+                    nearest_view_depth,
+                    compute_volume_index,
+                    bind_group: None,
+                },
+            ));
+        }
+
+        // Each volume is blended over the ones drawn before it, so draw them
+        // back to front.
+        view_fog_volumes.sort_by(|(depth_a, _), (depth_b, _)| depth_b.total_cmp(depth_a));
 
-            view_fog_volumes.push(ViewCloudVolume {
-                uniform_buffer_offset,
-                exterior: !interior,
-                density_texture: fog_volume.density_texture.as_ref().map(Handle::id),
+        commands.entity(view_entity).insert(ViewVolumetricCloud(
+            view_fog_volumes
+                .into_iter()
+                .map(|(_, view_fog_volume)| view_fog_volume)
+                .collect(),
+        ));
+    }
+
+    compute_volume_buffer.write_buffer(&render_device, &render_queue);
+}
+
+/// Fills in the bind group of each cloud volume in each view, reusing the ones
+/// from earlier frames where nothing that they bind has changed.
+pub fn prepare_volumetric_cloud_bind_groups(
+    mut view_targets: Query<(&ViewDepthTexture, &mut ViewVolumetricCloud)>,
+    mut bind_group_cache: ResMut<VolumetricCloudBindGroupCache>,
+    volumetric_lighting_pipeline: Res<VolumetricCloudPipeline>,
+    volumetric_lighting_uniform_buffer: Res<VolumetricCloudUniformBuffer>,
+    image_assets: Res<RenderAssets<GpuImage>>,
+    msaa: Res<Msaa>,
+    render_device: Res<RenderDevice>,
+) {
+    // Move the bind groups out of the cache, and put back only the ones that
+    // are used this frame.
+    let mut previous_bind_groups = mem::take(&mut bind_group_cache.bind_groups);
+
+    // The transmittance lookup table is built on the first frame, so it may
+    // not have been uploaded yet.
+    let (Some(uniform_buffer), Some(uniform_buffer_binding), Some(atmosphere_transmittance_lut)) = (
+        volumetric_lighting_uniform_buffer.buffer(),
+        volumetric_lighting_uniform_buffer.binding(),
+        image_assets.get(&ATMOSPHERE_TRANSMITTANCE_LUT),
+    ) else {
+        return;
+    };
+    let blackbody_lut = image_assets.get(&BLACKBODY_LUT);
+
+    for (view_depth_texture, mut view_fog_volumes) in view_targets.iter_mut() {
+        for view_fog_volume in view_fog_volumes.iter_mut() {
+            let density_image = view_fog_volume
+                .density_texture
+                .and_then(|density_texture| image_assets.get(density_texture));
+            let emission_images = view_fog_volume
+                .emission_texture
+                .and_then(|emission_texture| image_assets.get(emission_texture))
+                .zip(blackbody_lut);
+
+            let mut layout_key = VolumetricCloudBindGroupLayoutKey::empty();
+            layout_key.set(
+                VolumetricCloudBindGroupLayoutKey::MULTISAMPLED,
+                !matches!(*msaa, Msaa::Off),
+            );
+            layout_key.set(
+                VolumetricCloudBindGroupLayoutKey::DENSITY_TEXTURE,
+                density_image.is_some(),
+            );
+            layout_key.set(
+                VolumetricCloudBindGroupLayoutKey::EMISSION_TEXTURE,
+                emission_images.is_some(),
+            );
+
+            let key = VolumetricCloudBindGroupCacheKey {
+                layout_key,
+                uniform_buffer: uniform_buffer.id(),
+                depth_view: view_depth_texture.view().id(),
+                density_view: density_image.map(|density_image| density_image.texture_view.id()),
+                emission_view: emission_images
+                    .map(|(emission_image, _)| emission_image.texture_view.id()),
+                blackbody_lut_view: emission_images
+                    .map(|(_, blackbody_lut)| blackbody_lut.texture_view.id()),
+                atmosphere_transmittance_lut_view: atmosphere_transmittance_lut.texture_view.id(),
+            };
+
+            let bind_group = bind_group_cache.bind_groups.entry(key).or_insert_with(|| {
+                previous_bind_groups.remove(&key).unwrap_or_else(|| {
+                    // Create the bind group entries. The ones relating to the
+                    // density and emission textures will only be filled in if
+                    // those textures are present.
+                    let mut bind_group_entries = DynamicBindGroupEntries::sequential((
+                        uniform_buffer_binding.clone(),
+                        BindingResource::TextureView(view_depth_texture.view()),
+                    ));
+                    if let Some(density_image) = density_image {
+                        bind_group_entries = bind_group_entries.extend_sequential((
+                            BindingResource::TextureView(&density_image.texture_view),
+                            BindingResource::Sampler(&density_image.sampler),
+                        ));
+                    }
+                    if let Some((emission_image, blackbody_lut)) = emission_images {
+                        bind_group_entries = bind_group_entries.extend_with_indices((
+                            (
+                                4,
+                                BindingResource::TextureView(&emission_image.texture_view),
+                            ),
+                            (5, BindingResource::Sampler(&emission_image.sampler)),
+                            (6, BindingResource::TextureView(&blackbody_lut.texture_view)),
+                        ));
+                    }
+                    bind_group_entries = bind_group_entries.extend_with_indices(((
+                        7,
+                        BindingResource::TextureView(&atmosphere_transmittance_lut.texture_view),
+                    ),));
+
+                    render_device.create_bind_group(
+                        None,
+                        &volumetric_lighting_pipeline.volumetric_view_bind_group_layouts
+                            [layout_key.bits() as usize],
+                        &bind_group_entries,
+                    )
+                })
             });
+            view_fog_volume.bind_group = Some(bind_group.clone());
         }
-
-        commands
-            .entity(view_entity)
-            .insert(ViewVolumetricCloud(view_fog_volumes));
     }
 }
 
@@ -767,6 +1654,96 @@ fn get_far_planes(view_from_local: &Mat4) -> [Vec4; 3] {
     far_planes
 }
 
+impl VolumetricCloudPipelineKeyFlags {
+    /// Returns the shader definitions for the raymarch with these flags,
+    /// which both backends share.
+    pub(crate) fn shader_defs(self, multisampled: bool) -> Vec<ShaderDefVal> {
+        let mut shader_defs = vec![
+            // Filter the shadow maps the same way as the rest of the scene, so
+            // that the edges of light shafts match the edges of shadows.
+            if self.contains(Self::SHADOW_FILTER_METHOD_GAUSSIAN) {
+                "SHADOW_FILTER_METHOD_GAUSSIAN".into()
+            } else if self.contains(Self::SHADOW_FILTER_METHOD_TEMPORAL) {
+                "SHADOW_FILTER_METHOD_TEMPORAL".into()
+            } else {
+                "SHADOW_FILTER_METHOD_HARDWARE_2X2".into()
+            },
+            ShaderDefVal::UInt(
+                "MAX_DIRECTIONAL_LIGHTS".into(),
+                MAX_DIRECTIONAL_LIGHTS as u32,
+            ),
+        ];
+
+        if multisampled {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+
+        if self.contains(Self::DENSITY_TEXTURE) {
+            shader_defs.push("DENSITY_TEXTURE".into());
+        }
+
+        if self.contains(Self::EMISSION_TEXTURE) {
+            shader_defs.push("EMISSION_TEXTURE".into());
+        }
+
+        if self.contains(Self::BLACKBODY) {
+            shader_defs.push("BLACKBODY".into());
+        }
+
+        if self.contains(Self::ANALYTIC_INTEGRATION) {
+            shader_defs.push("ANALYTIC_INTEGRATION".into());
+        }
+
+        if self.contains(Self::ADAPTIVE_STEPS) {
+            shader_defs.push("ADAPTIVE_STEPS".into());
+        }
+
+        shader_defs
+    }
+
+    /// Returns the flags for the features that a cloud volume with the given
+    /// textures needs, or `None` if any of those textures hasn't been loaded
+    /// yet.
+    fn for_cloud_volume(
+        density_texture: Option<AssetId<Image>>,
+        emission_texture: Option<AssetId<Image>>,
+        blackbody: bool,
+        images: &RenderAssets<GpuImage>,
+    ) -> Option<Self> {
+        let mut flags = Self::empty();
+
+        if let Some(density_texture) = density_texture {
+            images.get(density_texture)?;
+            flags.insert(Self::DENSITY_TEXTURE);
+        }
+
+        if let Some(emission_texture) = emission_texture {
+            images.get(emission_texture)?;
+            images.get(&BLACKBODY_LUT)?;
+            flags.insert(Self::EMISSION_TEXTURE);
+            flags.set(Self::BLACKBODY, blackbody);
+        }
+
+        Some(flags)
+    }
+}
+
+impl ViewCloudVolume {
+    /// Returns the flags for the features that this volume needs, or `None` if
+    /// any of its textures hasn't been loaded yet.
+    pub(crate) fn pipeline_flags(
+        &self,
+        images: &RenderAssets<GpuImage>,
+    ) -> Option<VolumetricCloudPipelineKeyFlags> {
+        VolumetricCloudPipelineKeyFlags::for_cloud_volume(
+            self.density_texture,
+            self.emission_texture,
+            self.blackbody,
+            images,
+        )
+    }
+}
+
 impl VolumetricCloudBindGroupLayoutKey {
     /// Creates an appropriate debug description for the bind group layout with
     /// these flags.
@@ -781,6 +1758,8 @@ impl VolumetricCloudBindGroupLayoutKey {
                 .filter_map(|flag| {
                     if flag == VolumetricCloudBindGroupLayoutKey::DENSITY_TEXTURE {
                         Some("density texture")
+                    } else if flag == VolumetricCloudBindGroupLayoutKey::EMISSION_TEXTURE {
+                        Some("emission texture")
                     } else if flag == VolumetricCloudBindGroupLayoutKey::MULTISAMPLED {
                         Some("multisampled")
                     } else {
@@ -825,3 +1804,55 @@ fn calculate_fog_volume_clip_from_local_transforms(
         vec4(0.0, 0.0, z_near, z_near),
     )
 }
+
+/// Returns the texels of a target of the given size that the 1×1×1 cloud
+/// volume cube covers on screen, along with the view-space depth of its nearest
+/// point, which is 0 if the camera is inside it.
+///
+/// If any corner of the cube is behind the camera, the cube may cover any part
+/// of the screen, so the whole target is returned.
+fn calculate_fog_volume_screen_bounds(
+    clip_from_view: &Mat4,
+    view_from_local: &Mat4,
+    target_size: UVec2,
+) -> (URect, f32) {
+    let (mut ndc_min, mut ndc_max) = (Vec2::MAX, Vec2::MIN);
+    let mut nearest_view_depth = f32::MAX;
+    let mut behind_camera = false;
+    for corner in 0..8 {
+        let local_position = vec3(
+            (corner & 1) as f32 - 0.5,
+            ((corner >> 1) & 1) as f32 - 0.5,
+            (corner >> 2) as f32 - 0.5,
+        );
+        let view_position = view_from_local.transform_point3(local_position);
+        nearest_view_depth = nearest_view_depth.min(-view_position.z);
+
+        let clip_position = *clip_from_view * view_position.extend(1.0);
+        if clip_position.w <= 0.0 {
+            behind_camera = true;
+            continue;
+        }
+        let ndc_position = clip_position.xy() / clip_position.w;
+        ndc_min = ndc_min.min(ndc_position);
+        ndc_max = ndc_max.max(ndc_position);
+    }
+    let nearest_view_depth = nearest_view_depth.max(0.0);
+
+    if behind_camera {
+        return (
+            URect::from_corners(UVec2::ZERO, target_size),
+            nearest_view_depth,
+        );
+    }
+
+    // NDC Y points up, while texel rows go down.
+    let size = target_size.as_vec2();
+    let texel_min = (vec2(ndc_min.x, -ndc_max.y) * 0.5 + 0.5) * size;
+    let texel_max = (vec2(ndc_max.x, -ndc_min.y) * 0.5 + 0.5) * size;
+    let screen_rect = URect::from_corners(
+        texel_min.floor().clamp(Vec2::ZERO, size).as_uvec2(),
+        texel_max.ceil().clamp(Vec2::ZERO, size).as_uvec2(),
+    );
+    (screen_rect, nearest_view_depth)
+}
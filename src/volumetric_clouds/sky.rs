@@ -0,0 +1,371 @@
+//! A time-of-day driver for the sun and the moon.
+//!
+//! Insert a [`CloudSkyClock`] resource, and the plugin spawns a sun and a moon
+//! as [`VolumetricCloudLight`]s, moves them across the sky as the clock runs,
+//! and sets their illuminance and color from the transmittance of the
+//! [`CloudAtmosphere`]. Clouds then go through sunrise, noon, sunset, and night
+//! without any manual keyframing.
+//!
+//! The positions of the sun and the moon come from the low-precision formulas
+//! in the [Astronomical Almanac], which are accurate to a fraction of a degree
+//! for a few centuries around the year 2000.
+//!
+//! [Astronomical Almanac]: https://aa.usno.navy.mil/faq/sun_approx
+
+use std::f64::consts::PI;
+
+use bevy::{math::vec3, prelude::*};
+
+use crate::volumetric_clouds::{
+    atmosphere::CloudAtmosphere, blackbody::blackbody_luminance, VolumetricCloudLight,
+};
+
+/// The effective temperature of the sun's photosphere, in kelvin.
+const SOLAR_TEMPERATURE: f32 = 5778.0;
+
+/// The weights that give the luminance of a linear Rec. 709 color.
+const LUMINANCE_WEIGHTS: Vec3 = vec3(0.2126, 0.7152, 0.0722);
+
+/// A clock that moves the sun and the moon across the sky.
+///
+/// The world is oriented with +Y up, -Z north, and +X east.
+#[derive(Clone, Debug, Resource, Reflect)]
+#[reflect(Resource)]
+pub struct CloudSkyClock {
+    /// The year.
+    ///
+    /// The default value is 2024.
+    pub year: i32,
+
+    /// The day of the year, starting from 1 on January 1st.
+    ///
+    /// The default value is 172, the June solstice.
+    pub day_of_year: u32,
+
+    /// The local solar time, in hours from midnight.
+    ///
+    /// The sun is highest at 12.0.
+    ///
+    /// The default value is 12.0.
+    pub time_of_day: f32,
+
+    /// The latitude of the scene, in degrees north of the equator.
+    ///
+    /// The default value is 45.0.
+    pub latitude: f32,
+
+    /// How many seconds pass on the clock for every second of real time.
+    ///
+    /// Set this to 0 to stop the clock.
+    ///
+    /// The default value is 1.0.
+    pub time_scale: f32,
+
+    /// The illuminance of the sun above the atmosphere, in lux.
+    ///
+    /// The default value is 128,000.
+    pub sun_illuminance: f32,
+
+    /// The illuminance of the full moon above the atmosphere, in lux.
+    ///
+    /// Other phases of the moon are dimmer.
+    ///
+    /// The default value is 0.3.
+    pub moon_illuminance: f32,
+}
+
+/// Marks the [`DirectionalLight`] that a [`CloudSkyClock`] moves as the sun.
+///
+/// One is spawned automatically if there's none. The clouds light each sample
+/// with the sun as it arrives at the altitude of that sample, rather than as
+/// it arrives at the ground, so that high clouds stay lit after sunset.
+#[derive(Clone, Copy, Component, Debug, Reflect)]
+#[reflect(Component)]
+pub struct CloudSun {
+    /// The illuminance of the sun above the atmosphere, in lux per color
+    /// channel.
+    ///
+    /// The [`CloudSkyClock`] keeps this up to date.
+    ///
+    /// The default value is the color of a 5778 K blackbody, with a luminance
+    /// of 128,000 lux.
+    pub top_of_atmosphere_illuminance: Vec3,
+}
+
+/// Marks the [`DirectionalLight`] that a [`CloudSkyClock`] moves as the moon.
+///
+/// One is spawned automatically if there's none.
+#[derive(Clone, Copy, Component, Debug, Default, Reflect)]
+#[reflect(Component)]
+pub struct CloudMoon;
+
+impl Default for CloudSun {
+    fn default() -> Self {
+        Self {
+            top_of_atmosphere_illuminance: normalized_solar_color()
+                * CloudSkyClock::default().sun_illuminance,
+        }
+    }
+}
+
+impl Default for CloudSkyClock {
+    fn default() -> Self {
+        Self {
+            year: 2024,
+            day_of_year: 172,
+            time_of_day: 12.0,
+            latitude: 45.0,
+            time_scale: 1.0,
+            sun_illuminance: 128_000.0,
+            moon_illuminance: 0.3,
+        }
+    }
+}
+
+/// The positions of the sun and the moon at some instant.
+struct Ephemeris {
+    /// The world-space direction toward the sun.
+    sun_direction: Vec3,
+    /// The world-space direction toward the moon.
+    moon_direction: Vec3,
+    /// The brightness of the moon relative to the full moon.
+    moon_phase: f32,
+}
+
+impl CloudSkyClock {
+    /// Advances the clock by the given number of real seconds.
+    pub fn advance(&mut self, seconds: f32) {
+        self.time_of_day += seconds * self.time_scale / 3600.0;
+
+        while self.time_of_day >= 24.0 {
+            self.time_of_day -= 24.0;
+            self.day_of_year += 1;
+            if self.day_of_year > days_in_year(self.year) {
+                self.day_of_year = 1;
+                self.year += 1;
+            }
+        }
+        while self.time_of_day < 0.0 {
+            self.time_of_day += 24.0;
+            if self.day_of_year <= 1 {
+                self.year -= 1;
+                self.day_of_year = days_in_year(self.year);
+            } else {
+                self.day_of_year -= 1;
+            }
+        }
+    }
+
+    /// Returns the world-space direction toward the sun.
+    pub fn sun_direction(&self) -> Vec3 {
+        self.ephemeris().sun_direction
+    }
+
+    /// Returns the world-space direction toward the moon.
+    pub fn moon_direction(&self) -> Vec3 {
+        self.ephemeris().moon_direction
+    }
+
+    /// Returns the number of days since noon on January 1st, 2000, treating
+    /// local solar time as universal time.
+    fn days_since_j2000(&self) -> f64 {
+        (days_before_year(self.year) - days_before_year(2000)) as f64
+            + (self.day_of_year as f64 - 1.0)
+            + (self.time_of_day as f64 - 12.0) / 24.0
+    }
+
+    /// Computes the positions of the sun and the moon.
+    fn ephemeris(&self) -> Ephemeris {
+        let n = self.days_since_j2000();
+        let obliquity = (23.439 - 0.000_000_4 * n).to_radians();
+
+        // The sun's ecliptic longitude, from its mean longitude and anomaly.
+        let sun_mean_longitude = (280.460 + 0.985_647_4 * n).to_radians();
+        let sun_mean_anomaly = (357.528 + 0.985_600_3 * n).to_radians();
+        let sun_longitude = sun_mean_longitude
+            + 1.915_f64.to_radians() * sun_mean_anomaly.sin()
+            + 0.020_f64.to_radians() * (2.0 * sun_mean_anomaly).sin();
+
+        // The moon's ecliptic longitude and latitude, keeping only the largest
+        // terms.
+        let moon_mean_longitude = (218.316 + 13.176_396 * n).to_radians();
+        let moon_mean_anomaly = (134.963 + 13.064_993 * n).to_radians();
+        let moon_argument_of_latitude = (93.272 + 13.229_350 * n).to_radians();
+        let moon_longitude = moon_mean_longitude + 6.289_f64.to_radians() * moon_mean_anomaly.sin();
+        let moon_latitude = 5.128_f64.to_radians() * moon_argument_of_latitude.sin();
+
+        let (sun_right_ascension, sun_declination) =
+            ecliptic_to_equatorial(sun_longitude, 0.0, obliquity);
+        let (moon_right_ascension, moon_declination) =
+            ecliptic_to_equatorial(moon_longitude, moon_latitude, obliquity);
+
+        // Local solar time puts the sun on the meridian at noon. The moon is
+        // offset from the sun by the difference in right ascension.
+        let latitude = (self.latitude as f64).to_radians();
+        let sun_hour_angle = (self.time_of_day as f64 - 12.0) / 24.0 * 2.0 * PI;
+        let moon_hour_angle = sun_hour_angle + sun_right_ascension - moon_right_ascension;
+
+        // The phase angle of the moon is the supplement of its elongation from
+        // the sun. Treat the moon as a Lambertian sphere.
+        let elongation = (moon_latitude.cos() * (moon_longitude - sun_longitude).cos()).acos();
+        let phase_angle = PI - elongation;
+        let moon_phase = (phase_angle.sin() + (PI - phase_angle) * phase_angle.cos()) / PI;
+
+        Ephemeris {
+            sun_direction: horizontal_direction(sun_hour_angle, sun_declination, latitude),
+            moon_direction: horizontal_direction(moon_hour_angle, moon_declination, latitude),
+            moon_phase: moon_phase.max(0.0) as f32,
+        }
+    }
+}
+
+/// A system that spawns the sun and the moon, if they don't exist yet.
+pub fn spawn_cloud_sky_lights(
+    mut commands: Commands,
+    suns: Query<(), With<CloudSun>>,
+    moons: Query<(), With<CloudMoon>>,
+) {
+    if suns.is_empty() {
+        commands.spawn((
+            DirectionalLightBundle {
+                directional_light: DirectionalLight {
+                    shadows_enabled: true,
+                    ..default()
+                },
+                ..default()
+            },
+            VolumetricCloudLight {
+                step_count: 8,
+                ..default()
+            },
+            CloudSun::default(),
+            Name::new("Sun"),
+        ));
+    }
+
+    if moons.is_empty() {
+        commands.spawn((
+            DirectionalLightBundle {
+                directional_light: DirectionalLight {
+                    shadows_enabled: true,
+                    ..default()
+                },
+                ..default()
+            },
+            // Moonlight is too dim for the detail of a long march to show.
+            VolumetricCloudLight {
+                step_count: 2,
+                ..default()
+            },
+            CloudMoon,
+            Name::new("Moon"),
+        ));
+    }
+}
+
+/// A system that advances the [`CloudSkyClock`] and moves the sun and the moon
+/// accordingly.
+pub fn update_cloud_sky_clock(
+    mut clock: ResMut<CloudSkyClock>,
+    atmosphere: Res<CloudAtmosphere>,
+    time: Res<Time>,
+    mut suns: Query<(&mut Transform, &mut DirectionalLight, &mut CloudSun), Without<CloudMoon>>,
+    mut moons: Query<(&mut Transform, &mut DirectionalLight), (With<CloudMoon>, Without<CloudSun>)>,
+    mut solar_color: Local<Option<Vec3>>,
+) {
+    clock.advance(time.delta_seconds());
+    let ephemeris = clock.ephemeris();
+
+    // The moon reflects sunlight, so both share the color of the sun above
+    // the atmosphere. This never changes, so it's only computed once.
+    let solar_color = *solar_color.get_or_insert_with(normalized_solar_color);
+
+    for (mut transform, mut light, mut sun) in suns.iter_mut() {
+        sun.top_of_atmosphere_illuminance = solar_color * clock.sun_illuminance;
+        set_sky_light(
+            &mut transform,
+            &mut light,
+            ephemeris.sun_direction,
+            sun.top_of_atmosphere_illuminance,
+            &atmosphere,
+        );
+    }
+
+    for (mut transform, mut light) in moons.iter_mut() {
+        set_sky_light(
+            &mut transform,
+            &mut light,
+            ephemeris.moon_direction,
+            solar_color * clock.moon_illuminance * ephemeris.moon_phase,
+            &atmosphere,
+        );
+    }
+}
+
+/// Returns the color of the sun above the atmosphere, normalized to a
+/// luminance of 1.
+fn normalized_solar_color() -> Vec3 {
+    let solar_color = blackbody_luminance(SOLAR_TEMPERATURE);
+    solar_color / solar_color.dot(LUMINANCE_WEIGHTS)
+}
+
+/// Points a sky light along the given direction, and sets its illuminance and
+/// color from its illuminance above the atmosphere, in lux per color channel.
+fn set_sky_light(
+    transform: &mut Transform,
+    light: &mut DirectionalLight,
+    direction_to_light: Vec3,
+    top_of_atmosphere: Vec3,
+    atmosphere: &CloudAtmosphere,
+) {
+    let up = if direction_to_light.y.abs() > 0.999 {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    };
+    *transform = Transform::IDENTITY.looking_to(-direction_to_light, up);
+
+    // Split the light that reaches the ground into a luminance, which becomes
+    // the illuminance, and a color. Once the light sets, leave the color alone
+    // so that it doesn't flicker.
+    let ground = top_of_atmosphere
+        * atmosphere.transmittance(atmosphere.scene_altitude, direction_to_light.y);
+    light.illuminance = ground.dot(LUMINANCE_WEIGHTS);
+    if light.illuminance > 0.0 {
+        light.color = LinearRgba::from_vec3(ground / light.illuminance).into();
+    }
+}
+
+/// Converts ecliptic longitude and latitude into right ascension and
+/// declination, all in radians.
+fn ecliptic_to_equatorial(longitude: f64, latitude: f64, obliquity: f64) -> (f64, f64) {
+    let right_ascension = (longitude.sin() * obliquity.cos() - latitude.tan() * obliquity.sin())
+        .atan2(longitude.cos());
+    let declination = (latitude.sin() * obliquity.cos()
+        + latitude.cos() * obliquity.sin() * longitude.sin())
+    .asin();
+    (right_ascension, declination)
+}
+
+/// Returns the world-space direction toward a body with the given hour angle
+/// and declination, seen from the given latitude, all in radians.
+fn horizontal_direction(hour_angle: f64, declination: f64, latitude: f64) -> Vec3 {
+    let east = -declination.cos() * hour_angle.sin();
+    let north =
+        latitude.cos() * declination.sin() - latitude.sin() * declination.cos() * hour_angle.cos();
+    let up =
+        latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    vec3(east as f32, up as f32, -north as f32).normalize()
+}
+
+/// Returns the number of days in the given year of the Gregorian calendar.
+fn days_in_year(year: i32) -> u32 {
+    (days_before_year(year + 1) - days_before_year(year)) as u32
+}
+
+/// Returns the number of days from an arbitrary epoch to January 1st of the
+/// given year of the Gregorian calendar.
+fn days_before_year(year: i32) -> i64 {
+    let year = year as i64 - 1;
+    365 * year + year.div_euclid(4) - year.div_euclid(100) + year.div_euclid(400)
+}
@@ -0,0 +1,547 @@
+//! Temporal accumulation of clouds.
+//!
+//! With [`VolumetricCloudSettings::temporal`] set, the clouds of a view are
+//! raymarched offscreen, as for [`VolumetricCloudSettings::resolution_scale`],
+//! and then blended into a history of previous frames before they're
+//! upsampled onto the view. Jittering the rays differently every frame turns
+//! the banding of a low step count into noise, which the history averages
+//! away.
+//!
+//! The history is reprojected with the motion of the clouds themselves rather
+//! than that of the scene behind them: each volume writes where its clouds
+//! were on screen in the previous frame, from the previous view projection
+//! and the previous transform of the volume. Before blending, the history is
+//! clamped to the neighborhood of the new sample, which rejects most of what
+//! the reprojection gets wrong. Where the scene behind the clouds changed
+//! depth since the previous frame, such as at an edge that was just
+//! disoccluded, the history is dropped altogether.
+//!
+//! With [`VolumetricCloudSettings::update_pattern`], only one pixel of each
+//! block is raymarched per frame. The others keep their reprojected history,
+//! following the motion of the raymarched pixel of their block, and only take
+//! its new sample where the history is unusable.
+
+use bevy::{
+    core::FrameCount,
+    core_pipeline::{
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
+    },
+    ecs::entity::EntityHashMap,
+    pbr::{MeshPipelineViewLayoutKey, MeshPipelineViewLayouts, MeshViewBindGroup},
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{
+                sampler, texture_2d, texture_depth_2d, texture_depth_2d_multisampled,
+                uniform_buffer,
+            },
+            AddressMode, BindGroupLayout, BindGroupLayoutEntries, BindingResource,
+            CachedRenderPipelineId, ColorTargetState, ColorWrites, DynamicBindGroupEntries,
+            DynamicUniformBuffer, Extent3d, FilterMode, FragmentState, LoadOp, MultisampleState,
+            Operations, PipelineCache, PrimitiveState, RenderPassColorAttachment,
+            RenderPassDescriptor, RenderPipelineDescriptor, Sampler, SamplerBindingType,
+            SamplerDescriptor, ShaderStages, ShaderType, SpecializedRenderPipeline,
+            SpecializedRenderPipelines, StoreOp, TextureDescriptor, TextureDimension,
+            TextureSampleType, TextureUsages,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::{CachedTexture, TextureCache},
+        view::{ExtractedView, ViewDepthTexture},
+    },
+};
+
+use crate::volumetric_clouds::{
+    upsample::{ViewCloudOffscreenTextures, CLOUD_OFFSCREEN_FORMAT},
+    CloudVolume, VolumetricCloudSettings,
+};
+
+/// Accumulates clouds over several frames.
+///
+/// Set this as [`VolumetricCloudSettings::temporal`] to enable it. It only
+/// pays off together with [`VolumetricCloudSettings::jitter`], which varies
+/// the rays from frame to frame.
+#[derive(Clone, Copy, Debug, Reflect)]
+pub struct CloudTemporalAccumulation {
+    /// How much of each new frame is blended into the history.
+    ///
+    /// Lower values converge to a smoother image, but take longer to catch up
+    /// when the clouds change, and ghost more.
+    ///
+    /// The default value is 0.1.
+    pub current_weight: f32,
+
+    /// How much the depth of the scene behind the clouds may change, relative
+    /// to that depth, before the history there is dropped as disoccluded.
+    ///
+    /// The default value is 0.1.
+    pub disocclusion_threshold: f32,
+}
+
+impl Default for CloudTemporalAccumulation {
+    fn default() -> Self {
+        Self {
+            current_weight: 0.1,
+            disocclusion_threshold: 0.1,
+        }
+    }
+}
+
+/// The transforms of the previous frame, which the history is reprojected
+/// with.
+#[derive(Resource, Default)]
+pub struct CloudTemporalHistory {
+    /// The views that accumulated clouds over time in the previous frame.
+    views: EntityHashMap<PreviousCloudView>,
+    /// The world-from-local transforms of the cloud volumes in the previous
+    /// frame.
+    volumes: EntityHashMap<Mat4>,
+}
+
+/// A view that accumulated clouds over time in the previous frame.
+struct PreviousCloudView {
+    clip_from_world: Mat4,
+    view_from_world: Mat4,
+    /// The size of the offscreen targets of the view. If this changes, the
+    /// history is discarded.
+    target_size: UVec2,
+}
+
+impl CloudTemporalHistory {
+    /// Returns the clip-from-world transform of a view in the previous frame,
+    /// if it accumulated clouds over time.
+    pub fn previous_clip_from_world(&self, view: Entity) -> Option<Mat4> {
+        self.views.get(&view).map(|view| view.clip_from_world)
+    }
+
+    /// Returns the world-from-local transform of a cloud volume in the
+    /// previous frame, if it existed.
+    pub fn previous_world_from_local(&self, volume: Entity) -> Option<Mat4> {
+        self.volumes.get(&volume).copied()
+    }
+}
+
+/// The GPU version of [`CloudTemporalAccumulation`], together with the
+/// previous transforms of the view.
+#[derive(ShaderType)]
+pub struct CloudTemporalUniform {
+    previous_clip_from_world: Mat4,
+    previous_view_from_world: Mat4,
+    /// The weight of the new frame, or 1.0 if there's no usable history.
+    current_weight: f32,
+    disocclusion_threshold: f32,
+    /// The size of the blocks of [`VolumetricCloudSettings::update_pattern`].
+    update_pattern_size: u32,
+}
+
+/// The GPU buffer that stores the [`CloudTemporalUniform`] data.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct CloudTemporalUniformBuffer(pub DynamicUniformBuffer<CloudTemporalUniform>);
+
+/// Identifies a single specialization of the cloud temporal accumulation
+/// shader.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct CloudTemporalPipelineKey {
+    /// The layout of the mesh view bind group.
+    mesh_pipeline_view_key: MeshPipelineViewLayoutKey,
+}
+
+/// The GPU pipeline that blends the offscreen clouds into the history.
+#[derive(Resource)]
+pub struct CloudTemporalPipeline {
+    shader: Handle<Shader>,
+    /// A reference to the shared set of mesh pipeline view layouts.
+    mesh_view_layouts: MeshPipelineViewLayouts,
+    /// The bind group layouts, without and with multisampling.
+    bind_group_layouts: [BindGroupLayout; 2],
+    /// The sampler that the reprojected history is sampled with.
+    history_sampler: Sampler,
+}
+
+/// The temporal accumulation pipeline for a view.
+#[derive(Component, Deref, DerefMut)]
+pub struct ViewCloudTemporalPipeline(pub CachedRenderPipelineId);
+
+/// The history of the clouds of a view, in the same layout as the offscreen
+/// targets.
+pub struct CloudHistoryTextures {
+    /// The color that the clouds scatter toward the camera, with the inverse
+    /// depth of the scene in alpha.
+    pub color: CachedTexture,
+    /// The transmittance of the clouds, per color channel.
+    pub transmittance: CachedTexture,
+}
+
+/// The temporal accumulation state of a view, ready to be drawn.
+#[derive(Component)]
+pub struct ViewCloudTemporal {
+    /// The history from the previous frame.
+    pub read: CloudHistoryTextures,
+    /// The history that this frame writes, and upsamples onto the view.
+    pub write: CloudHistoryTextures,
+    /// The offset of this view's [`CloudTemporalUniform`] within the
+    /// [`CloudTemporalUniformBuffer`].
+    uniform_buffer_offset: u32,
+}
+
+impl FromWorld for CloudTemporalPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let mesh_view_layouts = world.resource::<MeshPipelineViewLayouts>();
+
+        let bind_group_layouts = [false, true].map(|multisampled| {
+            render_device.create_bind_group_layout(
+                "cloud temporal bind group layout",
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        // `temporal`
+                        uniform_buffer::<CloudTemporalUniform>(true),
+                        // `cloud_color`, `cloud_transmittance`, and
+                        // `cloud_motion`
+                        texture_2d(TextureSampleType::Float { filterable: false }),
+                        texture_2d(TextureSampleType::Float { filterable: false }),
+                        texture_2d(TextureSampleType::Float { filterable: false }),
+                        // `history_color`, `history_transmittance`, and
+                        // `history_sampler`
+                        texture_2d(TextureSampleType::Float { filterable: true }),
+                        texture_2d(TextureSampleType::Float { filterable: true }),
+                        sampler(SamplerBindingType::Filtering),
+                        // `depth_texture`
+                        if multisampled {
+                            texture_depth_2d_multisampled()
+                        } else {
+                            texture_depth_2d()
+                        },
+                    ),
+                ),
+            )
+        });
+
+        let history_sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("cloud history sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        CloudTemporalPipeline {
+            shader: world
+                .resource::<AssetServer>()
+                .load("embedded://bevy_clouds/volumetric_clouds/cloud_temporal.wgsl"),
+            mesh_view_layouts: mesh_view_layouts.clone(),
+            bind_group_layouts,
+            history_sampler,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for CloudTemporalPipeline {
+    type Key = CloudTemporalPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mesh_view_layout = self
+            .mesh_view_layouts
+            .get_view_layout(key.mesh_pipeline_view_key);
+
+        let multisampled = key
+            .mesh_pipeline_view_key
+            .contains(MeshPipelineViewLayoutKey::MULTISAMPLED);
+
+        let mut shader_defs = vec![];
+        if multisampled {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+
+        // The history is overwritten, not blended onto.
+        let target = Some(ColorTargetState {
+            format: CLOUD_OFFSCREEN_FORMAT,
+            blend: None,
+            write_mask: ColorWrites::ALL,
+        });
+
+        RenderPipelineDescriptor {
+            label: Some("cloud temporal pipeline".into()),
+            layout: vec![
+                mesh_view_layout.clone(),
+                self.bind_group_layouts[multisampled as usize].clone(),
+            ],
+            push_constant_ranges: vec![],
+            vertex: fullscreen_shader_vertex_state(),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![target.clone(), target],
+            }),
+        }
+    }
+}
+
+/// Specializes the temporal accumulation pipeline for all views that
+/// accumulate clouds over time.
+pub fn prepare_cloud_temporal_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<CloudTemporalPipeline>>,
+    temporal_pipeline: Res<CloudTemporalPipeline>,
+    view_targets: Query<(
+        Entity,
+        Has<NormalPrepass>,
+        Has<DepthPrepass>,
+        Has<MotionVectorPrepass>,
+        Has<DeferredPrepass>,
+        &VolumetricCloudSettings,
+    )>,
+    msaa: Res<Msaa>,
+) {
+    for (
+        entity,
+        normal_prepass,
+        depth_prepass,
+        motion_vector_prepass,
+        deferred_prepass,
+        volumetric_cloud_settings,
+    ) in view_targets.iter()
+    {
+        if volumetric_cloud_settings.temporal.is_none() {
+            continue;
+        }
+
+        // Create a mesh pipeline view layout key corresponding to the view.
+        let mut mesh_pipeline_view_key = MeshPipelineViewLayoutKey::from(*msaa);
+        mesh_pipeline_view_key.set(MeshPipelineViewLayoutKey::NORMAL_PREPASS, normal_prepass);
+        mesh_pipeline_view_key.set(MeshPipelineViewLayoutKey::DEPTH_PREPASS, depth_prepass);
+        mesh_pipeline_view_key.set(
+            MeshPipelineViewLayoutKey::MOTION_VECTOR_PREPASS,
+            motion_vector_prepass,
+        );
+        mesh_pipeline_view_key.set(
+            MeshPipelineViewLayoutKey::DEFERRED_PREPASS,
+            deferred_prepass,
+        );
+
+        let pipeline_id = pipelines.specialize(
+            &pipeline_cache,
+            &temporal_pipeline,
+            CloudTemporalPipelineKey {
+                mesh_pipeline_view_key,
+            },
+        );
+
+        commands
+            .entity(entity)
+            .insert(ViewCloudTemporalPipeline(pipeline_id));
+    }
+}
+
+/// Writes the [`CloudTemporalUniform`]s and allocates the history textures
+/// for all views that accumulate clouds over time.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_cloud_temporal(
+    mut commands: Commands,
+    view_targets: Query<(Entity, &ExtractedView, &VolumetricCloudSettings)>,
+    history: Res<CloudTemporalHistory>,
+    mut temporal_uniform_buffer: ResMut<CloudTemporalUniformBuffer>,
+    mut texture_cache: ResMut<TextureCache>,
+    frame_count: Res<FrameCount>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let view_count = view_targets
+        .iter()
+        .filter(|(_, _, settings)| settings.temporal.is_some())
+        .count();
+    let Some(mut writer) =
+        temporal_uniform_buffer.get_writer(view_count, &render_device, &render_queue)
+    else {
+        return;
+    };
+
+    for (view_entity, extracted_view, volumetric_cloud_settings) in view_targets.iter() {
+        let Some(temporal) = volumetric_cloud_settings.temporal else {
+            continue;
+        };
+
+        let target_size = volumetric_cloud_settings
+            .resolution_scale
+            .target_size(extracted_view.viewport.zw());
+        let view_from_world = extracted_view.world_from_view.compute_matrix().inverse();
+        let clip_from_world = extracted_view.clip_from_view * view_from_world;
+
+        // Start over if there's no history, or if it no longer lines up with
+        // the offscreen targets.
+        let previous = history
+            .views
+            .get(&view_entity)
+            .filter(|previous| previous.target_size == target_size);
+        let uniform_buffer_offset = writer.write(&CloudTemporalUniform {
+            previous_clip_from_world: previous
+                .map_or(clip_from_world, |previous| previous.clip_from_world),
+            previous_view_from_world: previous
+                .map_or(view_from_world, |previous| previous.view_from_world),
+            current_weight: if previous.is_some() {
+                temporal.current_weight
+            } else {
+                1.0
+            },
+            disocclusion_threshold: temporal.disocclusion_threshold,
+            update_pattern_size: volumetric_cloud_settings.update_pattern_size(),
+        });
+
+        // Alternate between two sets of textures, reading the one written in
+        // the previous frame.
+        let mut get_texture = |label| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d {
+                        width: target_size.x,
+                        height: target_size.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: CLOUD_OFFSCREEN_FORMAT,
+                    usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                },
+            )
+        };
+        let history_1 = CloudHistoryTextures {
+            color: get_texture("cloud history 1 color"),
+            transmittance: get_texture("cloud history 1 transmittance"),
+        };
+        let history_2 = CloudHistoryTextures {
+            color: get_texture("cloud history 2 color"),
+            transmittance: get_texture("cloud history 2 transmittance"),
+        };
+        let (write, read) = if frame_count.0 % 2 == 0 {
+            (history_1, history_2)
+        } else {
+            (history_2, history_1)
+        };
+
+        commands.entity(view_entity).insert(ViewCloudTemporal {
+            read,
+            write,
+            uniform_buffer_offset,
+        });
+    }
+}
+
+/// Remembers the transforms of this frame's views and cloud volumes, so that
+/// the next frame can reproject its history.
+pub fn record_cloud_temporal_history(
+    mut history: ResMut<CloudTemporalHistory>,
+    view_targets: Query<(Entity, &ExtractedView, &VolumetricCloudSettings)>,
+    cloud_volumes: Query<(Entity, &GlobalTransform), With<CloudVolume>>,
+) {
+    history.views.clear();
+    for (view_entity, extracted_view, volumetric_cloud_settings) in view_targets.iter() {
+        if volumetric_cloud_settings.temporal.is_none() {
+            continue;
+        }
+
+        let view_from_world = extracted_view.world_from_view.compute_matrix().inverse();
+        history.views.insert(
+            view_entity,
+            PreviousCloudView {
+                clip_from_world: extracted_view.clip_from_view * view_from_world,
+                view_from_world,
+                target_size: volumetric_cloud_settings
+                    .resolution_scale
+                    .target_size(extracted_view.viewport.zw()),
+            },
+        );
+    }
+
+    history.volumes.clear();
+    for (volume_entity, transform) in cloud_volumes.iter() {
+        history
+            .volumes
+            .insert(volume_entity, transform.compute_matrix());
+    }
+}
+
+/// Blends the offscreen clouds of a view into its history.
+///
+/// This is called by the volumetric cloud node after it draws the clouds and
+/// before it upsamples them. Returns false if the pipeline isn't ready yet, in
+/// which case the history wasn't written and the offscreen clouds should be
+/// upsampled as they are.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn render_cloud_temporal(
+    render_context: &mut RenderContext,
+    world: &World,
+    view_depth_texture: &ViewDepthTexture,
+    view_bind_group: &MeshViewBindGroup,
+    view_bind_group_offsets: &[u32],
+    offscreen_textures: &ViewCloudOffscreenTextures,
+    view_temporal: &ViewCloudTemporal,
+    view_temporal_pipeline: &ViewCloudTemporalPipeline,
+) -> bool {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let temporal_pipeline = world.resource::<CloudTemporalPipeline>();
+    let temporal_uniform_buffer = world.resource::<CloudTemporalUniformBuffer>();
+    let msaa = world.resource::<Msaa>();
+
+    let (Some(pipeline), Some(temporal_uniform_buffer_binding), Some(motion)) = (
+        pipeline_cache.get_render_pipeline(**view_temporal_pipeline),
+        temporal_uniform_buffer.binding(),
+        offscreen_textures.motion.as_ref(),
+    ) else {
+        return false;
+    };
+
+    let bind_group = render_context.render_device().create_bind_group(
+        "cloud temporal bind group",
+        &temporal_pipeline.bind_group_layouts[!matches!(*msaa, Msaa::Off) as usize],
+        &DynamicBindGroupEntries::sequential((
+            temporal_uniform_buffer_binding,
+            BindingResource::TextureView(&offscreen_textures.color.default_view),
+            BindingResource::TextureView(&offscreen_textures.transmittance.default_view),
+            BindingResource::TextureView(&motion.default_view),
+            BindingResource::TextureView(&view_temporal.read.color.default_view),
+            BindingResource::TextureView(&view_temporal.read.transmittance.default_view),
+            BindingResource::Sampler(&temporal_pipeline.history_sampler),
+            BindingResource::TextureView(view_depth_texture.view()),
+        )),
+    );
+
+    let write_attachment = |view| {
+        Some(RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Load,
+                store: StoreOp::Store,
+            },
+        })
+    };
+
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("cloud temporal pass"),
+        color_attachments: &[
+            write_attachment(&view_temporal.write.color.default_view),
+            write_attachment(&view_temporal.write.transmittance.default_view),
+        ],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+
+    render_pass.set_render_pipeline(pipeline);
+    render_pass.set_bind_group(0, &view_bind_group.value, view_bind_group_offsets);
+    render_pass.set_bind_group(1, &bind_group, &[view_temporal.uniform_buffer_offset]);
+    render_pass.draw(0..3, 0..1);
+
+    true
+}
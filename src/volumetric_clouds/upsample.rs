@@ -0,0 +1,419 @@
+//! Rendering clouds below the resolution of the view.
+//!
+//! When [`VolumetricCloudSettings::resolution_scale`] is below full
+//! resolution, or the clouds are accumulated over time, the cloud volumes are
+//! raymarched into a pair of offscreen targets instead of the view: one
+//! accumulates the color that the clouds scatter toward the camera, and the
+//! other multiplies together their transmittance. A fullscreen pass then
+//! upsamples both onto the view.
+//!
+//! Plain bilinear upsampling would smear the clouds across the silhouettes of
+//! the scene in front of them. Instead, each of the four nearest low-resolution
+//! texels is weighted by how close the depth that it was raymarched against is
+//! to the depth of the full-resolution pixel, as in a joint bilateral filter.
+//! Texels that saw the sky behind a tree don't bleed onto the tree.
+
+use bevy::{
+    core_pipeline::{
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
+    },
+    pbr::{MeshPipelineViewLayoutKey, MeshPipelineViewLayouts, MeshViewBindGroup},
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{texture_2d, texture_depth_2d, texture_depth_2d_multisampled},
+            BindGroupLayout, BindGroupLayoutEntries, BindingResource, BlendComponent, BlendFactor,
+            BlendOperation, BlendState, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            DynamicBindGroupEntries, Extent3d, FragmentState, LoadOp, MultisampleState, Operations,
+            PipelineCache, PrimitiveState, RenderPassColorAttachment, RenderPassDescriptor,
+            RenderPipelineDescriptor, ShaderStages, SpecializedRenderPipeline,
+            SpecializedRenderPipelines, StoreOp, TextureDescriptor, TextureDimension,
+            TextureFormat, TextureSampleType, TextureUsages, TextureView,
+        },
+        renderer::{RenderContext, RenderDevice},
+        settings::WgpuFeatures,
+        texture::{BevyDefault, CachedTexture, TextureCache},
+        view::{ExtractedView, ViewDepthTexture, ViewTarget},
+    },
+};
+use bitflags::bitflags;
+
+use crate::volumetric_clouds::{VolumetricCloudBackend, VolumetricCloudSettings};
+
+/// The texture format of the offscreen cloud targets.
+pub const CLOUD_OFFSCREEN_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+bitflags! {
+    /// Flags that describe the pipeline used to upsample the clouds.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    struct CloudUpsamplePipelineKeyFlags: u8 {
+        /// The view's color format has high dynamic range.
+        const HDR = 0x1;
+        /// The device supports dual-source blending.
+        const DUAL_SOURCE_BLENDING = 0x2;
+    }
+}
+
+/// Identifies a single specialization of the cloud upsampling shader.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct CloudUpsamplePipelineKey {
+    /// The layout of the mesh view bind group.
+    mesh_pipeline_view_key: MeshPipelineViewLayoutKey,
+    /// Flags that describe the view.
+    flags: CloudUpsamplePipelineKeyFlags,
+}
+
+/// The GPU pipeline that upsamples the low-resolution clouds onto the view.
+#[derive(Resource)]
+pub struct CloudUpsamplePipeline {
+    shader: Handle<Shader>,
+    /// A reference to the shared set of mesh pipeline view layouts.
+    mesh_view_layouts: MeshPipelineViewLayouts,
+    /// The bind group layouts, without and with multisampling.
+    bind_group_layouts: [BindGroupLayout; 2],
+}
+
+/// The upsampling pipeline for a view that renders clouds below full
+/// resolution.
+#[derive(Component, Deref, DerefMut)]
+pub struct ViewCloudUpsamplePipeline(pub CachedRenderPipelineId);
+
+/// The offscreen targets that the clouds of a view are raymarched into.
+#[derive(Component)]
+pub struct ViewCloudOffscreenTextures {
+    /// The color that the clouds scatter toward the camera.
+    pub color: CachedTexture,
+    /// The transmittance of the clouds, per color channel.
+    pub transmittance: CachedTexture,
+    /// The screen-space motion of the clouds since the previous frame,
+    /// premultiplied by their opacity, with the opacity in alpha.
+    ///
+    /// This is only present if the clouds are accumulated over time.
+    pub motion: Option<CachedTexture>,
+}
+
+impl FromWorld for CloudUpsamplePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let mesh_view_layouts = world.resource::<MeshPipelineViewLayouts>();
+
+        let bind_group_layouts = [false, true].map(|multisampled| {
+            render_device.create_bind_group_layout(
+                "cloud upsample bind group layout",
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        // `cloud_color`
+                        texture_2d(TextureSampleType::Float { filterable: false }),
+                        // `cloud_transmittance`
+                        texture_2d(TextureSampleType::Float { filterable: false }),
+                        // `depth_texture`
+                        if multisampled {
+                            texture_depth_2d_multisampled()
+                        } else {
+                            texture_depth_2d()
+                        },
+                    ),
+                ),
+            )
+        });
+
+        CloudUpsamplePipeline {
+            shader: world
+                .resource::<AssetServer>()
+                .load("embedded://bevy_clouds/volumetric_clouds/cloud_upsample.wgsl"),
+            mesh_view_layouts: mesh_view_layouts.clone(),
+            bind_group_layouts,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for CloudUpsamplePipeline {
+    type Key = CloudUpsamplePipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mesh_view_layout = self
+            .mesh_view_layouts
+            .get_view_layout(key.mesh_pipeline_view_key);
+
+        let multisampled = key
+            .mesh_pipeline_view_key
+            .contains(MeshPipelineViewLayoutKey::MULTISAMPLED);
+
+        let mut shader_defs = vec![];
+        if multisampled {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+
+        // Blend the same way the cloud volumes do at full resolution.
+        let color_blend = if key
+            .flags
+            .contains(CloudUpsamplePipelineKeyFlags::DUAL_SOURCE_BLENDING)
+        {
+            shader_defs.push("DUAL_SOURCE_BLENDING".into());
+            BlendComponent {
+                src_factor: BlendFactor::One,
+                dst_factor: BlendFactor::Src1,
+                operation: BlendOperation::Add,
+            }
+        } else {
+            BlendComponent {
+                src_factor: BlendFactor::One,
+                dst_factor: BlendFactor::OneMinusSrcAlpha,
+                operation: BlendOperation::Add,
+            }
+        };
+
+        RenderPipelineDescriptor {
+            label: Some("cloud upsample pipeline".into()),
+            layout: vec![
+                mesh_view_layout.clone(),
+                self.bind_group_layouts[multisampled as usize].clone(),
+            ],
+            push_constant_ranges: vec![],
+            vertex: fullscreen_shader_vertex_state(),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: if key.flags.contains(CloudUpsamplePipelineKeyFlags::HDR) {
+                        ViewTarget::TEXTURE_FORMAT_HDR
+                    } else {
+                        TextureFormat::bevy_default()
+                    },
+                    blend: Some(BlendState {
+                        color: color_blend,
+                        alpha: BlendComponent {
+                            src_factor: BlendFactor::Zero,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                    }),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+        }
+    }
+}
+
+/// Specializes the upsampling pipeline for all views that render clouds below
+/// full resolution.
+pub fn prepare_cloud_upsample_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<CloudUpsamplePipeline>>,
+    upsample_pipeline: Res<CloudUpsamplePipeline>,
+    view_targets: Query<(
+        Entity,
+        &ExtractedView,
+        Has<NormalPrepass>,
+        Has<DepthPrepass>,
+        Has<MotionVectorPrepass>,
+        Has<DeferredPrepass>,
+        &VolumetricCloudSettings,
+    )>,
+    msaa: Res<Msaa>,
+    render_device: Res<RenderDevice>,
+) {
+    for (
+        entity,
+        view,
+        normal_prepass,
+        depth_prepass,
+        motion_vector_prepass,
+        deferred_prepass,
+        volumetric_cloud_settings,
+    ) in view_targets.iter()
+    {
+        if !volumetric_cloud_settings.renders_offscreen() {
+            continue;
+        }
+
+        // Create a mesh pipeline view layout key corresponding to the view.
+        let mut mesh_pipeline_view_key = MeshPipelineViewLayoutKey::from(*msaa);
+        mesh_pipeline_view_key.set(MeshPipelineViewLayoutKey::NORMAL_PREPASS, normal_prepass);
+        mesh_pipeline_view_key.set(MeshPipelineViewLayoutKey::DEPTH_PREPASS, depth_prepass);
+        mesh_pipeline_view_key.set(
+            MeshPipelineViewLayoutKey::MOTION_VECTOR_PREPASS,
+            motion_vector_prepass,
+        );
+        mesh_pipeline_view_key.set(
+            MeshPipelineViewLayoutKey::DEFERRED_PREPASS,
+            deferred_prepass,
+        );
+
+        let mut flags = CloudUpsamplePipelineKeyFlags::empty();
+        flags.set(CloudUpsamplePipelineKeyFlags::HDR, view.hdr);
+        flags.set(
+            CloudUpsamplePipelineKeyFlags::DUAL_SOURCE_BLENDING,
+            render_device
+                .features()
+                .contains(WgpuFeatures::DUAL_SOURCE_BLENDING),
+        );
+
+        let pipeline_id = pipelines.specialize(
+            &pipeline_cache,
+            &upsample_pipeline,
+            CloudUpsamplePipelineKey {
+                mesh_pipeline_view_key,
+                flags,
+            },
+        );
+
+        commands
+            .entity(entity)
+            .insert(ViewCloudUpsamplePipeline(pipeline_id));
+    }
+}
+
+/// Allocates the offscreen cloud targets for all views that don't draw clouds
+/// straight onto the view.
+pub fn prepare_cloud_offscreen_textures(
+    mut commands: Commands,
+    view_targets: Query<(Entity, &ExtractedView, &VolumetricCloudSettings)>,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+) {
+    for (entity, view, volumetric_cloud_settings) in view_targets.iter() {
+        if !volumetric_cloud_settings.renders_offscreen() {
+            continue;
+        }
+
+        let size = volumetric_cloud_settings
+            .resolution_scale
+            .target_size(view.viewport.zw());
+        // The compute backend composites the clouds onto the targets as
+        // storage textures.
+        let mut usage = TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING;
+        if volumetric_cloud_settings.backend == VolumetricCloudBackend::Compute {
+            usage |= TextureUsages::STORAGE_BINDING;
+        }
+        let mut get_texture = |label| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d {
+                        width: size.x,
+                        height: size.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: CLOUD_OFFSCREEN_FORMAT,
+                    usage,
+                    view_formats: &[],
+                },
+            )
+        };
+
+        let textures = ViewCloudOffscreenTextures {
+            color: get_texture("offscreen cloud color"),
+            transmittance: get_texture("offscreen cloud transmittance"),
+            motion: volumetric_cloud_settings
+                .temporal
+                .map(|_| get_texture("offscreen cloud motion")),
+        };
+        commands.entity(entity).insert(textures);
+    }
+}
+
+/// Clears the offscreen cloud targets of a view to no light, full
+/// transmittance, and no motion.
+///
+/// This is called by the volumetric cloud node before it draws the clouds.
+pub(crate) fn clear_cloud_offscreen_textures(
+    render_context: &mut RenderContext,
+    textures: &ViewCloudOffscreenTextures,
+) {
+    let clear_attachment = |view, color: LinearRgba| {
+        Some(RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(color.into()),
+                store: StoreOp::Store,
+            },
+        })
+    };
+
+    let mut color_attachments = vec![
+        clear_attachment(&textures.color.default_view, LinearRgba::NONE),
+        clear_attachment(&textures.transmittance.default_view, LinearRgba::WHITE),
+    ];
+    if let Some(motion) = &textures.motion {
+        color_attachments.push(clear_attachment(&motion.default_view, LinearRgba::NONE));
+    }
+
+    render_context
+        .command_encoder()
+        .begin_render_pass(&RenderPassDescriptor {
+            label: Some("clear offscreen clouds pass"),
+            color_attachments: &color_attachments,
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+}
+
+/// Upsamples the offscreen clouds of a view onto the main pass.
+///
+/// This is called by the volumetric cloud node after it draws the clouds, and
+/// after it accumulates them over time if enabled. `color` and
+/// `transmittance` are the final offscreen color and transmittance.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn render_cloud_upsample(
+    render_context: &mut RenderContext,
+    world: &World,
+    view_target: &ViewTarget,
+    view_depth_texture: &ViewDepthTexture,
+    view_bind_group: &MeshViewBindGroup,
+    view_bind_group_offsets: &[u32],
+    color: &TextureView,
+    transmittance: &TextureView,
+    view_upsample_pipeline: &ViewCloudUpsamplePipeline,
+) {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let upsample_pipeline = world.resource::<CloudUpsamplePipeline>();
+    let msaa = world.resource::<Msaa>();
+
+    let Some(pipeline) = pipeline_cache.get_render_pipeline(**view_upsample_pipeline) else {
+        return;
+    };
+
+    let bind_group = render_context.render_device().create_bind_group(
+        "cloud upsample bind group",
+        &upsample_pipeline.bind_group_layouts[!matches!(*msaa, Msaa::Off) as usize],
+        &DynamicBindGroupEntries::sequential((
+            BindingResource::TextureView(color),
+            BindingResource::TextureView(transmittance),
+            BindingResource::TextureView(view_depth_texture.view()),
+        )),
+    );
+
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("cloud upsample pass"),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view: view_target.main_texture_view(),
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Load,
+                store: StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+
+    render_pass.set_render_pipeline(pipeline);
+    render_pass.set_bind_group(0, &view_bind_group.value, view_bind_group_offsets);
+    render_pass.set_bind_group(1, &bind_group, &[]);
+    render_pass.draw(0..3, 0..1);
+}
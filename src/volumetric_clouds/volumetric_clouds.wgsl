@@ -15,17 +15,47 @@
 
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
 #import bevy_pbr::mesh_functions::{get_world_from_local, mesh_position_local_to_clip}
-#import bevy_pbr::mesh_view_bindings::{globals, lights, view}
-#import bevy_pbr::mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_VOLUMETRIC_BIT
-#import bevy_pbr::shadow_sampling::sample_shadow_map_hardware
-#import bevy_pbr::shadows::{get_cascade_index, world_to_directional_light_local}
+#import bevy_pbr::fog::{atmospheric_fog, exponential_fog, exponential_squared_fog, linear_fog}
+#import bevy_pbr::mesh_view_bindings::{fog, globals, lights, view}
+#import bevy_pbr::mesh_view_types::{
+    DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT,
+    FOG_MODE_ATMOSPHERIC,
+    FOG_MODE_EXPONENTIAL,
+    FOG_MODE_EXPONENTIAL_SQUARED,
+    FOG_MODE_LINEAR
+}
+#ifdef IRRADIANCE_VOLUME
+#import bevy_pbr::irradiance_volume::irradiance_volume_light
+#endif  // IRRADIANCE_VOLUME
+#import bevy_pbr::shadows::fetch_directional_shadow
 #import bevy_pbr::utils::interleaved_gradient_noise
 #import bevy_pbr::view_transformations::{
     depth_ndc_to_view_z,
     frag_coord_to_ndc,
+    frag_coord_to_uv,
+    ndc_to_uv,
     position_ndc_to_view,
     position_ndc_to_world,
-    position_view_to_world
+    position_view_to_world,
+    view_z_to_depth_ndc
+}
+
+// A single segment of a lightning bolt. See `CloudLightningSegmentUniform` in
+// `render.rs`.
+struct LightningSegment {
+    start: vec3<f32>,
+    end: vec3<f32>,
+    intensity: vec3<f32>,
+}
+
+// The settings of a single directional light. See
+// `VolumetricCloudLightUniform` in `render.rs`.
+struct VolumetricCloudLight {
+    tint: vec3<f32>,
+    step_count: u32,
+    scattering_asymmetry: f32,
+    flags: u32,
+    top_of_atmosphere: vec3<f32>,
 }
 
 // The GPU version of [`VolumetricFogSettings`]. See the comments in
@@ -36,19 +66,41 @@ struct VolumetricFog {
     far_planes: array<vec4<f32>, 3>,
     fog_color: vec3<f32>,
     light_tint: vec3<f32>,
+    emissive: vec3<f32>,
+    temperature_range: vec2<f32>,
     ambient_color: vec3<f32>,
     ambient_intensity: f32,
     step_count: u32,
+    lightning_count: u32,
+    lightning: array<LightningSegment, MAX_CLOUD_LIGHTNING_SEGMENTS>,
+    cloud_lights: array<VolumetricCloudLight, #{MAX_DIRECTIONAL_LIGHTS}u>,
     bounding_radius: f32,
-    absorption: f32,
-    scattering: f32,
+    absorption: vec3<f32>,
+    scattering: vec3<f32>,
     density_factor: f32,
     scattering_asymmetry: f32,
     light_intensity: f32,
     jitter_strength: f32,
+    atmosphere_bottom_radius: f32,
+    atmosphere_top_radius: f32,
+    scene_altitude: f32,
+    ground_albedo: vec3<f32>,
+    resolution_scale: vec2<f32>,
+    previous_clip_from_world: mat4x4<f32>,
+    update_pattern_size: u32,
+    nearest_view_depth: f32,
+    min_step_size: f32,
+    max_step_size: f32,
+    step_distance_factor: f32,
 }
 
+#ifdef COMPUTE
+// The compute backend raymarches several volumes per pixel, loading the
+// settings of each from `cloud_volumes` in turn.
+var<private> volumetric_fog: VolumetricFog;
+#else   // COMPUTE
 @group(1) @binding(0) var<uniform> volumetric_fog: VolumetricFog;
+#endif  // COMPUTE
 
 #ifdef MULTISAMPLED
 @group(1) @binding(1) var depth_texture: texture_depth_multisampled_2d;
@@ -61,8 +113,81 @@ struct VolumetricFog {
 @group(1) @binding(3) var density_sampler: sampler;
 #endif  // DENSITY_TEXTURE
 
+#ifdef EMISSION_TEXTURE
+@group(1) @binding(4) var emission_texture: texture_3d<f32>;
+@group(1) @binding(5) var emission_sampler: sampler;
+@group(1) @binding(6) var blackbody_lut: texture_2d<f32>;
+#endif  // EMISSION_TEXTURE
+
+@group(1) @binding(7) var atmosphere_transmittance_lut: texture_2d<f32>;
+
+#ifdef COMPUTE
+// The offscreen targets. The compute backend composites each dispatch under
+// the dispatches before it by hand, with the same equations that the raster
+// backend blends with.
+@group(2) @binding(0) var cloud_color: texture_storage_2d<rgba16float, read_write>;
+@group(2) @binding(1) var cloud_transmittance: texture_storage_2d<rgba16float, read_write>;
+#ifdef TEMPORAL
+@group(2) @binding(2) var cloud_motion: texture_storage_2d<rgba16float, read_write>;
+#endif  // TEMPORAL
+
+// The settings of the volumes, and the tile bins of this dispatch. Each tile has
+// the offset and length of its list in `tile_volumes`, which holds indices into
+// `cloud_volumes`, front to back. See `CloudTileBinBuffers` in `compute.rs`.
+@group(2) @binding(3) var<storage> cloud_volumes: array<VolumetricFog>;
+@group(2) @binding(4) var<storage> tile_ranges: array<vec2<u32>>;
+@group(2) @binding(5) var<storage> tile_volumes: array<u32>;
+
+// The farthest view-space depth of the scene in the tile of this workgroup.
+// Positive floats order the same way as their bits, so this holds the bits.
+var<workgroup> tile_max_scene_depth: atomic<u32>;
+#endif  // COMPUTE
+
+// The optical depth that an adaptive raymarching step crosses at most, unless
+// the bounds on its length stop it.
+const MAX_STEP_OPTICAL_DEPTH: f32 = 0.25;
+
+// The transmittance below which the compute backend stops raymarching the
+// volumes behind a pixel.
+const MIN_COMPUTE_TRANSMITTANCE: f32 = 0.001;
+
+// The most cloud volumes in the list of a tile in a single dispatch, which
+// each pixel marches together. This must match the constant in `compute.rs`.
+const MAX_PIXEL_CLOUD_VOLUMES: u32 = 8u;
+
+// A view-space depth beyond any in the scene.
+const MAX_VIEW_DEPTH: f32 = 3.4e38;
+
+// This must match the constant in `lightning.rs`.
+const MAX_CLOUD_LIGHTNING_SEGMENTS: u32 = 16u;
+
+// The closest distance to a lightning bolt that we evaluate its light at, to
+// avoid the singularity on the bolt itself.
+const MIN_LIGHTNING_DISTANCE: f32 = 0.01;
+
+// These must match the constants in `blackbody.rs`.
+const BLACKBODY_LUT_SIZE: u32 = 256u;
+const BLACKBODY_LUT_MIN_TEMPERATURE: f32 = 500.0;
+const BLACKBODY_LUT_MAX_TEMPERATURE: f32 = 40000.0;
+
+// These must match `VolumetricCloudLightFlags` in `render.rs`.
+const VOLUMETRIC_CLOUD_LIGHT_FLAGS_ENABLED: u32 = 1u;
+const VOLUMETRIC_CLOUD_LIGHT_FLAGS_SHADOW_MAP: u32 = 2u;
+const VOLUMETRIC_CLOUD_LIGHT_FLAGS_SCATTERING_ASYMMETRY: u32 = 4u;
+const VOLUMETRIC_CLOUD_LIGHT_FLAGS_SUN: u32 = 8u;
+
+// These must match the constants in `atmosphere.rs`.
+const ATMOSPHERE_TRANSMITTANCE_LUT_WIDTH: u32 = 256u;
+const ATMOSPHERE_TRANSMITTANCE_LUT_HEIGHT: u32 = 64u;
+
 // 1 / (4π)
 const FRAC_4_PI: f32 = 0.07957747154594767;
+// 1 / π
+const FRAC_1_PI: f32 = 0.3183098861837907;
+
+// The number of steps in the short march from each sample down to the ground,
+// which shades the light bounced off the ground.
+const GROUND_BOUNCE_STEP_COUNT: u32 = 4u;
 
 struct Vertex {
     @builtin(instance_index) instance_index: u32,
@@ -83,44 +208,594 @@ fn vertex(vertex: Vertex) -> @builtin(position) vec4<f32> {
 // [1]: https://www.scratchapixel.com/lessons/3d-basic-rendering/volume-rendering-for-developers/ray-marching-get-it-right.html
 //
 // [2]: https://www.pbr-book.org/4ed/Volume_Scattering/Phase_Functions#TheHenyeyndashGreensteinPhaseFunction
-fn henyey_greenstein(neg_LdotV: f32) -> f32 {
-    let g = volumetric_fog.scattering_asymmetry;
+fn henyey_greenstein(neg_LdotV: f32, g: f32) -> f32 {
     let denom = 1.0 + g * g - 2.0 * g * neg_LdotV;
     return FRAC_4_PI * (1.0 - g * g) / (denom * sqrt(denom));
 }
 
-@fragment
-fn fragment(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
-    
+#ifdef EMISSION_TEXTURE
+// Looks up the luminance, in cd/m², of a blackbody at the given temperature in
+// kelvin.
+//
+// The lookup table is spaced evenly in reciprocal temperature and stores the
+// logarithm of the luminance, so we interpolate by hand rather than relying on
+// a filtering sampler.
+fn blackbody(temperature: f32) -> vec3<f32> {
+    let inverse_min = 1.0 / BLACKBODY_LUT_MIN_TEMPERATURE;
+    let inverse_max = 1.0 / BLACKBODY_LUT_MAX_TEMPERATURE;
+    let u = saturate((inverse_min - 1.0 / max(temperature, 1.0)) / (inverse_min - inverse_max));
 
-    // Unpack the `volumetric_fog` settings.
+    let texel = u * f32(BLACKBODY_LUT_SIZE - 1u);
+    let texel_a = u32(floor(texel));
+    let texel_b = min(texel_a + 1u, BLACKBODY_LUT_SIZE - 1u);
+    let entry = mix(
+        textureLoad(blackbody_lut, vec2(texel_a, 0u), 0),
+        textureLoad(blackbody_lut, vec2(texel_b, 0u), 0),
+        fract(texel)
+    );
+
+    // Temperatures below the table are far too dim to see.
+    return entry.rgb * exp2(entry.a) * f32(temperature >= BLACKBODY_LUT_MIN_TEMPERATURE);
+}
+#endif  // EMISSION_TEXTURE
+
+// Looks up the transmittance from a point at the given altitude above sea
+// level to the top of the atmosphere, along a direction whose angle from the
+// zenith has the cosine `mu`.
+//
+// This inverts the mapping in `CloudAtmosphere::transmittance_lut_image`. The
+// geometry is rearranged to avoid cancellation between the huge radii of the
+// planet and the atmosphere. Like the blackbody table, the lookup table holds
+// 32-bit floats, so we interpolate by hand.
+fn atmosphere_transmittance(altitude: f32, mu: f32) -> vec3<f32> {
+    let bottom_radius = volumetric_fog.atmosphere_bottom_radius;
+    let top_radius = volumetric_fog.atmosphere_top_radius;
+    let height = clamp(altitude, 0.0, top_radius - bottom_radius);
+    let r = bottom_radius + height;
+
+    // The squared distances to the horizon from this point and from the
+    // ground.
+    let rho_squared = height * (2.0 * bottom_radius + height);
+    let horizon = sqrt((top_radius - bottom_radius) * (top_radius + bottom_radius));
+
+    // Rays that hit the planet see no light.
+    if (mu < 0.0 && r * r * mu * mu <= rho_squared) {
+        return vec3(0.0);
+    }
+
+    // Find the distance to the top of the atmosphere.
+    let top_squared = (top_radius - r) * (top_radius + r);
+    let discriminant = sqrt(max(r * r * mu * mu + top_squared, 0.0));
+    var d = -r * mu + discriminant;
+    if (mu > 0.0) {
+        d = top_squared / (r * mu + discriminant);
+    }
+
+    let rho = sqrt(rho_squared);
+    let d_min = top_radius - r;
+    let d_max = rho + horizon;
+    let uv = saturate(vec2((d - d_min) / max(d_max - d_min, 1e-3), rho / horizon));
+
+    let size = vec2(ATMOSPHERE_TRANSMITTANCE_LUT_WIDTH, ATMOSPHERE_TRANSMITTANCE_LUT_HEIGHT);
+    let texel = uv * vec2<f32>(size - 1u);
+    let texel_a = vec2<u32>(floor(texel));
+    let texel_b = min(texel_a + 1u, size - 1u);
+    let t = fract(texel);
+    return mix(
+        mix(
+            textureLoad(atmosphere_transmittance_lut, texel_a, 0).rgb,
+            textureLoad(atmosphere_transmittance_lut, vec2(texel_b.x, texel_a.y), 0).rgb,
+            t.x
+        ),
+        mix(
+            textureLoad(atmosphere_transmittance_lut, vec2(texel_a.x, texel_b.y), 0).rgb,
+            textureLoad(atmosphere_transmittance_lut, texel_b, 0).rgb,
+            t.x
+        ),
+        t.y
+    );
+}
+
+// Returns the transmittance from the given point along the given world-space
+// direction, through the cloud volume, up to the given distance or to the
+// boundary of the volume, whichever is closer.
+fn march_transmittance(
+    P_uvw: vec3<f32>,
+    Rd_world: vec3<f32>,
+    max_distance: f32,
+    step_count: u32
+) -> vec3<f32> {
+    // Find where the ray leaves the volume. Axes that it runs parallel to
+    // never bound it.
     let uvw_from_world = volumetric_fog.uvw_from_world;
+    let Rd_uvw = mat3x3(uvw_from_world[0].xyz, uvw_from_world[1].xyz, uvw_from_world[2].xyz) *
+        Rd_world;
+    let t_planes = select(
+        select((vec3(1.0) - P_uvw) / Rd_uvw, -P_uvw / Rd_uvw, Rd_uvw < vec3(0.0)),
+        vec3(1e30),
+        abs(Rd_uvw) < vec3(1e-8)
+    );
+    let ray_length = clamp(min(t_planes.x, min(t_planes.y, t_planes.z)), 0.0, max_distance);
+
+    // March through the density, sampling at the middle of each step.
+    let step_size = ray_length / f32(step_count);
+    var density_sum = 0.0;
+    for (var step = 0u; step < step_count; step += 1u) {
+        density_sum += sample_density(P_uvw + Rd_uvw * ((f32(step) + 0.5) * step_size));
+    }
+    return exp(-(volumetric_fog.absorption + volumetric_fog.scattering) * density_sum * step_size);
+}
+
+// Returns the transmittance from the given point straight down to the ground
+// at world-space height 0, through the part of the cloud volume below it.
+fn transmittance_to_ground(P_world: vec3<f32>, P_uvw: vec3<f32>) -> vec3<f32> {
+    if (P_world.y <= 0.0) {
+        return vec3(0.0);
+    }
+    return march_transmittance(P_uvw, vec3(0.0, -1.0, 0.0), P_world.y, GROUND_BOUNCE_STEP_COUNT);
+}
+
+// Returns the radiance of the baked global illumination at the given point,
+// averaged over all directions.
+//
+// An irradiance volume stores an ambient cube, which is a color for each of
+// the six axis directions. Looking up along both diagonals weighs each face
+// by 1/3, so their average is the average of all six faces.
+fn sample_ambient_light(P_world: vec3<f32>) -> vec3<f32> {
+#ifdef IRRADIANCE_VOLUME
+    let diagonal = vec3(0.5773502691896258);
+    return 0.5 * (irradiance_volume_light(P_world, diagonal) +
+        irradiance_volume_light(P_world, -diagonal));
+#else   // IRRADIANCE_VOLUME
+    return vec3(0.0);
+#endif  // IRRADIANCE_VOLUME
+}
+
+// Integrates the light that a single raymarching step contributes, given the
+// light scattered in or emitted per unit length (`source`), the extinction
+// coefficient, the step length, and the transmittance across the step.
+//
+// The analytic integrator [1] solves for the light that reaches the start of
+// the step exactly, assuming the source and extinction are constant across it.
+// Unlike a Riemann sum, this conserves energy however long the steps are, so
+// the step count mostly trades noise against cost rather than changing the
+// brightness, and dense media don't blow up.
+//
+// [1]: https://www.ea.com/frostbite/news/physically-based-unified-volumetric-rendering-in-frostbite
+fn integrate_step(
+    source: vec3<f32>,
+    extinction: vec3<f32>,
+    step_size: f32,
+    step_transmittance: vec3<f32>
+) -> vec3<f32> {
+#ifdef ANALYTIC_INTEGRATION
+    // Fall back to the limit, `source * step_size`, as the extinction goes to
+    // zero.
+    return select(
+        source * (1.0 - step_transmittance) / max(extinction, vec3(1e-7)),
+        source * step_size,
+        extinction < vec3(1e-7)
+    );
+#else   // ANALYTIC_INTEGRATION
+    // The classic Riemann sum, attenuated by the step itself.
+    return source * step_size * step_transmittance;
+#endif  // ANALYTIC_INTEGRATION
+}
+
+#ifdef ADAPTIVE_STEPS
+// Returns the length of the adaptive raymarching step that starts at a point
+// with the given extinction and distance from the camera, given how much of
+// the ray and of the step budget are left. See `CloudAdaptiveSteps` in
+// `mod.rs`.
+fn adaptive_step_size(
+    extinction: vec3<f32>,
+    camera_distance: f32,
+    remaining_length: f32,
+    remaining_steps: u32
+) -> f32 {
+    // Shorten the step as the medium thickens. In empty space, this is the
+    // longest step.
+    let optical_step_size =
+        MAX_STEP_OPTICAL_DEPTH / max(max(extinction.x, extinction.y), max(extinction.z, 1e-7));
+
+    // Don't shorten it further than the distance from the camera calls for,
+    // since detail is smaller on screen farther away, and keep it within the
+    // allowed lengths.
+    let distance_step_size = camera_distance * volumetric_fog.step_distance_factor;
+    let step_size = clamp(
+        max(optical_step_size, distance_step_size),
+        volumetric_fog.min_step_size,
+        volumetric_fog.max_step_size
+    );
+
+    // Lengthen the step, even beyond the longest, if the budget wouldn't reach
+    // the end of the ray otherwise, and don't overshoot it.
+    return min(max(step_size, remaining_length / f32(remaining_steps)), remaining_length);
+}
+#endif  // ADAPTIVE_STEPS
+
+// Returns the density of the medium at the given point in the local space of
+// the density texture.
+fn sample_density(P_uvw: vec3<f32>) -> f32 {
+    var density = volumetric_fog.density_factor;
+#ifdef DENSITY_TEXTURE
+    // Take the density texture into account, if there is one.
+    //
+    // The uvs should never go outside the (0, 0, 0) to (1, 1, 1) box, but
+    // sometimes due to floating point error they can. Handle this case.
+    if (all(P_uvw >= vec3(0.0)) && all(P_uvw <= vec3(1.0))) {
+        density *= textureSampleLevel(density_texture, density_sampler, P_uvw, 0.0).r;
+    } else {
+        density = 0.0;
+    }
+#endif  // DENSITY_TEXTURE
+    return density;
+}
+
+// Returns the luminance, in cd/m², that the medium emits at the given point.
+fn sample_emission(P_uvw: vec3<f32>) -> vec3<f32> {
+    var emission = volumetric_fog.emissive;
+#ifdef EMISSION_TEXTURE
+    let value = textureSampleLevel(emission_texture, emission_sampler, P_uvw, 0.0).r;
+#ifdef BLACKBODY
+    let temperature_range = volumetric_fog.temperature_range;
+    emission *= blackbody(mix(temperature_range.x, temperature_range.y, value));
+#else   // BLACKBODY
+    emission *= value;
+#endif  // BLACKBODY
+#endif  // EMISSION_TEXTURE
+    return emission;
+}
+
+// Returns the light from the lightning inside this volume that scatters toward
+// the viewer at the given point, per unit of scattering.
+//
+// Each bolt segment is treated as a line light that emits evenly in all
+// directions, for which the illuminance has a closed form [1]. The light is
+// attenuated on its way to the point as though the medium between the two had
+// the density `local_extinction` everywhere.
+//
+// [1]: https://www.iquilezles.org/www/articles/linefog/linefog.htm
+fn lightning_in_scattering(P_world: vec3<f32>, Rd_world: vec3<f32>, local_extinction: vec3<f32>)
+        -> vec3<f32> {
+    var in_scattered = vec3(0.0);
+    for (var segment_index = 0u;
+            segment_index < volumetric_fog.lightning_count;
+            segment_index += 1u) {
+        let segment = volumetric_fog.lightning[segment_index];
+        let axis = segment.end - segment.start;
+        let segment_length = max(length(axis), MIN_LIGHTNING_DISTANCE);
+        let tangent = axis / segment_length;
+
+        // Find the distance to the line through the segment, as well as the
+        // signed distances from the foot of the perpendicular to each end.
+        let along = dot(P_world - segment.start, tangent);
+        let distance = max(
+            length(P_world - (segment.start + tangent * along)),
+            MIN_LIGHTNING_DISTANCE
+        );
+        let illuminance = segment.intensity *
+            (atan((segment_length - along) / distance) - atan(-along / distance)) / distance;
+
+        // Attenuate by the medium between the point and the closest point on
+        // the segment, and apply the phase toward that point.
+        let to_light = segment.start + tangent * clamp(along, 0.0, segment_length) - P_world;
+        let light_distance = length(to_light);
+        let phase = henyey_greenstein(
+            dot(to_light / max(light_distance, 1e-6), Rd_world),
+            volumetric_fog.scattering_asymmetry
+        );
+
+        in_scattered += illuminance * exp(-local_extinction * light_distance) * phase;
+    }
+    return in_scattered;
+}
+
+// The lights of the cloud volume as seen along a single ray, which don't
+// change along it.
+struct RayLights {
+    // The phase of each directional light. It determines the fraction of light
+    // that's scattered toward the camera instead of away from it.
+    phases: array<f32, #{MAX_DIRECTIONAL_LIGHTS}u>,
+    // Whether each directional light lights the volume.
+    enabled: array<bool, #{MAX_DIRECTIONAL_LIGHTS}u>,
+    // Whether there's emission, lightning, or baked ambient light to gather.
+    march_ambient: bool,
+}
+
+// Finds the lights of the cloud volume along the given world-space ray.
+fn find_ray_lights(Rd_world: vec3<f32>) -> RayLights {
+    // Emission, lightning, and baked ambient light don't depend on the
+    // directional lights, so skip them where there's none.
+    var lights_along_ray: RayLights;
+    lights_along_ray.march_ambient = any(volumetric_fog.emissive > vec3(0.0)) ||
+        volumetric_fog.lightning_count > 0u;
+#ifdef IRRADIANCE_VOLUME
+    lights_along_ray.march_ambient = true;
+#endif  // IRRADIANCE_VOLUME
+
+    // Work out the phase of each directional light that lights the volume.
+    for (var light_index = 0u; light_index < lights.n_directional_lights; light_index += 1u) {
+        // Skip lights without a `VolumetricCloudLight`, as well as those that
+        // Bevy marks with `skip` because the view doesn't share their render
+        // layers.
+        let light = &lights.directional_lights[light_index];
+        let cloud_light = &volumetric_fog.cloud_lights[light_index];
+        lights_along_ray.enabled[light_index] =
+            ((*cloud_light).flags & VOLUMETRIC_CLOUD_LIGHT_FLAGS_ENABLED) != 0u &&
+            (*light).skip == 0u;
+        if (!lights_along_ray.enabled[light_index]) {
+            continue;
+        }
+
+        let neg_LdotV = dot(normalize((*light).direction_to_light.xyz), Rd_world);
+        var scattering_asymmetry = volumetric_fog.scattering_asymmetry;
+        if (((*cloud_light).flags & VOLUMETRIC_CLOUD_LIGHT_FLAGS_SCATTERING_ASYMMETRY) != 0u) {
+            scattering_asymmetry = (*cloud_light).scattering_asymmetry;
+        }
+        lights_along_ray.phases[light_index] = henyey_greenstein(neg_LdotV, scattering_asymmetry);
+    }
+
+    return lights_along_ray;
+}
+
+// Returns the light that the medium of the cloud volume scatters toward the
+// camera or emits, per unit length, at a point with the given density. This is
+// the in-scattering (amount of light other fog particles scattered into this
+// ray), along with emission.
+fn sample_source(
+    density: f32,
+    P_world: vec3<f32>,
+    P_uvw: vec3<f32>,
+    Rd_world: vec3<f32>,
+    lights_along_ray: ptr<function, RayLights>
+) -> vec3<f32> {
+    // Unpack the `volumetric_fog` settings.
     let fog_color = volumetric_fog.fog_color;
-    let ambient_color = volumetric_fog.ambient_color;
-    let ambient_intensity = volumetric_fog.ambient_intensity;
-    let step_count = volumetric_fog.step_count;
     let bounding_radius = volumetric_fog.bounding_radius;
     let absorption = volumetric_fog.absorption;
     let scattering = volumetric_fog.scattering;
-    let density_factor = volumetric_fog.density_factor;
     let light_tint = volumetric_fog.light_tint;
     let light_intensity = volumetric_fog.light_intensity;
-    let jitter_strength = volumetric_fog.jitter_strength;
-
-    // Unpack the view.
     let exposure = view.exposure;
+    let extinction = density * (absorption + scattering);
+
+
+    var source = vec3(0.0);
+
+    if ((*lights_along_ray).march_ambient) {
+        // The medium emits in proportion to how much it absorbs, so that
+        // a thick medium converges to the emissive luminance times the
+        // fraction of its extinction that's absorption.
+        let emitted = sample_emission(P_uvw) * absorption;
+
+        // Lightning is scattered in just like any other light.
+        let lightning = fog_color * light_tint * light_intensity * scattering *
+            lightning_in_scattering(P_world, Rd_world, extinction);
+
+        // The baked global illumination is scattered in from every
+        // direction, so the phase function averages out.
+        let ambient = fog_color * scattering * sample_ambient_light(P_world);
+
+        source += (emitted + lightning + ambient) * density * exposure;
+    }
+
+    for (var light_index = 0u; light_index < lights.n_directional_lights; light_index += 1u) {
+        // Skip the lights that don't light the volume.
+        if (!(*lights_along_ray).enabled[light_index]) {
+            continue;
+        }
+        let light = &lights.directional_lights[light_index];
+        let cloud_light = &volumetric_fog.cloud_lights[light_index];
+        let phase = (*lights_along_ray).phases[light_index];
+        let cloud_light_tint = (*cloud_light).tint;
+        let light_mu = normalize((*light).direction_to_light.xyz).y;
+
+        // Sample the shadow map to determine whether, and by how much,
+        // this sample is in the light, if the light has one and it's
+        // wanted. The cascade is chosen by the view depth of the sample
+        // itself, and neighboring cascades are blended where they
+        // overlap, exactly as for meshes. Clouds have no surface normal,
+        // so there's no normal bias. The shadow map is filtered with the
+        // view's `ShadowFilteringMethod`.
+        var local_light_attenuation = 1.0;
+        if (((*cloud_light).flags & VOLUMETRIC_CLOUD_LIGHT_FLAGS_SHADOW_MAP) != 0u &&
+                ((*light).flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            let view_z = (view.view_from_world * vec4(P_world, 1.0)).z;
+            local_light_attenuation =
+                fetch_directional_shadow(light_index, vec4(P_world, 1.0), vec3(0.0), view_z);
+        }
+
+        if (local_light_attenuation != 0.0) {
+            // Either march toward the light, or assume that the medium is
+            // as dense as it is here all the way to the boundary.
+            var light_attenuation: vec3<f32>;
+            if ((*cloud_light).step_count == 0u) {
+                light_attenuation = exp(-density * bounding_radius * (absorption + scattering));
+            } else {
+                light_attenuation = march_transmittance(
+                    P_uvw,
+                    normalize((*light).direction_to_light.xyz),
+                    2.0 * bounding_radius,
+                    (*cloud_light).step_count
+                );
+            }
+
+            let light_factors = fog_color * light_tint * cloud_light_tint * light_attenuation *
+                scattering * density * light_intensity * exposure;
+
+            // The light's color is what reaches the ground at the origin
+            // of the scene. For the sun, use the light above the
+            // atmosphere instead, filtered by the atmosphere down to this
+            // sample, so that high clouds stay lit after the sun has set
+            // at the ground.
+            var light_color = (*light).color.rgb;
+            if (((*cloud_light).flags & VOLUMETRIC_CLOUD_LIGHT_FLAGS_SUN) != 0u) {
+                light_color = (*cloud_light).top_of_atmosphere * atmosphere_transmittance(
+                    volumetric_fog.scene_altitude + P_world.y,
+                    light_mu
+                );
+            }
+
+            // Modulate the factor we calculated above by the phase, fog
+            // color, light color, light tint.
+            source += light_color * phase * light_factors * local_light_attenuation;
+        }
+
+        // Add the light that the ground reflects back up. The ground is a
+        // Lambertian reflector lit by the light as it arrives at the
+        // origin of the scene, ignoring the shadows of the clouds on it.
+        // Seen from the sample, it fills the lower hemisphere, so the
+        // phase function gathers about half of its radiance.
+        if (any(volumetric_fog.ground_albedo > vec3(0.0)) && light_mu > 0.0) {
+            let ground_radiance = volumetric_fog.ground_albedo * (*light).color.rgb * light_mu *
+                FRAC_1_PI;
+            source += 0.5 * ground_radiance * transmittance_to_ground(P_world, P_uvw) *
+                fog_color * light_tint * cloud_light_tint * scattering * density *
+                light_intensity * exposure;
+        }
+    }
+
+    return source;
+}
+
+// Applies the camera's distance fog to a single color at the given distance
+// from the camera, along the given direction.
+//
+// This mirrors `apply_fog` in `bevy_pbr::pbr_functions`, which we can't import
+// here because it drags in the material bindings.
+fn apply_distance_fog(input_color: vec3<f32>, distance: f32, Rd_world: vec3<f32>) -> vec3<f32> {
+    var scattering = vec3(0.0);
+    if (fog.directional_light_color.a > 0.0) {
+        for (var light_index = 0u; light_index < lights.n_directional_lights; light_index += 1u) {
+            let light = &lights.directional_lights[light_index];
+            scattering += pow(
+                max(dot(Rd_world, (*light).direction_to_light), 0.0),
+                fog.directional_light_exponent
+            ) * (*light).color.rgb * view.exposure;
+        }
+    }
+
+    let color = vec4(input_color, 1.0);
+    if (fog.mode == FOG_MODE_LINEAR) {
+        return linear_fog(fog, color, distance, scattering).rgb;
+    } else if (fog.mode == FOG_MODE_EXPONENTIAL) {
+        return exponential_fog(fog, color, distance, scattering).rgb;
+    } else if (fog.mode == FOG_MODE_EXPONENTIAL_SQUARED) {
+        return exponential_squared_fog(fog, color, distance, scattering).rgb;
+    } else if (fog.mode == FOG_MODE_ATMOSPHERIC) {
+        return atmospheric_fog(fog, color, distance, scattering).rgb;
+    }
+    return input_color;
+}
+
+// Applies the camera's distance fog to the light that the cloud contributes,
+// as though the whole cloud sat at `distance`.
+//
+// The background behind the cloud has already been fogged by the main pass, so
+// only the part of the fog that the cloud hides needs to be added back in: the
+// cloud's own light is attenuated by the fog, and the fog in front of the cloud
+// scatters in over the cloud's coverage.
+fn fog_cloud(
+    accumulated_color: vec3<f32>,
+    transmittance: vec3<f32>,
+    distance: f32,
+    Rd_world: vec3<f32>
+) -> vec3<f32> {
+    // All of Bevy's fog modes are affine in the input color, so evaluating them
+    // for black and white separates the fog's in-scattering from its
+    // transmittance.
+    let fog_in_scattering = apply_distance_fog(vec3(0.0), distance, Rd_world);
+    let fog_transmittance = apply_distance_fog(vec3(1.0), distance, Rd_world) - fog_in_scattering;
+    return accumulated_color * fog_transmittance + fog_in_scattering * (1.0 - transmittance);
+}
+
+// The order in which the pixels of each 4×4 block are raymarched, for
+// `CloudUpdatePattern::Bayer4x4`. The top left 2×2 corner, divided by 4, is the
+// order for `CloudUpdatePattern::Bayer2x2`.
+const BAYER_4X4: array<u32, 16> = array<u32, 16>(
+    0u, 8u, 2u, 10u,
+    12u, 4u, 14u, 6u,
+    3u, 11u, 1u, 9u,
+    15u, 7u, 13u, 5u,
+);
+
+// Returns true if the given pixel of the target is raymarched in this frame,
+// given the size of the blocks of the update pattern. The other pixels are
+// filled in from the history. This is the same as in `cloud_temporal.wgsl`.
+fn is_updated_this_frame(pixel: vec2<u32>, pattern_size: u32) -> bool {
+    let cell = pixel % pattern_size;
+    let pixel_count = pattern_size * pattern_size;
+    var bayer = BAYER_4X4;
+    return bayer[cell.y * 4u + cell.x] / (16u / pixel_count) == globals.frame_count % pixel_count;
+}
+
+// The output of the fragment shader.
+//
+// When dual-source blending is available, the blender multiplies the
+// background by the transmittance of each color channel separately. Otherwise,
+// the transmittance is averaged into the alpha channel, and colored extinction
+// only shows in the in-scattered light.
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef DUAL_SOURCE_BLENDING
+    @location(0) @second_blend_source transmittance: vec4<f32>,
+#else ifdef OFFSCREEN
+    @location(1) transmittance: vec4<f32>,
+#endif  // DUAL_SOURCE_BLENDING
+#ifdef TEMPORAL
+    @location(2) motion: vec4<f32>,
+#endif  // TEMPORAL
+}
 
-    if true {
-        return vec4(1.0, 0.0, 0.0, 0.0);
+@fragment
+fn fragment(@builtin(position) position: vec4<f32>) -> FragmentOutput {
+    // With an update pattern, skip the pixels that aren't due this frame. They
+    // keep the cleared, empty values.
+    if (!is_updated_this_frame(vec2<u32>(position.xy), volumetric_fog.update_pattern_size)) {
+        discard;
     }
 
+    return raymarch(position, RaySegment(MAX_VIEW_DEPTH, volumetric_fog.step_count, 1.0));
+}
+
+// The part of the ray through a pixel that `raymarch` marches.
+struct RaySegment {
+    // The view-space depth at which the segment ends. The ray also stops at
+    // the back of the volume and at the scene, whichever comes first.
+    end_depth_view: f32,
+    // The number of steps to take along the segment.
+    step_count: u32,
+    // The fraction of the whole ray through the volume that the segment
+    // covers, which the ambient light is spread over.
+    fraction: f32,
+}
+
+// Raymarches the cloud volume along the ray through the given pixel of the
+// target, starting at the NDC depth in `position.z`, which is where the ray
+// enters the volume unless only a segment of it is marched. This is shared by
+// the raster and compute backends.
+fn raymarch(position: vec4<f32>, segment: RaySegment) -> FragmentOutput {
+    // Unpack the `volumetric_fog` settings.
+    let uvw_from_world = volumetric_fog.uvw_from_world;
+    let ambient_color = volumetric_fog.ambient_color;
+    let ambient_intensity = volumetric_fog.ambient_intensity;
+    let step_count = segment.step_count;
+    let absorption = volumetric_fog.absorption;
+    let scattering = volumetric_fog.scattering;
+    let jitter_strength = volumetric_fog.jitter_strength;
+
     // Sample the depth to put an upper bound on the length of the ray (as we
     // shouldn't trace through solid objects). If this is multisample, just use
     // sample 0; this is approximate but good enough.
-    let frag_coord = position;
+    //
+    // Below full resolution, map the pixel back to the coordinates of the view,
+    // so that the ray and the depth are those of the matching full-resolution
+    // pixel.
+    let frag_coord = vec4(position.xy / volumetric_fog.resolution_scale, position.zw);
     let ndc_end_depth_from_buffer = textureLoad(depth_texture, vec2<i32>(frag_coord.xy), 0);
     let view_end_depth_from_buffer = -position_ndc_to_view(
-        frag_coord_to_ndc(vec4(position.xy, ndc_end_depth_from_buffer, 1.0))).z;
+        frag_coord_to_ndc(vec4(frag_coord.xy, ndc_end_depth_from_buffer, 1.0))).z;
 
     // Calculate the start position of the ray. Since we're only rendering front
     // faces of the AABB, this is the current fragment's depth.
@@ -157,7 +832,7 @@ fn fragment(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
 
     // Starting at the end depth, which we got above, figure out how long the
     // ray we want to trace is and the length of each increment.
-    end_depth_view = min(end_depth_view, view_end_depth_from_buffer);
+    end_depth_view = min(min(end_depth_view, view_end_depth_from_buffer), segment.end_depth_view);
 
     // We assume world and view have the same scale here.
     let start_depth_view = -depth_ndc_to_view_z(frag_coord.z);
@@ -165,12 +840,10 @@ fn fragment(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
     let inv_step_count = 1.0 / f32(step_count);
     let step_size_world = ray_length_view * inv_step_count;
 
-    let directional_light_count = lights.n_directional_lights;
-
-    // Calculate the ray origin (`Ro`) and the ray direction (`Rd`) in NDC,
-    // view, and world coordinates.
-    let Rd_ndc = vec3(frag_coord_to_ndc(position).xy, 1.0);
-    let Rd_view = normalize(position_ndc_to_view(Rd_ndc));
+    // Calculate the ray origin (`Ro`) and the ray direction (`Rd`) in NDC
+    // and world coordinates. Positions along the ray are transformed back to
+    // view space as needed, since the ray doesn't start at the camera.
+    let Rd_ndc = vec3(frag_coord_to_ndc(frag_coord).xy, 1.0);
     var Ro_world = position_view_to_world(view_start_pos.xyz);
     let Rd_world = normalize(position_ndc_to_world(Rd_ndc) - view.world_position);
 
@@ -188,115 +861,501 @@ fn fragment(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
     // [2]: https://en.wikipedia.org/wiki/Beer%E2%80%93Lambert_law
 
     // Use Beer's law again to accumulate the ambient light all along the path.
-    var accumulated_color = exp(-ray_length_view * (absorption + scattering)) * ambient_color *
-        ambient_intensity;
+    // A segment gets its share of that of the whole ray.
+    var accumulated_color = exp(-ray_length_view / segment.fraction * (absorption + scattering)) *
+        ambient_color * ambient_intensity * segment.fraction;
 
-    // This is the amount of the background that shows through. We're actually
-    // going to recompute this over and over again for each directional light,
-    // coming up with the same values each time.
-    var background_alpha = 1.0;
+    // This is the amount of the background that shows through, per color
+    // channel.
+    var transmittance = vec3(1.0);
 
-    // If we have a density texture, transform to its local space.
-#ifdef DENSITY_TEXTURE
+    // To apply distance fog, we average the distance from the camera to each
+    // sample, weighted by how much of the background that sample hides.
+    var fog_distance_sum = 0.0;
+    var fog_weight_sum = 0.0;
+
+    // Transform the ray to the local space of the density and emission
+    // textures.
     let Ro_uvw = (uvw_from_world * vec4(Ro_world, 1.0)).xyz;
-    let Rd_step_uvw = mat3x3(uvw_from_world[0].xyz, uvw_from_world[1].xyz, uvw_from_world[2].xyz) *
-        (Rd_world * step_size_world);
-#endif  // DENSITY_TEXTURE
+    let Rd_uvw = mat3x3(uvw_from_world[0].xyz, uvw_from_world[1].xyz, uvw_from_world[2].xyz) *
+        Rd_world;
 
-    for (var light_index = 0u; light_index < directional_light_count; light_index += 1u) {
-        // Volumetric lights are all sorted first, so the first time we come to
-        // a non-volumetric light, we know we've seen them all.
-        let light = &lights.directional_lights[light_index];
-        if (((*light).flags & DIRECTIONAL_LIGHT_FLAGS_VOLUMETRIC_BIT) == 0) {
+    // Work out which lights light the volume, and how much of their light is
+    // scattered toward the camera.
+    var lights_along_ray = find_ray_lights(Rd_world);
+
+    // Start raymarching. The density is sampled once per step, and the light
+    // that every source scatters in there is accumulated together. With
+    // adaptive steps, `step_count` is the budget, and the march ends early
+    // when it reaches the end of the ray.
+    var ray_distance = 0.0;
+    for (var step = 0u; step < step_count; step += 1u) {
+        // As an optimization, break if we've gotten too dark.
+        if (all(transmittance < vec3(0.001))) {
             break;
         }
 
-        // Offset the depth value by the bias.
-        let depth_offset = (*light).shadow_depth_bias * (*light).direction_to_light.xyz;
+        // Calculate where we are in the ray.
+#ifdef ADAPTIVE_STEPS
+        if (ray_distance >= ray_length_view) {
+            break;
+        }
+#else   // ADAPTIVE_STEPS
+        ray_distance = f32(step) * step_size_world;
+#endif  // ADAPTIVE_STEPS
+        let P_world = Ro_world + Rd_world * ray_distance;
+        let P_uvw = Ro_uvw + Rd_uvw * ray_distance;
+        let density = sample_density(P_uvw);
 
-        // Compute phase, which determines the fraction of light that's
-        // scattered toward the camera instead of away from it.
-        let neg_LdotV = dot(normalize((*light).direction_to_light.xyz), Rd_world);
-        let phase = henyey_greenstein(neg_LdotV);
+        // Size the step from the density where it starts.
+#ifdef ADAPTIVE_STEPS
+        let step_size = adaptive_step_size(
+            density * (absorption + scattering),
+            distance(P_world, view.world_position),
+            ray_length_view - ray_distance,
+            step_count - step
+        );
+        ray_distance += step_size;
+#else   // ADAPTIVE_STEPS
+        let step_size = step_size_world;
+#endif  // ADAPTIVE_STEPS
+
+        if (density == 0.0) {
+            continue;
+        }
+
+        // Calculate absorption (amount of light absorbed by the fog) and
+        // out-scattering (amount of light the fog scattered away).
+        let extinction = density * (absorption + scattering);
+        let sample_attenuation = exp(-step_size * extinction);
+
+        // Compute in-scattering (amount of light other fog particles
+        // scattered into this ray), along with emission, as the light that
+        // this step sources.
+        let source = sample_source(density, P_world, P_uvw, Rd_world, &lights_along_ray);
+
+        // Accumulate the light.
+        accumulated_color += integrate_step(source, extinction, step_size, sample_attenuation) *
+            transmittance;
+
+        let fog_weight = dot(transmittance * (1.0 - sample_attenuation), vec3(1.0 / 3.0));
+        fog_distance_sum += distance(P_world, view.world_position) * fog_weight;
+        fog_weight_sum += fog_weight;
+
+        // Process absorption and out-scattering.
+        transmittance *= sample_attenuation;
+    }
+
+    // Fade the cloud into the camera's distance fog at its average depth. If
+    // the ray never hit anything, fall back to the start of the ray.
+    var fog_distance = distance(Ro_world, view.world_position);
+    if (fog_weight_sum > 0.0) {
+        fog_distance = fog_distance_sum / fog_weight_sum;
+    }
+    accumulated_color = fog_cloud(accumulated_color, transmittance, fog_distance, Rd_world);
+
+    // We're done! Return the color along with the transmittance so it can be
+    // blended onto the render target.
+    var output: FragmentOutput;
+    output.color = vec4(accumulated_color, 1.0 - dot(transmittance, vec3(1.0 / 3.0)));
+#ifdef DUAL_SOURCE_BLENDING
+    output.transmittance = vec4(transmittance, 1.0);
+#else ifdef OFFSCREEN
+    output.transmittance = vec4(transmittance, 1.0);
+#endif  // DUAL_SOURCE_BLENDING
+
+#ifdef TEMPORAL
+    // Find where the clouds along this ray were on screen in the previous
+    // frame, at their average depth, so that the history can follow them.
+    // This is weighted by opacity, like the color.
+    let P_cloud = view.world_position + Rd_world * fog_distance;
+    let previous_clip = volumetric_fog.previous_clip_from_world * vec4(P_cloud, 1.0);
+    let motion = frag_coord_to_uv(frag_coord.xy) - ndc_to_uv(previous_clip.xy / previous_clip.w);
+    output.motion = vec4(motion, 0.0, 1.0) * output.color.a;
+#endif  // TEMPORAL
+    return output;
+}
+
+#ifdef COMPUTE
+// Returns the distances along the given world-space ray at which it enters
+// and leaves the cloud volume. The entry is 0 if the ray starts inside the
+// volume, and the exit is before the entry if the ray misses it.
+fn ray_volume_interval(Ro_world: vec3<f32>, Rd_world: vec3<f32>) -> vec2<f32> {
+    // Intersect the ray with the slabs of the unit cube in UVW space. Axes that
+    // the ray runs parallel to never bound it.
+    let uvw_from_world = volumetric_fog.uvw_from_world;
+    let Ro_uvw = (uvw_from_world * vec4(Ro_world, 1.0)).xyz;
+    var Rd_uvw = mat3x3(uvw_from_world[0].xyz, uvw_from_world[1].xyz, uvw_from_world[2].xyz) *
+        Rd_world;
+    Rd_uvw = select(Rd_uvw, vec3(1e-8), abs(Rd_uvw) < vec3(1e-8));
+
+    let t_a = -Ro_uvw / Rd_uvw;
+    let t_b = (vec3(1.0) - Ro_uvw) / Rd_uvw;
+    let t_near = min(t_a, t_b);
+    let t_far = max(t_a, t_b);
+    let t_enter = max(max(t_near.x, t_near.y), max(t_near.z, 0.0));
+    let t_exit = min(min(t_far.x, t_far.y), t_far.z);
+    return vec2(t_enter, t_exit);
+}
+
+// Raymarches the slice of the ray through the given pixel of the target
+// between the view-space depths in `slice`, where several cloud volumes
+// overlap.
+//
+// Each step sums the extinction and the light of every volume there, so the
+// volumes dim each other as a single medium would. The slice takes as many
+// steps as the volume that would take the most over it. With adaptive steps,
+// that's the budget, and each step is sized from the summed extinction. Each
+// volume still only casts shadows on itself.
+fn raymarch_overlap(
+    pixel: vec2<u32>,
+    Rd_world: vec3<f32>,
+    depth_per_distance: f32,
+    slice: vec2<f32>,
+    pixel_volumes: ptr<function, array<u32, MAX_PIXEL_CLOUD_VOLUMES>>,
+    pixel_intervals: ptr<function, array<vec2<f32>, MAX_PIXEL_CLOUD_VOLUMES>>,
+    pixel_volume_count: u32
+) -> FragmentOutput {
+    // Gather the volumes that cover the slice, and find the lights along the
+    // ray for each. Like `raymarch`, each adds its share of the ambient light
+    // of the whole ray through it.
+    var volumes: array<u32, MAX_PIXEL_CLOUD_VOLUMES>;
+    var volume_lights: array<RayLights, MAX_PIXEL_CLOUD_VOLUMES>;
+    var volume_count = 0u;
+    var step_count = 1u;
+    var accumulated_color = vec3(0.0);
+    for (var volume = 0u; volume < pixel_volume_count; volume += 1u) {
+        let interval = (*pixel_intervals)[volume];
+        if (interval.x > slice.x || interval.y <= slice.x) {
+            continue;
+        }
+        volumetric_fog = cloud_volumes[(*pixel_volumes)[volume]];
+        volumes[volume_count] = (*pixel_volumes)[volume];
+        volume_lights[volume_count] = find_ray_lights(Rd_world);
+        volume_count += 1u;
+
+        let fraction = (slice.y - slice.x) / (interval.y - interval.x);
+        step_count = max(step_count, u32(ceil(f32(volumetric_fog.step_count) * fraction)));
+        accumulated_color += exp(-(interval.y - interval.x) *
+            (volumetric_fog.absorption + volumetric_fog.scattering)) *
+            volumetric_fog.ambient_color * volumetric_fog.ambient_intensity * fraction;
+    }
+
+    let frag_coord = (vec2<f32>(pixel) + 0.5) / volumetric_fog.resolution_scale;
+    let jitter = interleaved_gradient_noise(vec2<f32>(pixel) + 0.5, globals.frame_count) *
+        volumetric_fog.jitter_strength;
+    let Ro_world = view.world_position + Rd_world * (slice.x / depth_per_distance + jitter);
+    let slice_length = (slice.y - slice.x) / depth_per_distance;
+    let step_size_world = slice_length / f32(step_count);
+
+    var transmittance = vec3(1.0);
 
-        // Reset `background_alpha` for a new raymarch.
-        background_alpha = 1.0;
+    // Each sample's weight for the distance fog is shared among the volumes
+    // by their extinction there, so that each can follow its own motion.
+    var volume_weights: array<f32, MAX_PIXEL_CLOUD_VOLUMES>;
+    var volume_distance_sums: array<f32, MAX_PIXEL_CLOUD_VOLUMES>;
 
-        // Start raymarching.
-        for (var step = 0u; step < step_count; step += 1u) {
-            // As an optimization, break if we've gotten too dark.
-            if (background_alpha < 0.001) {
-                break;
+    var ray_distance = 0.0;
+    for (var step = 0u; step < step_count; step += 1u) {
+        // As an optimization, break if we've gotten too dark.
+        if (all(transmittance < vec3(0.001))) {
+            break;
+        }
+
+#ifdef ADAPTIVE_STEPS
+        if (ray_distance >= slice_length) {
+            break;
+        }
+#else   // ADAPTIVE_STEPS
+        ray_distance = f32(step) * step_size_world;
+#endif  // ADAPTIVE_STEPS
+        let P_world = Ro_world + Rd_world * ray_distance;
+
+        // Sum the extinction and the light of all the volumes here.
+        var extinction = vec3(0.0);
+        var source = vec3(0.0);
+        var volume_extinctions: array<f32, MAX_PIXEL_CLOUD_VOLUMES>;
+        for (var volume = 0u; volume < volume_count; volume += 1u) {
+            volumetric_fog = cloud_volumes[volumes[volume]];
+            let P_uvw = (volumetric_fog.uvw_from_world * vec4(P_world, 1.0)).xyz;
+            let density = sample_density(P_uvw);
+            if (density == 0.0) {
+                continue;
             }
 
-            // Calculate where we are in the ray.
-            let P_world = Ro_world + Rd_world * f32(step) * step_size_world;
-            let P_view = Rd_view * f32(step) * step_size_world;
+            let volume_extinction =
+                density * (volumetric_fog.absorption + volumetric_fog.scattering);
+            source += sample_source(density, P_world, P_uvw, Rd_world, &volume_lights[volume]);
+            extinction += volume_extinction;
+            volume_extinctions[volume] = dot(volume_extinction, vec3(1.0 / 3.0));
+        }
 
-            var density = density_factor;
-#ifdef DENSITY_TEXTURE
-            // Take the density texture into account, if there is one.
-            //
-            // The uvs should never go outside the (0, 0, 0) to (1, 1, 1) box,
-            // but sometimes due to floating point error they can. Handle this
-            // case.
-            let P_uvw = Ro_uvw + Rd_step_uvw * f32(step);
-            if (all(P_uvw >= vec3(0.0)) && all(P_uvw <= vec3(1.0))) {
-                density *= textureSample(density_texture, density_sampler, P_uvw).r;
-            } else {
-                density = 0.0;
+        // Size the step from the summed extinction where it starts.
+#ifdef ADAPTIVE_STEPS
+        let step_size = adaptive_step_size(
+            extinction,
+            distance(P_world, view.world_position),
+            slice_length - ray_distance,
+            step_count - step
+        );
+        ray_distance += step_size;
+#else   // ADAPTIVE_STEPS
+        let step_size = step_size_world;
+#endif  // ADAPTIVE_STEPS
+
+        // Accumulate the light.
+        let sample_attenuation = exp(-step_size * extinction);
+        accumulated_color += integrate_step(source, extinction, step_size, sample_attenuation) *
+            transmittance;
+
+        let fog_weight = dot(transmittance * (1.0 - sample_attenuation), vec3(1.0 / 3.0));
+        let total_extinction = dot(extinction, vec3(1.0 / 3.0));
+        if (total_extinction > 0.0) {
+            let sample_distance = distance(P_world, view.world_position);
+            for (var volume = 0u; volume < volume_count; volume += 1u) {
+                let volume_weight = fog_weight * volume_extinctions[volume] / total_extinction;
+                volume_weights[volume] += volume_weight;
+                volume_distance_sums[volume] += sample_distance * volume_weight;
             }
-#endif  // DENSITY_TEXTURE
+        }
 
-            // Calculate absorption (amount of light absorbed by the fog) and
-            // out-scattering (amount of light the fog scattered away).
-            let sample_attenuation = exp(-step_size_world * density * (absorption + scattering));
+        // Process absorption and out-scattering.
+        transmittance *= sample_attenuation;
+    }
 
-            // Process absorption and out-scattering.
-            background_alpha *= sample_attenuation;
+    // Fade the slice into the camera's distance fog at its average depth, as
+    // `raymarch` does.
+    var fog_distance_sum = 0.0;
+    var fog_weight_sum = 0.0;
+    for (var volume = 0u; volume < volume_count; volume += 1u) {
+        fog_distance_sum += volume_distance_sums[volume];
+        fog_weight_sum += volume_weights[volume];
+    }
+    var fog_distance = distance(Ro_world, view.world_position);
+    if (fog_weight_sum > 0.0) {
+        fog_distance = fog_distance_sum / fog_weight_sum;
+    }
+    accumulated_color = fog_cloud(accumulated_color, transmittance, fog_distance, Rd_world);
 
-            // Compute in-scattering (amount of light other fog particles
-            // scattered into this ray). This is where any directional light is
-            // scattered in.
+    var output: FragmentOutput;
+    output.color = vec4(accumulated_color, 1.0 - dot(transmittance, vec3(1.0 / 3.0)));
+    output.transmittance = vec4(transmittance, 1.0);
 
-            // Prepare to sample the shadow map.
-            let cascade_index = get_cascade_index(light_index, P_view.z);
-            let light_local = world_to_directional_light_local(
-                light_index,
-                cascade_index,
-                vec4(P_world + depth_offset, 1.0)
-            );
+#ifdef TEMPORAL
+    // Average the motion of the volumes, each at its own average depth, by
+    // their weights.
+    var motion = vec2(0.0);
+    for (var volume = 0u; volume < volume_count; volume += 1u) {
+        if (volume_weights[volume] == 0.0) {
+            continue;
+        }
+        volumetric_fog = cloud_volumes[volumes[volume]];
+        let P_cloud = view.world_position +
+            Rd_world * (volume_distance_sums[volume] / volume_weights[volume]);
+        let previous_clip = volumetric_fog.previous_clip_from_world * vec4(P_cloud, 1.0);
+        motion += (frag_coord_to_uv(frag_coord) - ndc_to_uv(previous_clip.xy / previous_clip.w)) *
+            volume_weights[volume];
+    }
+    if (fog_weight_sum > 0.0) {
+        motion /= fog_weight_sum;
+    }
+    output.motion = vec4(motion, 0.0, 1.0) * output.color.a;
+#endif  // TEMPORAL
+    return output;
+}
 
-            // If we're outside the shadow map entirely, local light attenuation
-            // is zero.
-            var local_light_attenuation = f32(light_local.w != 0.0);
-
-            // Otherwise, sample the shadow map to determine whether, and by how
-            // much, this sample is in the light.
-            if (local_light_attenuation != 0.0) {
-                let cascade = &(*light).cascades[cascade_index];
-                let array_index = i32((*light).depth_texture_base_index + cascade_index);
-                local_light_attenuation =
-                    sample_shadow_map_hardware(light_local.xy, light_local.z, array_index);
-            }
+// The compute backend, which raymarches one 8×8 tile of the offscreen targets
+// per workgroup.
+//
+// Each pixel gathers the volumes in its tile's list that its ray passes
+// through, then cuts the ray into slices wherever it enters or leaves one of
+// them, and marches the slices front to back. Where volumes overlap, they're
+// marched together as one medium, so that interpenetrating volumes dim each
+// other. The whole tile stops at the first volume that the scene is
+// closer than everywhere in it, since all the volumes after it are farther
+// still.
+@compute @workgroup_size(8, 8, 1)
+fn compute(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let tile_range = tile_ranges[workgroup_id.y * num_workgroups.x + workgroup_id.x];
+    if (tile_range.y == 0u) {
+        return;
+    }
+
+    // The settings of the view are the same in all the volumes.
+    volumetric_fog = cloud_volumes[tile_volumes[tile_range.x]];
+
+    let pixel = global_id.xy;
+    let in_bounds = all(pixel < textureDimensions(cloud_color));
+
+    // Like the raster backend, use the full-resolution pixel at the center of
+    // the texel.
+    let frag_coord = (vec2<f32>(pixel) + 0.5) / volumetric_fog.resolution_scale;
+
+    // Find the farthest depth of the scene in this tile. A depth of zero is
+    // the far plane at infinity, so clamp it to a large but finite distance.
+    if (local_index == 0u) {
+        atomicStore(&tile_max_scene_depth, 0u);
+    }
+    workgroupBarrier();
+    var scene_depth = 0.0;
+    if (in_bounds) {
+        let ndc_depth = textureLoad(depth_texture, vec2<i32>(frag_coord), 0);
+        scene_depth = -depth_ndc_to_view_z(max(ndc_depth, 1e-7));
+        atomicMax(&tile_max_scene_depth, bitcast<u32>(scene_depth));
+    }
+    workgroupBarrier();
+    let tile_max_depth = bitcast<f32>(atomicLoad(&tile_max_scene_depth));
+
+    if (!in_bounds || !is_updated_this_frame(pixel, volumetric_fog.update_pattern_size)) {
+        return;
+    }
+
+    let Rd_ndc = vec3(frag_coord_to_ndc(vec4(frag_coord, 0.0, 1.0)).xy, 1.0);
+    let Rd_world = normalize(position_ndc_to_world(Rd_ndc) - view.world_position);
+
+    // The view-space depth along the ray grows at this rate per unit of
+    // distance. Rays start no closer than the near plane.
+    let depth_per_distance = dot(Rd_world, normalize(-view.world_from_view[2].xyz));
+    let near_depth = -depth_ndc_to_view_z(1.0);
+
+    // Find the stretch of the ray, in view-space depth, that each volume
+    // covers in front of the scene.
+    var pixel_volumes: array<u32, MAX_PIXEL_CLOUD_VOLUMES>;
+    var pixel_intervals: array<vec2<f32>, MAX_PIXEL_CLOUD_VOLUMES>;
+    var pixel_volume_count = 0u;
+    for (var list_index = 0u; list_index < tile_range.y; list_index += 1u) {
+        let volume_index = tile_volumes[tile_range.x + list_index];
+        volumetric_fog = cloud_volumes[volume_index];
+
+        // If the scene hides this volume all over the tile, it hides the ones
+        // behind it too.
+        if (volumetric_fog.nearest_view_depth > tile_max_depth ||
+                pixel_volume_count == MAX_PIXEL_CLOUD_VOLUMES) {
+            break;
+        }
+
+        let distances = ray_volume_interval(view.world_position, Rd_world);
+        let interval = vec2(
+            max(distances.x * depth_per_distance, near_depth),
+            min(distances.y * depth_per_distance, scene_depth)
+        );
+        if (interval.x >= interval.y) {
+            continue;
+        }
 
-            if (local_light_attenuation != 0.0) {
-                let light_attenuation = exp(-density * bounding_radius * (absorption + scattering));
-                let light_factors_per_step = fog_color * light_tint * light_attenuation *
-                    scattering * density * step_size_world * light_intensity * exposure;
+        pixel_volumes[pixel_volume_count] = volume_index;
+        pixel_intervals[pixel_volume_count] = interval;
+        pixel_volume_count += 1u;
+    }
 
-                // Modulate the factor we calculated above by the phase, fog color,
-                // light color, light tint.
-                let light_color_per_step = (*light).color.rgb * phase * light_factors_per_step;
+    var color = vec4(0.0);
+    var transmittance = vec3(1.0);
+#ifdef TEMPORAL
+    var motion = vec4(0.0);
+#endif  // TEMPORAL
 
-                // Accumulate the light.
-                accumulated_color += light_color_per_step * local_light_attenuation *
-                    background_alpha;
+    // Walk the slices between the points where the ray enters or leaves a
+    // volume. There are at most twice as many as there are volumes.
+    var slice_start = MAX_VIEW_DEPTH;
+    for (var volume = 0u; volume < pixel_volume_count; volume += 1u) {
+        slice_start = min(slice_start, pixel_intervals[volume].x);
+    }
+    for (var slice = 0u; slice < 2u * pixel_volume_count; slice += 1u) {
+        // Find where the slice ends, and how many volumes it's in.
+        var slice_end = MAX_VIEW_DEPTH;
+        var overlap_count = 0u;
+        for (var volume = 0u; volume < pixel_volume_count; volume += 1u) {
+            let interval = pixel_intervals[volume];
+            if (interval.x > slice_start) {
+                slice_end = min(slice_end, interval.x);
+            } else if (interval.y > slice_start) {
+                slice_end = min(slice_end, interval.y);
+                overlap_count += 1u;
             }
         }
+        if (slice_end == MAX_VIEW_DEPTH || all(transmittance < vec3(MIN_COMPUTE_TRANSMITTANCE))) {
+            break;
+        }
+
+        // Skip the gaps between volumes.
+        if (overlap_count == 0u) {
+            slice_start = slice_end;
+            continue;
+        }
+
+        var output: FragmentOutput;
+        if (overlap_count == 1u) {
+            // March the volume alone, with its share of its steps.
+            var volume = 0u;
+            for (; volume < pixel_volume_count; volume += 1u) {
+                let interval = pixel_intervals[volume];
+                if (interval.x <= slice_start && interval.y > slice_start) {
+                    break;
+                }
+            }
+            let interval = pixel_intervals[volume];
+            volumetric_fog = cloud_volumes[pixel_volumes[volume]];
+            let fraction = (slice_end - slice_start) / (interval.y - interval.x);
+            let step_count = max(u32(ceil(f32(volumetric_fog.step_count) * fraction)), 1u);
+            output = raymarch(
+                vec4(vec2<f32>(pixel) + 0.5, view_z_to_depth_ndc(-slice_start), 1.0),
+                RaySegment(slice_end, step_count, fraction)
+            );
+        } else {
+            output = raymarch_overlap(
+                pixel,
+                Rd_world,
+                depth_per_distance,
+                vec2(slice_start, slice_end),
+                &pixel_volumes,
+                &pixel_intervals,
+                pixel_volume_count
+            );
+        }
+
+        // This slice is behind the ones accumulated so far, so it only shows
+        // through them.
+#ifdef TEMPORAL
+        motion += output.motion * (1.0 - color.a);
+#endif  // TEMPORAL
+        color = vec4(
+            color.rgb + output.color.rgb * transmittance,
+            color.a + output.color.a * (1.0 - color.a)
+        );
+        transmittance *= output.transmittance.rgb;
+
+        slice_start = slice_end;
+    }
+
+    if (all(transmittance == vec3(1.0))) {
+        return;
     }
 
-    // We're done! Return the color with alpha so it can be blended onto the
-    // render target.
-    return vec4(accumulated_color, 1.0 - background_alpha);
+    // The dispatches before this one are in front of it, so blend under them.
+    // See the offscreen targets in `VolumetricCloudPipeline::specialize`.
+    let front_color = textureLoad(cloud_color, pixel);
+    let front_transmittance = textureLoad(cloud_transmittance, pixel);
+    textureStore(
+        cloud_color,
+        pixel,
+        vec4(
+            front_color.rgb + color.rgb * front_transmittance.rgb,
+            front_color.a + color.a * (1.0 - front_color.a)
+        )
+    );
+    textureStore(
+        cloud_transmittance,
+        pixel,
+        vec4(front_transmittance.rgb * transmittance, front_transmittance.a)
+    );
+#ifdef TEMPORAL
+    let front_motion = textureLoad(cloud_motion, pixel);
+    textureStore(cloud_motion, pixel, front_motion + motion * (1.0 - front_color.a));
+#endif  // TEMPORAL
 }
+#endif  // COMPUTE